@@ -0,0 +1,375 @@
+use crate::engine::SceneObject;
+use crate::engine::SceneObjectId;
+use crate::geometry::Point3;
+use crate::geometry::Ray;
+use crate::intersectable::Intersection;
+use crate::kdtree::{AxisAlignedBoundingBox, AxisAlignedBoundingBoxable, Direction, DIRECTIONS};
+
+/// Parameters of the binned Surface Area Heuristic used to build the BVH.
+#[derive(Debug, Clone)]
+pub struct BVHSettings {
+    /// Number of buckets the object centroids are sorted into when looking
+    /// for a split.
+    pub bin_count: usize,
+    /// Cost of visiting an interior node.
+    pub traversal_cost: f64,
+    /// Cost of testing the ray against one object.
+    pub intersection_cost: f64,
+    /// Nodes holding more objects are always split, smaller ones only when
+    /// the heuristic finds it worth it.
+    pub max_leaf_size: usize,
+}
+
+impl Default for BVHSettings {
+    fn default() -> Self {
+        BVHSettings {
+            bin_count: 12,
+            traversal_cost: 1f64,
+            intersection_cost: 1f64,
+            max_leaf_size: 8,
+        }
+    }
+}
+
+/// Nodes are stored depth first: the first child of an interior node
+/// directly follows it, the second one is at `offset`. Leaves hold the
+/// objects `objects[offset..offset + count]`.
+#[derive(Debug, Copy, Clone)]
+pub struct BVHNode {
+    pub aabb: AxisAlignedBoundingBox,
+    pub offset: usize,
+    /// Number of objects of a leaf, zero for interior nodes.
+    pub count: usize,
+    pub direction: Direction,
+}
+
+impl BVHNode {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+pub struct BVH {
+    pub nodes: Vec<BVHNode>,
+    pub objects: Vec<SceneObjectId>,
+    /// Objects without a bounding box (planes), tested against every ray.
+    pub unbounded_objects: Vec<SceneObjectId>,
+}
+
+struct BuildEntry {
+    index: SceneObjectId,
+    aabb: AxisAlignedBoundingBox,
+    centroid: Point3,
+}
+
+pub fn build_bvh(objects: &[SceneObject], settings: &BVHSettings) -> BVH {
+    let mut entries: Vec<BuildEntry> = vec![];
+    let mut unbounded_objects: Vec<SceneObjectId> = vec![];
+    for object in objects.iter() {
+        match object.geometry.get_aabb() {
+            Some(aabb) => entries.push(BuildEntry {
+                index: object.id,
+                aabb,
+                centroid: aabb.centroid(),
+            }),
+            None => unbounded_objects.push(object.id),
+        }
+    }
+
+    let mut bvh = BVH {
+        nodes: vec![],
+        objects: Vec::with_capacity(entries.len()),
+        unbounded_objects,
+    };
+    if !entries.is_empty() {
+        bvh.build_node(entries, settings);
+    }
+    bvh
+}
+
+impl BVH {
+    fn build_node(&mut self, entries: Vec<BuildEntry>, settings: &BVHSettings) {
+        let bounds = entries
+            .iter()
+            .fold(AxisAlignedBoundingBox::empty(), |bounds, entry| {
+                bounds.union(&entry.aabb)
+            });
+        let node_index = self.nodes.len();
+        let split = if entries.len() == 1 {
+            None
+        } else {
+            find_split(&entries, &bounds, settings)
+        };
+
+        match split {
+            None => {
+                self.nodes.push(BVHNode {
+                    aabb: bounds,
+                    offset: self.objects.len(),
+                    count: entries.len(),
+                    direction: Direction::X,
+                });
+                self.objects.extend(entries.iter().map(|entry| entry.index));
+            }
+            Some((direction, goes_first)) => {
+                let (first, second): (Vec<BuildEntry>, Vec<BuildEntry>) =
+                    entries.into_iter().partition(|entry| goes_first(entry));
+                self.nodes.push(BVHNode {
+                    aabb: bounds,
+                    offset: 0,
+                    count: 0,
+                    direction,
+                });
+                self.build_node(first, settings);
+                self.nodes[node_index].offset = self.nodes.len();
+                self.build_node(second, settings);
+            }
+        }
+    }
+
+    /// Recomputes the bounding boxes after objects moved, keeping the
+    /// hierarchy itself. Much cheaper than a rebuild, but the tree gets less
+    /// efficient as objects drift away from where they were at build time.
+    pub fn refit(&mut self, objects: &[SceneObject]) {
+        // children always come after their parent
+        for node_index in (0..self.nodes.len()).rev() {
+            let node = self.nodes[node_index];
+            let aabb = if node.is_leaf() {
+                self.objects[node.offset..node.offset + node.count]
+                    .iter()
+                    .filter_map(|&index| objects[index].geometry.get_aabb())
+                    .fold(AxisAlignedBoundingBox::empty(), |bounds, aabb| {
+                        bounds.union(&aabb)
+                    })
+            } else {
+                self.nodes[node_index + 1]
+                    .aabb
+                    .union(&self.nodes[node.offset].aabb)
+            };
+            self.nodes[node_index].aabb = aabb;
+        }
+    }
+
+    /// Closest intersection along the ray, as given by `intersect` for the
+    /// objects the ray may hit. Nodes further than the closest hit so far
+    /// are skipped.
+    pub fn get_closest_intersection<F>(
+        &self,
+        ray: &Ray,
+        mut intersect: F,
+    ) -> Option<(SceneObjectId, Intersection)>
+    where
+        F: FnMut(SceneObjectId) -> Option<Intersection>,
+    {
+        let mut closest: Option<(SceneObjectId, Intersection)> = None;
+        let mut test = |index: SceneObjectId,
+                        closest: &mut Option<(SceneObjectId, Intersection)>,
+                        max_distance: &mut f64| {
+            if let Some(intersection) = intersect(index) {
+                if intersection.distance < *max_distance {
+                    *max_distance = intersection.distance;
+                    *closest = Some((index, intersection));
+                }
+            }
+        };
+
+        let mut max_distance = f64::INFINITY;
+        for &index in self.unbounded_objects.iter() {
+            test(index, &mut closest, &mut max_distance);
+        }
+        self.traverse(ray, max_distance, |objects, max_distance| {
+            for &index in objects.iter() {
+                test(index, &mut closest, max_distance);
+            }
+            false
+        });
+        closest
+    }
+
+    /// Whether `predicate` holds for an object that the ray may hit within
+    /// `max_distance`. Stops as soon as one is found.
+    pub fn any_object_intersecting<F>(&self, ray: &Ray, max_distance: f64, mut predicate: F) -> bool
+    where
+        F: FnMut(SceneObjectId) -> bool,
+    {
+        self.unbounded_objects.iter().any(|&index| predicate(index))
+            || self.traverse(ray, max_distance, |objects, _max_distance| {
+                objects.iter().any(|&index| predicate(index))
+            })
+    }
+
+    /// Walks the leaves whose box the ray crosses before `max_distance`,
+    /// nearest child first. `visit` may shorten that distance as it finds
+    /// hits; returns true as soon as it does.
+    fn traverse<F>(&self, ray: &Ray, max_distance: f64, mut visit: F) -> bool
+    where
+        F: FnMut(&[SceneObjectId], &mut f64) -> bool,
+    {
+        let mut max_distance = max_distance;
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let mut pending: Vec<usize> = vec![0];
+        while let Some(node_index) = pending.pop() {
+            let node = &self.nodes[node_index];
+            match node.aabb.intersection_range(ray) {
+                Some((t_min, _)) if t_min <= max_distance => (),
+                _ => continue,
+            }
+            if node.is_leaf() {
+                if visit(
+                    &self.objects[node.offset..node.offset + node.count],
+                    &mut max_distance,
+                ) {
+                    return true;
+                }
+            } else if node.direction.of_vector(&ray.direction) < 0f64 {
+                pending.push(node_index + 1);
+                pending.push(node.offset);
+            } else {
+                pending.push(node.offset);
+                pending.push(node_index + 1);
+            }
+        }
+        false
+    }
+}
+
+type Partition = Box<dyn Fn(&BuildEntry) -> bool>;
+
+/// Axis and predicate selecting the objects of the first child, if
+/// splitting is cheaper than testing every object of the node.
+fn find_split(
+    entries: &[BuildEntry],
+    bounds: &AxisAlignedBoundingBox,
+    settings: &BVHSettings,
+) -> Option<(Direction, Partition)> {
+    let centroid_bounds = entries
+        .iter()
+        .fold(AxisAlignedBoundingBox::empty(), |bounds, entry| {
+            bounds.union(&AxisAlignedBoundingBox::from_point(&entry.centroid))
+        });
+    let direction = *DIRECTIONS.iter().max_by(|a, b| {
+        let (a_min, a_max) = centroid_bounds.extent(**a);
+        let (b_min, b_max) = centroid_bounds.extent(**b);
+        (a_max - a_min).partial_cmp(&(b_max - b_min)).unwrap()
+    })?;
+    let (min, max) = centroid_bounds.extent(direction);
+    if max <= min {
+        // all centroids at the same place, no split can separate them
+        return None;
+    }
+
+    let bin_count = settings.bin_count.max(2);
+    let bin_of = move |entry: &BuildEntry| {
+        let offset = (direction.of_point(&entry.centroid) - min) / (max - min);
+        ((offset * bin_count as f64) as usize).min(bin_count - 1)
+    };
+
+    let mut bin_counts = vec![0usize; bin_count];
+    let mut bin_bounds = vec![AxisAlignedBoundingBox::empty(); bin_count];
+    for entry in entries.iter() {
+        let bin = bin_of(entry);
+        bin_counts[bin] += 1;
+        bin_bounds[bin] = bin_bounds[bin].union(&entry.aabb);
+    }
+
+    // areas and counts of the objects below each bin boundary, then above
+    let mut below: Vec<(f64, usize)> = Vec::with_capacity(bin_count - 1);
+    let mut aabb = AxisAlignedBoundingBox::empty();
+    let mut count = 0;
+    for bin in 0..bin_count - 1 {
+        aabb = aabb.union(&bin_bounds[bin]);
+        count += bin_counts[bin];
+        below.push((aabb.surface_area(), count));
+    }
+    let mut best: Option<(f64, usize)> = None;
+    let mut aabb = AxisAlignedBoundingBox::empty();
+    let mut count = 0;
+    for bin in (1..bin_count).rev() {
+        aabb = aabb.union(&bin_bounds[bin]);
+        count += bin_counts[bin];
+        let (below_area, below_count) = below[bin - 1];
+        if below_count == 0 || count == 0 {
+            continue;
+        }
+        let cost = settings.traversal_cost
+            + settings.intersection_cost
+                * (below_area * below_count as f64 + aabb.surface_area() * count as f64)
+                / bounds.surface_area().max(f64::MIN_POSITIVE);
+        if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+            best = Some((cost, bin));
+        }
+    }
+
+    let (cost, split_bin) = best?;
+    let leaf_cost = settings.intersection_cost * entries.len() as f64;
+    if cost >= leaf_cost && entries.len() <= settings.max_leaf_size {
+        return None;
+    }
+    Some((
+        direction,
+        Box::new(move |entry: &BuildEntry| bin_of(entry) < split_bin),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vector3;
+    use crate::intersectable::Intersectable;
+    use crate::sample::{get_mesh, get_spheres_with_plane};
+
+    fn assert_closest_intersections(bvh: &BVH, objects: &[SceneObject], rays: &[Ray]) {
+        for ray in rays.iter() {
+            let expected = objects
+                .iter()
+                .filter_map(|object| object.geometry.intersect(ray))
+                .map(|intersection| intersection.distance)
+                .fold(f64::INFINITY, f64::min);
+            let found = bvh
+                .get_closest_intersection(ray, |index| objects[index].geometry.intersect(ray))
+                .map_or(f64::INFINITY, |(_, intersection)| intersection.distance);
+            assert_eq!(found, expected);
+            let any = bvh.any_object_intersecting(ray, f64::INFINITY, |index| {
+                objects[index].geometry.intersect(ray).is_some()
+            });
+            assert_eq!(any, expected.is_finite());
+        }
+    }
+
+    fn camera_rays(scene: &crate::engine::Scene) -> Vec<Ray> {
+        let camera = &scene.camera;
+        let mut rays = vec![];
+        for y in (0..camera.y_resolution).step_by(3) {
+            for x in (0..camera.x_resolution).step_by(3) {
+                rays.push(camera.get_ray(x, y));
+            }
+        }
+        rays
+    }
+
+    #[test]
+    fn bvh_finds_the_closest_intersection() {
+        let scene = get_mesh();
+        let objects = &scene.objects.objects;
+        let bvh = build_bvh(objects, &BVHSettings::default());
+        assert_closest_intersections(&bvh, objects, &camera_rays(&scene));
+    }
+
+    #[test]
+    fn refitted_bvh_follows_moved_objects() {
+        let mut scene = get_spheres_with_plane();
+        let mut bvh = build_bvh(&scene.objects.objects, &BVHSettings::default());
+        let rays = camera_rays(&scene);
+        for object in scene.objects.objects.iter_mut() {
+            object.geometry.translate(&Vector3 {
+                x: 0.7f64,
+                y: -0.4f64,
+                z: 0.3f64,
+            });
+        }
+        bvh.refit(&scene.objects.objects);
+        assert_closest_intersections(&bvh, &scene.objects.objects, &rays);
+    }
+}
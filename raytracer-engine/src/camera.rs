@@ -1,7 +1,13 @@
+use crate::display::DisplaySettings;
+use crate::geometry::Point2;
 use crate::geometry::Point3;
 use crate::geometry::Ray;
+use crate::geometry::RayCone;
 use crate::geometry::Vector3;
+use crate::integrator::Integrator;
+use crate::sampler::{concentric_disk, Sampling};
 use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -12,6 +18,82 @@ pub struct Camera {
     pub field_of_view: f64,
     pub x_resolution: u16,
     pub y_resolution: u16,
+    #[serde(default)]
+    pub sampling: Sampling,
+    #[serde(default)]
+    pub lens: Option<Lens>,
+    #[serde(default)]
+    pub projection: Projection,
+    #[serde(default)]
+    pub display: DisplaySettings,
+    #[serde(default)]
+    pub integrator: Integrator,
+}
+
+/// How image positions map to view rays. `Perspective` uses the camera
+/// `field_of_view`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Projection {
+    #[default]
+    Perspective,
+    /// Parallel rays, covering `view_width` scene units horizontally.
+    #[serde(rename_all = "camelCase")]
+    Orthographic { view_width: f64 },
+    /// Equidistant fisheye: the angle to the view direction grows linearly
+    /// with the distance to the image center, reaching `field_of_view / 2`
+    /// on the left and right edges.
+    #[serde(rename_all = "camelCase")]
+    Fisheye { field_of_view: f64 },
+    /// Full 360° x 180° panorama, longitude along x and latitude along y.
+    Equirectangular,
+}
+
+/// Thin lens in front of the camera position; without one the camera is a pinhole.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lens {
+    pub aperture_radius: f64,
+    /// Distance to the plane in focus, along the camera direction.
+    pub focal_distance: f64,
+    #[serde(default)]
+    pub aperture_shape: ApertureShape,
+    /// Focus on whatever is at the center of the image instead of `focal_distance`.
+    #[serde(default)]
+    pub auto_focus: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum ApertureShape {
+    #[default]
+    Circle,
+    Polygon {
+        blades: u32,
+        rotation: f64,
+    },
+}
+
+impl ApertureShape {
+    /// Maps a uniform sample of the unit square to a uniform point on the
+    /// aperture, scaled to a unit radius.
+    pub fn sample(&self, sample: &Point2) -> Point2 {
+        match *self {
+            ApertureShape::Circle => concentric_disk(sample),
+            ApertureShape::Polygon { blades, rotation } => {
+                let blades = blades.max(3) as f64;
+                // pick a blade, then a point in the triangle it makes with the center
+                let blade_float = sample.x * blades;
+                let blade = blade_float.floor();
+                let u = (blade_float - blade).sqrt();
+                let v = sample.y;
+                let angle_a = rotation + 2f64 * PI * blade / blades;
+                let angle_b = rotation + 2f64 * PI * (blade + 1f64) / blades;
+                Point2 {
+                    x: u * ((1f64 - v) * angle_a.cos() + v * angle_b.cos()),
+                    y: u * ((1f64 - v) * angle_a.sin() + v * angle_b.sin()),
+                }
+            }
+        }
+    }
 }
 
 pub struct ViewRay {
@@ -21,10 +103,41 @@ pub struct ViewRay {
 }
 
 impl Camera {
-    fn get_origin_and_sizes(&self) -> (Vector3, Vector3, Vector3) {
+    /// Perspective camera at `eye` looking towards `target`.
+    pub fn look_at(
+        eye: Point3,
+        target: &Point3,
+        up_direction: Vector3,
+        field_of_view: f64,
+        x_resolution: u16,
+        y_resolution: u16,
+    ) -> Camera {
+        let direction = (target - &eye).normalize();
+        Camera {
+            position: eye,
+            direction,
+            up_direction: orthogonal_up(&direction, &up_direction),
+            field_of_view,
+            x_resolution,
+            y_resolution,
+            sampling: Sampling::default(),
+            lens: None,
+            projection: Projection::Perspective,
+            display: DisplaySettings::default(),
+            integrator: Integrator::Whitted,
+        }
+    }
+
+    // Forward, right and down unit vectors of the image plane.
+    fn get_basis(&self) -> (Vector3, Vector3, Vector3) {
         let t_n = self.direction.normalize();
         let b_n = self.direction.cross(&self.up_direction).normalize();
         let v_n = t_n.cross(&b_n);
+        (t_n, b_n, v_n)
+    }
+
+    fn get_origin_and_sizes(&self) -> (Vector3, Vector3, Vector3) {
+        let (t_n, b_n, v_n) = self.get_basis();
         let g_x = (self.field_of_view / 2f64).tan();
         let g_y = g_x * (self.y_resolution as f64) / (self.x_resolution as f64);
         let q_x = b_n.times(2f64 * g_x / (self.x_resolution as f64));
@@ -36,37 +149,137 @@ impl Camera {
         (p_1_m, q_x, q_y)
     }
 
+    /// Cone covering one pixel around the rays of `get_ray`, measured at the
+    /// image center.
+    pub fn pixel_cone(&self) -> RayCone {
+        let width = self.x_resolution as f64;
+        let spread = match self.projection {
+            Projection::Perspective => 2f64 * (self.field_of_view / 2f64).tan() / width,
+            Projection::Orthographic { view_width } => {
+                return RayCone {
+                    width: view_width / width,
+                    spread: 0f64,
+                }
+            }
+            Projection::Fisheye { field_of_view } => field_of_view / width,
+            Projection::Equirectangular => 2f64 * PI / width,
+        };
+        RayCone {
+            width: 0f64,
+            spread,
+        }
+    }
+
     pub fn get_ray(&self, x: u16, y: u16) -> Ray {
-        let (p_1_m, q_x, q_y) = self.get_origin_and_sizes();
+        self.get_ray_through(x as f64 + 0.5f64, y as f64 + 0.5f64)
+    }
+
+    /// Ray through a continuous position on the image plane, in pixel units:
+    /// pixel `(x, y)` covers `[x, x + 1) x [y, y + 1)`.
+    pub fn get_ray_through(&self, x: f64, y: f64) -> Ray {
+        let width = self.x_resolution as f64;
+        let height = self.y_resolution as f64;
+        match self.projection {
+            Projection::Perspective => {
+                let (p_1_m, q_x, q_y) = self.get_origin_and_sizes();
 
-        let p = p_1_m
-            .plus(&q_x.times((x as f64) + 1f64))
-            .plus(&q_y.times((y as f64) + 1f64));
+                let p = p_1_m.plus(&q_x.times(x)).plus(&q_y.times(y));
+
+                Ray {
+                    origin: self.position,
+                    direction: p.normalize(),
+                }
+            }
+            Projection::Orthographic { view_width } => {
+                let (t_n, b_n, v_n) = self.get_basis();
+                let view_height = view_width * height / width;
+                Ray {
+                    origin: self.position.add(
+                        &b_n.times((x / width - 0.5f64) * view_width)
+                            .plus(&v_n.times((y / height - 0.5f64) * view_height)),
+                    ),
+                    direction: t_n,
+                }
+            }
+            Projection::Fisheye { field_of_view } => {
+                let (t_n, b_n, v_n) = self.get_basis();
+                let u = 2f64 * x / width - 1f64;
+                let v = (2f64 * y / height - 1f64) * height / width;
+                let theta = (u * u + v * v).sqrt() * field_of_view / 2f64;
+                let phi = v.atan2(u);
+                let sideways = b_n.times(phi.cos()).plus(&v_n.times(phi.sin()));
+                Ray {
+                    origin: self.position,
+                    direction: t_n
+                        .times(theta.cos())
+                        .plus(&sideways.times(theta.sin()))
+                        .normalize(),
+                }
+            }
+            Projection::Equirectangular => {
+                let (t_n, b_n, v_n) = self.get_basis();
+                let longitude = (x / width - 0.5f64) * 2f64 * PI;
+                let latitude = (0.5f64 - y / height) * PI;
+                let horizontal = t_n.times(longitude.cos()).plus(&b_n.times(longitude.sin()));
+                Ray {
+                    origin: self.position,
+                    direction: horizontal
+                        .times(latitude.cos())
+                        .minus(&v_n.times(latitude.sin()))
+                        .normalize(),
+                }
+            }
+        }
+    }
+
+    /// Same as `get_ray_through`, but starting from a point of the lens given
+    /// by `lens_sample` (uniform in the unit square) so that only the plane at
+    /// `focal_distance` is sharp.
+    pub fn get_lens_ray_through(
+        &self,
+        x: f64,
+        y: f64,
+        focal_distance: f64,
+        lens_sample: &Point2,
+    ) -> Ray {
+        let pinhole_ray = self.get_ray_through(x, y);
+        let lens = match &self.lens {
+            Some(lens) if lens.aperture_radius > 0f64 => lens,
+            _ => return pinhole_ray,
+        };
+
+        let (t_n, b_n, v_n) = self.get_basis();
+        // rays parallel to (or behind) the focal plane never reach it
+        if pinhole_ray.direction.dot(&t_n) < 1e-6 {
+            return pinhole_ray;
+        }
+
+        let focus_point = pinhole_ray.origin.add(
+            &pinhole_ray
+                .direction
+                .times(focal_distance / pinhole_ray.direction.dot(&t_n)),
+        );
+        let aperture_point = lens.aperture_shape.sample(lens_sample);
+        let origin = pinhole_ray.origin.add(
+            &b_n.times(aperture_point.x * lens.aperture_radius)
+                .plus(&v_n.times(aperture_point.y * lens.aperture_radius)),
+        );
 
         Ray {
-            origin: self.position,
-            direction: p.normalize(),
+            origin,
+            direction: (&focus_point - &origin).normalize(),
         }
     }
 
     pub fn generate_viewport(&self) -> Vec<ViewRay> {
         let mut view_rays: Vec<ViewRay> = vec![];
 
-        let (p_1_m, q_x, q_y) = self.get_origin_and_sizes();
-
         for x in 0..self.x_resolution {
             for y in 0..self.y_resolution {
-                let p = p_1_m
-                    .plus(&q_x.times((x as f64) + 1f64))
-                    .plus(&q_y.times((y as f64) + 1f64));
-                let ray = Ray {
-                    origin: self.position,
-                    direction: p.normalize(),
-                };
                 view_rays.push(ViewRay {
-                    x: x,
-                    y: y,
-                    ray: ray,
+                    x,
+                    y,
+                    ray: self.get_ray(x, y),
                 });
             }
         }
@@ -76,4 +289,395 @@ impl Camera {
     pub fn translate(&mut self, vector: &Vector3) {
         self.position = self.position.add(vector);
     }
+
+    /// Turns the camera left or right around its up direction.
+    pub fn yaw(&mut self, angle: f64) {
+        let up = self.up_direction.normalize();
+        self.direction = self.direction.rotate(&up, angle).normalize();
+    }
+
+    /// Tilts the camera up or down around its right direction.
+    pub fn pitch(&mut self, angle: f64) {
+        let (t_n, b_n, _v_n) = self.get_basis();
+        self.direction = t_n.rotate(&b_n, angle).normalize();
+        self.up_direction = orthogonal_up(&self.direction, &self.up_direction.rotate(&b_n, angle));
+    }
+
+    /// Rotates the camera around its view direction.
+    pub fn roll(&mut self, angle: f64) {
+        let t_n = self.direction.normalize();
+        self.up_direction = self.up_direction.rotate(&t_n, angle).normalize();
+    }
+
+    /// Moves the camera on a sphere around `target`, keeping it in view:
+    /// `yaw` turns around the up direction and `pitch` around the right one.
+    pub fn orbit(&mut self, target: &Point3, yaw: f64, pitch: f64) {
+        let up = self.up_direction.normalize();
+        let mut offset = (&self.position - target).rotate(&up, yaw);
+        let right = (target - &target.add(&offset)).cross(&up).normalize();
+        offset = offset.rotate(&right, pitch);
+        let up_direction = up.rotate(&right, pitch);
+
+        self.position = target.add(&offset);
+        self.direction = offset.times(-1f64).normalize();
+        self.up_direction = orthogonal_up(&self.direction, &up_direction);
+    }
+
+    /// Slides the camera and `target` together in the image plane, by
+    /// `right` and `up` scene units.
+    pub fn pan(&mut self, target: &mut Point3, right: f64, up: f64) {
+        let (_t_n, b_n, v_n) = self.get_basis();
+        let translation = b_n.times(right).minus(&v_n.times(up));
+        self.position = self.position.add(&translation);
+        *target = target.add(&translation);
+    }
+
+    /// Moves the camera towards `target` by `distance`, never going past it.
+    pub fn dolly(&mut self, target: &Point3, distance: f64) {
+        let to_target = target - &self.position;
+        let remaining = to_target.norm();
+        let step = distance.min(remaining - 1e-3);
+        self.position = self.position.add(&to_target.normalize().times(step));
+    }
+}
+
+// Up direction perpendicular to `direction`, as close as possible to `up`.
+fn orthogonal_up(direction: &Vector3, up: &Vector3) -> Vector3 {
+    let direction = direction.normalize();
+    up.minus(&direction.times(up.dot(&direction))).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::POINT3_ORIGIN;
+
+    fn get_camera(projection: Projection) -> Camera {
+        Camera {
+            position: Point3 {
+                x: 0f64,
+                y: 0f64,
+                z: 0f64,
+            },
+            direction: Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+            up_direction: Vector3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0f64,
+            },
+            field_of_view: PI / 2f64,
+            x_resolution: 200,
+            y_resolution: 100,
+            sampling: Sampling::default(),
+            lens: None,
+            projection,
+            display: DisplaySettings::default(),
+            integrator: Integrator::Whitted,
+        }
+    }
+
+    #[test]
+    fn image_center_looks_forward() {
+        let projections = vec![
+            Projection::Perspective,
+            Projection::Orthographic { view_width: 4f64 },
+            Projection::Fisheye { field_of_view: PI },
+            Projection::Equirectangular,
+        ];
+        for projection in projections {
+            let camera = get_camera(projection);
+            let ray = camera.get_ray_through(100f64, 50f64);
+            assert!(
+                (ray.direction.z - 1f64).abs() < 1e-9,
+                "{:?}: {:?}",
+                camera.projection,
+                ray.direction
+            );
+        }
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let camera = get_camera(Projection::Orthographic { view_width: 4f64 });
+        let left = camera.get_ray_through(0f64, 50f64);
+        let right = camera.get_ray_through(200f64, 50f64);
+        assert!((left.direction.dot(&right.direction) - 1f64).abs() < 1e-9);
+        assert!(((&left.origin - &right.origin).norm() - 4f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pixel_cone_matches_neighbouring_rays() {
+        let projections = vec![
+            Projection::Perspective,
+            Projection::Fisheye { field_of_view: PI },
+            Projection::Equirectangular,
+        ];
+        for projection in projections {
+            let camera = get_camera(projection);
+            let left = camera.get_ray_through(99.5f64, 50f64);
+            let right = camera.get_ray_through(100.5f64, 50f64);
+            let angle = left.direction.dot(&right.direction).acos();
+            let spread = camera.pixel_cone().spread;
+            assert!(
+                (spread / angle - 1f64).abs() < 1e-4,
+                "{:?}: {} {}",
+                camera.projection,
+                spread,
+                angle
+            );
+        }
+        let camera = get_camera(Projection::Orthographic { view_width: 4f64 });
+        assert!((camera.pixel_cone().width - 0.02f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn look_at_points_at_the_target() {
+        let target = Point3 {
+            x: 1f64,
+            y: 2f64,
+            z: 3f64,
+        };
+        let camera = Camera::look_at(
+            Point3 {
+                x: 1f64,
+                y: 2f64,
+                z: -7f64,
+            },
+            &target,
+            Vector3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0.5f64,
+            },
+            PI / 2f64,
+            100,
+            100,
+        );
+        assert!((camera.direction.z - 1f64).abs() < 1e-9);
+        assert!(camera.direction.dot(&camera.up_direction).abs() < 1e-9);
+    }
+
+    #[test]
+    fn orbit_keeps_distance_and_target_in_view() {
+        let target = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 10f64,
+        };
+        let mut camera = get_camera(Projection::Perspective);
+        camera.orbit(&target, PI / 2f64, 0.3f64);
+        let to_target = &target - &camera.position;
+        assert!((to_target.norm() - 10f64).abs() < 1e-9);
+        assert!((to_target.normalize().dot(&camera.direction) - 1f64).abs() < 1e-9);
+        assert!(camera.direction.dot(&camera.up_direction).abs() < 1e-9);
+    }
+
+    #[test]
+    fn equirectangular_edges_look_backward() {
+        let camera = get_camera(Projection::Equirectangular);
+        let ray = camera.get_ray_through(0f64, 50f64);
+        assert!((ray.direction.z + 1f64).abs() < 1e-9, "{:?}", ray.direction);
+    }
+
+    #[test]
+    fn lens_rays_start_on_the_aperture_and_meet_in_focus() {
+        let mut camera = get_camera(Projection::Perspective);
+        camera.lens = Some(Lens {
+            aperture_radius: 0.5f64,
+            focal_distance: 4f64,
+            aperture_shape: ApertureShape::Circle,
+            auto_focus: false,
+        });
+        let pinhole = camera.get_ray_through(30f64, 70f64);
+        let focus_point = pinhole
+            .origin
+            .add(&pinhole.direction.times(4f64 / pinhole.direction.z));
+        for (x, y) in &[(0.1f64, 0.2f64), (0.5f64, 0.5f64), (0.9f64, 0.7f64)] {
+            let ray = camera.get_lens_ray_through(30f64, 70f64, 4f64, &Point2 { x: *x, y: *y });
+            assert_eq!(ray.origin.z, 0f64);
+            assert!((&ray.origin - &camera.position).norm() <= 0.5f64 + 1e-9);
+            let reached = ray.origin.add(&ray.direction.times(4f64 / ray.direction.z));
+            assert!((&reached - &focus_point).norm() < 1e-9, "{:?}", reached);
+        }
+        let centered = camera.get_lens_ray_through(30f64, 70f64, 4f64, &Point2 { x: 0.5, y: 0.5 });
+        assert!((&centered.origin - &camera.position).norm() < 1e-9);
+    }
+
+    #[test]
+    fn aperture_samples_stay_inside_their_shape() {
+        let blades = 6;
+        let rotation = 0.3f64;
+        let hexagon = ApertureShape::Polygon { blades, rotation };
+        let apothem = (PI / blades as f64).cos();
+        for i in 0..20 {
+            for j in 0..20 {
+                let sample = Point2 {
+                    x: (i as f64 + 0.5f64) / 20f64,
+                    y: (j as f64 + 0.5f64) / 20f64,
+                };
+                let disk = ApertureShape::Circle.sample(&sample);
+                assert!(disk.x.hypot(disk.y) <= 1f64 + 1e-9);
+
+                let point = hexagon.sample(&sample);
+                for edge in 0..blades {
+                    let angle = rotation + 2f64 * PI * (edge as f64 + 0.5f64) / blades as f64;
+                    let inward = point.x * angle.cos() + point.y * angle.sin();
+                    assert!(inward <= apothem + 1e-9, "{:?}", point);
+                }
+            }
+        }
+        // the far end of a blade is a corner of the polygon
+        let corner = hexagon.sample(&Point2 {
+            x: 1f64 / 6f64 - 1e-12,
+            y: 0f64,
+        });
+        assert!(
+            (corner.x - rotation.cos()).abs() < 1e-6 && (corner.y - rotation.sin()).abs() < 1e-6
+        );
+    }
+
+    fn assert_close(actual: &Vector3, expected: &Vector3) {
+        assert!(
+            actual.minus(expected).norm() < 1e-9,
+            "{:?} {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn yaw_pitch_and_roll_turn_the_camera_in_place() {
+        let angle = 0.3f64;
+        let (sin, cos) = angle.sin_cos();
+        let mut camera = get_camera(Projection::Perspective);
+        camera.yaw(angle);
+        assert_close(
+            &camera.direction,
+            &Vector3 {
+                x: sin,
+                y: 0f64,
+                z: cos,
+            },
+        );
+        assert_close(
+            &camera.up_direction,
+            &Vector3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0f64,
+            },
+        );
+
+        let mut camera = get_camera(Projection::Perspective);
+        camera.pitch(angle);
+        assert_close(
+            &camera.direction,
+            &Vector3 {
+                x: 0f64,
+                y: sin,
+                z: cos,
+            },
+        );
+        assert_close(
+            &camera.up_direction,
+            &Vector3 {
+                x: 0f64,
+                y: cos,
+                z: -sin,
+            },
+        );
+
+        let mut camera = get_camera(Projection::Perspective);
+        camera.roll(angle);
+        assert_close(
+            &camera.direction,
+            &Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+        );
+        assert_close(
+            &camera.up_direction,
+            &Vector3 {
+                x: -sin,
+                y: cos,
+                z: 0f64,
+            },
+        );
+
+        assert_eq!(camera.position.x, 0f64);
+        assert_eq!(camera.position.z, 0f64);
+    }
+
+    #[test]
+    fn pan_slides_the_camera_and_its_target_in_the_image_plane() {
+        let mut camera = get_camera(Projection::Perspective);
+        // scene directions of the image right and top, from the edge rays
+        let towards = |x: f64, y: f64| {
+            let direction = camera.get_ray_through(x, y).direction;
+            direction.times(1f64 / direction.z).minus(&camera.direction)
+        };
+        let (right, up) = (towards(200f64, 50f64), towards(100f64, 0f64).normalize());
+        let mut target = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 10f64,
+        };
+        camera.pan(&mut target, 2f64, 1f64);
+        let expected = right.normalize().times(2f64).plus(&up);
+        assert_close(&(&camera.position - &POINT3_ORIGIN), &expected);
+        assert_close(
+            &(&target - &camera.position),
+            &Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 10f64,
+            },
+        );
+        assert_close(
+            &camera.direction,
+            &Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+        );
+    }
+
+    #[test]
+    fn dolly_moves_towards_the_target_without_passing_it() {
+        let target = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 10f64,
+        };
+        let mut camera = get_camera(Projection::Perspective);
+        camera.dolly(&target, 4f64);
+        assert_close(
+            &(&camera.position - &POINT3_ORIGIN),
+            &Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 4f64,
+            },
+        );
+        camera.dolly(&target, -1f64);
+        assert!((camera.position.z - 3f64).abs() < 1e-9);
+        camera.dolly(&target, 100f64);
+        let remaining = (&target - &camera.position).z;
+        assert!(remaining > 0f64 && remaining < 1e-2, "{}", remaining);
+        assert_close(
+            &camera.direction,
+            &Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+        );
+    }
 }
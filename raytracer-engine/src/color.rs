@@ -1,6 +1,8 @@
 use core::ops;
 use serde::{Deserialize, Serialize};
 
+/// Linear radiance, unbounded: only the display pipeline brings it back to
+/// [0, 1].
 #[derive(Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Color {
@@ -21,14 +23,98 @@ pub const WHITE: Color = Color {
     blue: 1f64,
 };
 
+impl Color {
+    pub fn grey(value: f64) -> Color {
+        Color {
+            red: value,
+            green: value,
+            blue: value,
+        }
+    }
+
+    pub fn luminance(&self) -> f64 {
+        0.2126f64 * self.red + 0.7152f64 * self.green + 0.0722f64 * self.blue
+    }
+
+    /// Linear interpolation, from `self` at 0 to `other` at 1.
+    pub fn mix(&self, other: &Color, amount: f64) -> Color {
+        &(self * (1f64 - amount)) + &(other * amount)
+    }
+
+    /// Clamps to [0, 1] and quantises to the nearest byte; only meant for
+    /// encoded display colors, see `DisplaySettings` for the linear radiance.
+    pub fn to_rgb8(&self) -> [u8; 3] {
+        [
+            (self.red.clamp(0f64, 1f64) * 255f64).round() as u8,
+            (self.green.clamp(0f64, 1f64) * 255f64).round() as u8,
+            (self.blue.clamp(0f64, 1f64) * 255f64).round() as u8,
+        ]
+    }
+
+    fn map<F>(&self, f: F) -> Color
+    where
+        F: Fn(f64) -> f64,
+    {
+        Color {
+            red: f(self.red),
+            green: f(self.green),
+            blue: f(self.blue),
+        }
+    }
+}
+
+/// Transfer function colors are encoded with, in scene files and images.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ColorSpace {
+    /// Gamma encoded, what color pickers and most image files give.
+    #[default]
+    Srgb,
+    /// Values used as they are.
+    Linear,
+}
+
+impl ColorSpace {
+    pub fn to_linear(&self, color: &Color) -> Color {
+        match self {
+            ColorSpace::Srgb => color.map(srgb_to_linear),
+            ColorSpace::Linear => *color,
+        }
+    }
+
+    pub fn from_linear(&self, color: &Color) -> Color {
+        match self {
+            ColorSpace::Srgb => color.map(linear_to_srgb),
+            ColorSpace::Linear => *color,
+        }
+    }
+}
+
+/// sRGB electro-optical transfer function, decoding a channel to linear.
+pub fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045f64 {
+        value / 12.92f64
+    } else {
+        ((value + 0.055f64) / 1.055f64).powf(2.4f64)
+    }
+}
+
+/// sRGB opto-electronic transfer function, encoding a linear channel.
+pub fn linear_to_srgb(value: f64) -> f64 {
+    if value <= 0.0031308f64 {
+        value * 12.92f64
+    } else {
+        1.055f64 * value.powf(1f64 / 2.4f64) - 0.055f64
+    }
+}
+
 impl ops::Mul<f64> for &Color {
     type Output = Color;
 
     fn mul(self, scalar: f64) -> Color {
         Color {
-            red: (self.red * scalar).min(1f64).max(0f64),
-            green: (self.green * scalar).min(1f64).max(0f64),
-            blue: (self.blue * scalar).min(1f64).max(0f64),
+            red: (self.red * scalar),
+            green: (self.green * scalar),
+            blue: (self.blue * scalar),
         }
     }
 }
@@ -46,9 +132,9 @@ impl ops::Mul<&Color> for &Color {
 
     fn mul(self, other: &Color) -> Color {
         Color {
-            red: (self.red * other.red).min(1f64).max(0f64),
-            green: (self.green * other.green).min(1f64).max(0f64),
-            blue: (self.blue * other.blue).min(1f64).max(0f64),
+            red: (self.red * other.red),
+            green: (self.green * other.green),
+            blue: (self.blue * other.blue),
         }
     }
 }
@@ -58,9 +144,35 @@ impl ops::Add<&Color> for &Color {
 
     fn add(self, other: &Color) -> Color {
         Color {
-            red: (self.red + other.red).min(1f64).max(0f64),
-            green: (self.green + other.green).min(1f64).max(0f64),
-            blue: (self.blue + other.blue).min(1f64).max(0f64),
+            red: (self.red + other.red),
+            green: (self.green + other.green),
+            blue: (self.blue + other.blue),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_transfer_functions_round_trip() {
+        for i in 0..=255 {
+            let encoded = i as f64 / 255f64;
+            let decoded = srgb_to_linear(encoded);
+            assert!((linear_to_srgb(decoded) - encoded).abs() < 1e-9, "{}", i);
+        }
+        assert!((srgb_to_linear(0.5f64) - 0.214f64).abs() < 1e-3);
+        assert!((linear_to_srgb(1f64) - 1f64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn quantisation_rounds() {
+        let color = Color {
+            red: 0.999f64,
+            green: 0.5f64 / 255f64 + 1e-9,
+            blue: -1f64,
+        };
+        assert_eq!(color.to_rgb8(), [255, 1, 0]);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::color::{Color, ColorSpace, WHITE};
+use crate::sampler::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Turns the linear radiance computed by the engine into displayable colors:
+/// exposure, then white balance, then tone mapping down to [0, 1], and
+/// finally encoding to bytes. Every frontend goes through `to_rgb8`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DisplaySettings {
+    /// Exposure compensation in stops, each stop doubling the brightness.
+    #[serde(default)]
+    pub exposure: f64,
+    /// Scene color that should come out as neutral grey.
+    #[serde(default = "default_white_balance")]
+    pub white_balance: Color,
+    #[serde(default)]
+    pub tone_mapping: ToneMapping,
+    /// Transfer function of the output bytes.
+    #[serde(default)]
+    pub encoding: ColorSpace,
+    /// Adds one byte of triangular noise before quantising, trading banding
+    /// in smooth gradients for fine grain.
+    #[serde(default)]
+    pub dither: bool,
+}
+
+fn default_white_balance() -> Color {
+    WHITE
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings {
+            exposure: 0f64,
+            white_balance: WHITE,
+            tone_mapping: ToneMapping::default(),
+            encoding: ColorSpace::default(),
+            dither: false,
+        }
+    }
+}
+
+/// Operator compressing unbounded radiance into the displayable range.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum ToneMapping {
+    /// Values above 1 are simply cut off.
+    #[default]
+    Clamp,
+    /// `L / (1 + L)` on the luminance, keeping the hue.
+    Reinhard,
+    /// Narkowicz's fit of the ACES reference rendering transform.
+    AcesFilmic,
+    /// Hable's filmic curve from Uncharted 2, with its linear white at 11.2.
+    Uncharted2,
+}
+
+impl ToneMapping {
+    pub fn apply(&self, color: &Color) -> Color {
+        match self {
+            ToneMapping::Clamp => map_channels(color, |c| c),
+            ToneMapping::Reinhard => {
+                let luminance = color.luminance();
+                if luminance <= 0f64 {
+                    return map_channels(color, |c| c);
+                }
+                let scale = 1f64 / (1f64 + luminance);
+                map_channels(color, |c| c * scale)
+            }
+            ToneMapping::AcesFilmic => map_channels(color, |c| {
+                (c * (2.51f64 * c + 0.03f64)) / (c * (2.43f64 * c + 0.59f64) + 0.14f64)
+            }),
+            ToneMapping::Uncharted2 => {
+                let white_scale = 1f64 / hable(11.2f64);
+                map_channels(color, |c| hable(2f64 * c) * white_scale)
+            }
+        }
+    }
+}
+
+// Applies `f` to the non-negative part of each channel, clamping the result.
+fn map_channels<F>(color: &Color, f: F) -> Color
+where
+    F: Fn(f64) -> f64,
+{
+    Color {
+        red: f(color.red.max(0f64)).clamp(0f64, 1f64),
+        green: f(color.green.max(0f64)).clamp(0f64, 1f64),
+        blue: f(color.blue.max(0f64)).clamp(0f64, 1f64),
+    }
+}
+
+fn hable(x: f64) -> f64 {
+    let a = 0.15f64;
+    let b = 0.50f64;
+    let c = 0.10f64;
+    let d = 0.20f64;
+    let e = 0.02f64;
+    let f = 0.30f64;
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+impl DisplaySettings {
+    /// Display-referred color, every channel within [0, 1].
+    pub fn apply(&self, color: &Color) -> Color {
+        let exposed = color * 2f64.powf(self.exposure);
+        let balanced = &exposed * &self.white_balance_gains();
+        self.tone_mapping.apply(&balanced)
+    }
+
+    /// Output bytes of the pixel `(x, y)`, which seeds the dithering noise.
+    pub fn to_rgb8(&self, color: &Color, x: u16, y: u16) -> [u8; 3] {
+        let encoded = self.encoding.from_linear(&self.apply(color));
+        if !self.dither {
+            return encoded.to_rgb8();
+        }
+        let mut rng = Rng::for_pixel(x, y, 4);
+        let noise = (rng.next_f64() + rng.next_f64() - 1f64) / 255f64;
+        Color {
+            red: encoded.red + noise,
+            green: encoded.green + noise,
+            blue: encoded.blue + noise,
+        }
+        .to_rgb8()
+    }
+
+    // Per-channel gains mapping the white balance color to a grey of the
+    // same luminance.
+    fn white_balance_gains(&self) -> Color {
+        let white = &self.white_balance;
+        let luminance = white.luminance();
+        let gain = |channel: f64| {
+            if channel > 0f64 {
+                luminance / channel
+            } else {
+                1f64
+            }
+        };
+        Color {
+            red: gain(white.red),
+            green: gain(white.green),
+            blue: gain(white.blue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{srgb_to_linear, BLACK};
+
+    #[test]
+    fn tone_mapping_operators_stay_in_range() {
+        let bright = Color {
+            red: 50f64,
+            green: 4f64,
+            blue: 0.5f64,
+        };
+        for tone_mapping in [
+            ToneMapping::Clamp,
+            ToneMapping::Reinhard,
+            ToneMapping::AcesFilmic,
+            ToneMapping::Uncharted2,
+        ]
+        .iter()
+        {
+            let mapped = tone_mapping.apply(&bright);
+            for channel in [mapped.red, mapped.green, mapped.blue].iter() {
+                assert!((0f64..=1f64).contains(channel), "{:?}", tone_mapping);
+            }
+            assert_eq!(tone_mapping.apply(&BLACK), BLACK, "{:?}", tone_mapping);
+            if *tone_mapping != ToneMapping::Clamp {
+                assert!(mapped.red > mapped.green, "{:?}", tone_mapping);
+            }
+        }
+    }
+
+    #[test]
+    fn exposure_and_white_balance() {
+        let settings = DisplaySettings {
+            exposure: 1f64,
+            white_balance: Color {
+                red: 1f64,
+                green: 0.5f64,
+                blue: 1f64,
+            },
+            tone_mapping: ToneMapping::Clamp,
+            encoding: ColorSpace::Linear,
+            dither: false,
+        };
+        let grey = Color {
+            red: 0.2f64,
+            green: 0.1f64,
+            blue: 0.2f64,
+        };
+        let displayed = settings.apply(&grey);
+
+        assert!((displayed.red - displayed.green).abs() < 1e-9);
+        assert!((displayed.red - displayed.blue).abs() < 1e-9);
+        let white_luminance = settings.white_balance.luminance();
+        assert!((displayed.red - 0.4f64 * white_luminance).abs() < 1e-9);
+    }
+
+    #[test]
+    fn output_is_srgb_encoded_and_dithering_averages_out() {
+        let settings = DisplaySettings::default();
+        let level = srgb_to_linear(128f64 / 255f64);
+        let mid_grey = Color {
+            red: level,
+            green: level,
+            blue: level,
+        };
+        assert_eq!(settings.to_rgb8(&mid_grey, 0, 0), [128, 128, 128]);
+
+        let dithered = DisplaySettings {
+            dither: true,
+            encoding: ColorSpace::Linear,
+            ..DisplaySettings::default()
+        };
+        let between = Color {
+            red: 100.25f64 / 255f64,
+            green: 100.25f64 / 255f64,
+            blue: 100.25f64 / 255f64,
+        };
+        let mut sum = 0f64;
+        for y in 0..64 {
+            for x in 0..64 {
+                let [red, green, blue] = dithered.to_rgb8(&between, x, y);
+                assert!((99..=101).contains(&red), "{}", red);
+                assert_eq!((green, blue), (red, red));
+                sum += red as f64;
+            }
+        }
+        assert!((sum / 4096f64 - 100.25f64).abs() < 0.05f64);
+    }
+}
@@ -1,15 +1,22 @@
+use crate::bvh::{build_bvh, BVHSettings, BVH};
 use crate::camera::*;
 use crate::color::*;
+use crate::framebuffer::Framebuffer;
 use crate::geometry::*;
+use crate::integrator::{trace_path, Integrator};
 use crate::intersectable::*;
 use crate::kdtree::build_kd_tree;
 use crate::kdtree::KDTree;
+use crate::kdtree::KDTreeSettings;
 use crate::light::*;
 use crate::material::*;
+use crate::sampler::{Rng, SampleAccumulator};
+use crate::scheduler::*;
 use rayon::prelude::*;
 use serde::ser::SerializeStruct;
 use serde::{de, ser, Deserialize, Serialize};
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Instant;
 
 const MAX_BOUNCES: u8 = 4;
@@ -22,21 +29,56 @@ pub struct SceneObject {
     pub material: Material,
 }
 
+/// Structure used to find the objects a ray may hit without testing all
+/// of them.
+pub enum Acceleration {
+    None,
+    KDTree(KDTree),
+    BVH(BVH),
+}
+
 pub struct SceneObjects {
     pub objects: Vec<SceneObject>,
-    pub kd_tree: Option<KDTree>,
+    pub acceleration: Acceleration,
+    pub emitters: EmissiveObjects,
 }
 
 impl SceneObjects {
     pub fn initialize(objects: Vec<SceneObject>) -> Self {
         SceneObjects {
+            emitters: EmissiveObjects::new(&objects),
             objects: objects,
-            kd_tree: None,
+            acceleration: Acceleration::None,
         }
     }
 
     pub fn build_kd_tree(&mut self) {
-        self.kd_tree = Some(build_kd_tree(&self.objects));
+        self.build_kd_tree_with(&KDTreeSettings::default());
+    }
+
+    pub fn build_kd_tree_with(&mut self, settings: &KDTreeSettings) {
+        self.acceleration = Acceleration::KDTree(build_kd_tree(&self.objects, settings));
+    }
+
+    pub fn build_bvh(&mut self) {
+        self.build_bvh_with(&BVHSettings::default());
+    }
+
+    pub fn build_bvh_with(&mut self, settings: &BVHSettings) {
+        self.acceleration = Acceleration::BVH(build_bvh(&self.objects, settings));
+    }
+
+    /// Moves one object and brings the acceleration structure up to date: a
+    /// BVH is refitted, a kd-tree has to be rebuilt.
+    pub fn translate_object(&mut self, id: SceneObjectId, vector: &Vector3) {
+        self.objects[id].geometry.translate(vector);
+        match &mut self.acceleration {
+            Acceleration::None => (),
+            Acceleration::KDTree(kd_tree) => {
+                *kd_tree = build_kd_tree(&self.objects, &kd_tree.settings);
+            }
+            Acceleration::BVH(bvh) => bvh.refit(&self.objects),
+        }
     }
 }
 
@@ -47,6 +89,28 @@ pub struct Scene {
     pub camera: Camera,
 }
 
+impl Scene {
+    /// Converts the material, texture and light colors, given in
+    /// `color_space`, to the linear values shading works with.
+    pub fn decode_colors(&mut self, color_space: ColorSpace) {
+        let decode = |color: &Color| color_space.to_linear(color);
+        for object in self.objects.objects.iter_mut() {
+            object.material.map_colors(&decode);
+        }
+        self.decode_light_colors(color_space);
+    }
+
+    /// Same as `decode_colors`, leaving the materials alone.
+    pub fn decode_light_colors(&mut self, color_space: ColorSpace) {
+        let decode = |color: &Color| color_space.to_linear(color);
+        for light in self.lights.iter_mut() {
+            let color = light.color_mut();
+            *color = decode(color);
+        }
+        self.ambient_light.color = decode(&self.ambient_light.color);
+    }
+}
+
 pub struct SceneIntersection<'a> {
     pub intersection: Intersection,
     pub object: &'a SceneObject,
@@ -70,89 +134,321 @@ impl SceneIntersection<'_> {
 pub struct TracedRay {
     pub ray: Ray,
     pub inside_objects: Vec<SceneObjectId>,
+    pub cone: RayCone,
 }
 
-pub fn render(scene: &Scene) -> HashMap<(u16, u16), Color> {
+impl TracedRay {
+    /// Width of what the ray covers on a surface hit `distance` away,
+    /// stretched at grazing angles.
+    pub fn footprint(&self, distance: f64, normal: &Vector3) -> f64 {
+        let cosine = self.ray.direction.dot(normal).abs().max(1e-6f64);
+        self.cone.width_at(distance) / cosine
+    }
+}
+
+pub fn render(scene: &Scene) -> Framebuffer {
+    render_tiles(
+        scene,
+        &RenderSettings::default(),
+        &CancellationToken::new(),
+        |_| (),
+    )
+    .unwrap()
+}
+
+/// Renders the scene tile by tile, handing each finished tile to `progress`.
+/// Tiles are picked up in the order given by the settings; returns `None` as
+/// soon as `cancellation` is triggered.
+pub fn render_tiles<F>(
+    scene: &Scene,
+    settings: &RenderSettings,
+    cancellation: &CancellationToken,
+    progress: F,
+) -> Option<Framebuffer>
+where
+    F: Fn(&RenderedTile) + Sync,
+{
     let now = Instant::now();
-    let viewport = scene.camera.generate_viewport();
-    let screen: HashMap<_, _> = viewport
-        //.iter()
-        .par_iter()
-        .map(|view_ray| {
-            let coordinates = (view_ray.x, view_ray.y);
-            let result = render_pixel(scene, view_ray.ray.clone());
-            (coordinates, result)
-        })
-        .collect();
+    let width = scene.camera.x_resolution;
+    let height = scene.camera.y_resolution;
+    let tiles = generate_tiles(width, height, settings);
+    let next_tile = AtomicUsize::new(0);
+    let mut framebuffer = Framebuffer::new(width, height);
+    framebuffer.set_display(scene.camera.display.clone());
+    let screen = Mutex::new(framebuffer);
+    let focal_distance = get_focal_distance(scene);
+
+    (0..rayon::current_num_threads())
+        .into_par_iter()
+        .for_each(|_worker| loop {
+            if cancellation.is_cancelled() {
+                return;
+            }
+            let index = next_tile.fetch_add(1, Ordering::Relaxed);
+            if index >= tiles.len() {
+                return;
+            }
+            let rendered_tile = render_tile(scene, &tiles[index], focal_distance);
+            screen.lock().unwrap().write_tile(&rendered_tile);
+            progress(&rendered_tile);
+        });
+
+    if cancellation.is_cancelled() {
+        return None;
+    }
     println!("{}", now.elapsed().as_millis());
-    screen
+    Some(screen.into_inner().unwrap())
 }
 
-pub fn render_pixel(scene: &Scene, ray: Ray) -> Color {
-    let mut traced_ray = TracedRay {
-        ray: ray,
-        inside_objects: vec![],
+/// Renders the pixels of `tile`, `focal_distance` being the one computed
+/// once for the whole frame by `get_focal_distance`.
+pub fn render_tile(scene: &Scene, tile: &Tile, focal_distance: f64) -> RenderedTile {
+    let pixel_count = tile.width as usize * tile.height as usize;
+    let mut pixels = Vec::with_capacity(pixel_count);
+    let mut sample_counts = Vec::with_capacity(pixel_count);
+    for y in tile.y..tile.y + tile.height {
+        for x in tile.x..tile.x + tile.width {
+            let accumulator = render_camera_pixel(scene, x, y, focal_distance);
+            pixels.push(accumulator.resolve());
+            sample_counts.push(accumulator.count());
+        }
+    }
+    RenderedTile {
+        tile: *tile,
+        pixels,
+        sample_counts,
+    }
+}
+
+/// Traces the camera samples of pixel `(x, y)`, weighted by the camera
+/// filter. In adaptive mode, samples are added in batches until the pixel
+/// estimate is precise enough.
+pub fn render_camera_pixel(
+    scene: &Scene,
+    x: u16,
+    y: u16,
+    focal_distance: f64,
+) -> SampleAccumulator {
+    let sampling = &scene.camera.sampling;
+    let radius = sampling.filter.radius();
+    let mut accumulator = SampleAccumulator::default();
+    let mut lens_rng = Rng::for_pixel(x, y, 2);
+    let mut trace_sample = |sample: &Point2, accumulator: &mut SampleAccumulator| {
+        let dx = (sample.x - 0.5f64) * 2f64 * radius;
+        let dy = (sample.y - 0.5f64) * 2f64 * radius;
+        let lens_sample = Point2 {
+            x: lens_rng.next_f64(),
+            y: lens_rng.next_f64(),
+        };
+        let ray = scene.camera.get_lens_ray_through(
+            x as f64 + 0.5f64 + dx,
+            y as f64 + 0.5f64 + dy,
+            focal_distance,
+            &lens_sample,
+        );
+        accumulator.add(&render_pixel(scene, ray), sampling.filter.evaluate(dx, dy));
     };
-    cast_ray(&scene, &mut traced_ray, MAX_BOUNCES)
+
+    match &sampling.adaptive {
+        None => {
+            for sample in sampling
+                .sampler
+                .generate(sampling.samples_per_pixel, x, y)
+                .iter()
+            {
+                trace_sample(sample, &mut accumulator);
+            }
+        }
+        Some(adaptive) => {
+            let samples = sampling
+                .sampler
+                .generate_progressive(adaptive.max_samples, x, y);
+            let batch_size = adaptive.min_samples.max(2) as usize;
+            for batch in samples.chunks(batch_size) {
+                for sample in batch.iter() {
+                    trace_sample(sample, &mut accumulator);
+                }
+                if accumulator.standard_error() <= adaptive.threshold {
+                    break;
+                }
+            }
+        }
+    }
+    accumulator
 }
 
-pub fn is_in_shadow(point: &Point3, light: &Light, scene: &Scene) -> bool {
-    let light_direction = light.get_direction(point);
+pub fn render_pixel(scene: &Scene, ray: Ray) -> Color {
+    match scene.camera.integrator {
+        Integrator::Whitted => {
+            let traced_ray = TracedRay {
+                ray: ray,
+                inside_objects: vec![],
+                cone: scene.camera.pixel_cone(),
+            };
+            cast_ray(scene, &traced_ray, MAX_BOUNCES)
+        }
+        Integrator::PathTracing {
+            max_depth,
+            russian_roulette_depth,
+        } => {
+            let mut rng = Rng::for_ray(&ray, 6);
+            trace_path(scene, ray, max_depth, russian_roulette_depth, &mut rng)
+        }
+    }
+}
+
+pub fn is_in_shadow(point: &Point3, light_sample: &LightSample, scene: &Scene) -> bool {
     let shadow_ray = Ray {
         origin: *point,
-        direction: light_direction.times(-1f64),
+        direction: light_sample.direction.times(-1f64),
     };
 
-    scene
-        .objects
-        .objects
-        .iter()
-        .filter_map(|object| object.geometry.intersect(&shadow_ray))
-        .any(|_d| true)
+    is_occluded(scene, &shadow_ray, light_sample.distance)
+}
+
+/// Any-hit query: whether something lies on the ray closer than
+/// `max_distance`, stopping at the first occluder found.
+pub fn is_occluded(scene: &Scene, ray: &Ray, max_distance: f64) -> bool {
+    let scene_objects = &scene.objects;
+    let occludes = |object: &SceneObject| {
+        object
+            .geometry
+            .intersect(ray)
+            .is_some_and(|intersection| intersection.distance < max_distance)
+    };
+    match &scene_objects.acceleration {
+        Acceleration::KDTree(kd_tree) => {
+            kd_tree.any_object_intersecting(ray, max_distance, |index| {
+                occludes(&scene_objects.objects[index])
+            })
+        }
+        Acceleration::BVH(bvh) => bvh.any_object_intersecting(ray, max_distance, |index| {
+            occludes(&scene_objects.objects[index])
+        }),
+        Acceleration::None => scene_objects.objects.iter().any(occludes),
+    }
 }
 
 pub fn cast_ray(scene: &Scene, ray: &TracedRay, max_bounces: u8) -> Color {
-    get_closest_intersection(scene, ray)
+    let intersection = get_closest_intersection(scene, ray);
+    let object_distance = intersection
+        .as_ref()
+        .map_or(f64::INFINITY, |i| i.intersection.distance);
+    if let Some(light) = get_closest_light(scene, &ray.ray, object_distance) {
+        let light_distance = light.intersect(&ray.ray).unwrap_or(object_distance);
+        return &light.get_radiance()
+            * &medium_transmittance(scene, &ray.inside_objects, light_distance);
+    }
+    let color = intersection
         .map(|i| {
             (*i.object)
                 .material
                 .render_color(ray, &i, &scene, max_bounces)
         })
-        .unwrap_or(BLACK)
+        .unwrap_or(BLACK);
+    // light coming from there was partly absorbed on the way
+    &color * &medium_transmittance(scene, &ray.inside_objects, object_distance)
 }
 
-fn get_closest_intersection<'a>(
+/// Area light seen directly along the ray, if one is closer than
+/// `max_distance`.
+pub(crate) fn get_closest_light<'a>(
+    scene: &'a Scene,
+    ray: &Ray,
+    max_distance: f64,
+) -> Option<&'a Light> {
+    scene
+        .lights
+        .iter()
+        .filter_map(|light| light.intersect(ray).map(|distance| (light, distance)))
+        .filter(|&(_, distance)| distance < max_distance)
+        .min_by(|(_, d1), (_, d2)| d1.partial_cmp(d2).unwrap())
+        .map(|(light, _)| light)
+}
+
+pub(crate) fn get_closest_intersection<'a>(
     scene: &'a Scene,
     ray: &TracedRay,
 ) -> Option<SceneIntersection<'a>> {
     let scene_objects = &scene.objects;
-    let candidates: Vec<&SceneObject> = match &scene_objects.kd_tree {
-        Some(kd_tree) => kd_tree
-            .get_leafs_intersecting(&ray.ray)
-            .union(&kd_tree.tree.objects) // adding unbound objects
-            .map(|&index| &scene_objects.objects[index])
-            .collect(),
-        None => scene_objects.objects.iter().map(|object| object).collect(),
+    let intersect =
+        |index: SceneObjectId| scene_objects.objects[index].geometry.intersect(&ray.ray);
+    let to_scene_intersection =
+        |(index, intersection): (SceneObjectId, Intersection)| SceneIntersection {
+            intersection,
+            object: &scene_objects.objects[index],
+        };
+    match &scene_objects.acceleration {
+        Acceleration::KDTree(kd_tree) => kd_tree
+            .get_closest_intersection(&ray.ray, intersect)
+            .map(to_scene_intersection),
+        Acceleration::BVH(bvh) => bvh
+            .get_closest_intersection(&ray.ray, intersect)
+            .map(to_scene_intersection),
+        Acceleration::None => scene_objects
+            .objects
+            .iter()
+            .filter_map(|object| {
+                object
+                    .geometry
+                    .intersect(&ray.ray)
+                    .map(|intersection| SceneIntersection {
+                        intersection,
+                        object,
+                    })
+            })
+            .min_by(|i1, i2| {
+                i1.intersection
+                    .distance
+                    .partial_cmp(&i2.intersection.distance)
+                    .unwrap()
+            }),
+    }
+}
+
+/// Distance to the plane in focus. With auto focus, it is the distance to
+/// the object seen at the center of the image, if any.
+pub fn get_focal_distance(scene: &Scene) -> f64 {
+    let lens = match &scene.camera.lens {
+        Some(lens) => lens,
+        None => return 0f64,
     };
+    if !lens.auto_focus {
+        return lens.focal_distance;
+    }
+    let ray = scene
+        .camera
+        .get_ray(scene.camera.x_resolution / 2, scene.camera.y_resolution / 2);
+    let view_direction = scene.camera.direction.normalize();
+    let intersection = get_closest_intersection(
+        scene,
+        &TracedRay {
+            ray: ray.clone(),
+            inside_objects: vec![],
+            cone: RayCone::default(),
+        },
+    );
+    match intersection {
+        Some(x) => x.intersection.distance * ray.direction.dot(&view_direction),
+        None => lens.focal_distance,
+    }
+}
 
-    let result = candidates
-        .iter()
-        .filter_map(|object| {
-            object
-                .geometry
-                .intersect(&ray.ray)
-                .map(|intersection| SceneIntersection {
-                    intersection: intersection,
-                    object: &object,
-                })
-        })
-        .min_by(|i1, i2| {
-            i1.intersection
-                .distance
-                .partial_cmp(&i2.intersection.distance)
-                .unwrap()
-        });
-    result
+/// Point of the scene seen through the center of pixel `(x, y)`, if any.
+pub fn get_point(scene: &Scene, x: u16, y: u16) -> Option<Point3> {
+    let ray = scene.camera.get_ray(x, y);
+    let intersection = get_closest_intersection(
+        scene,
+        &TracedRay {
+            ray: ray.clone(),
+            inside_objects: vec![],
+            cone: RayCone::default(),
+        },
+    );
+    intersection.map(|x| {
+        ray.origin
+            .add(&ray.direction.times(x.intersection.distance))
+    })
 }
 
 pub fn get_object<'a>(scene: &'a Scene, x: u16, y: u16) -> Option<usize> {
@@ -162,6 +458,7 @@ pub fn get_object<'a>(scene: &'a Scene, x: u16, y: u16) -> Option<usize> {
         &TracedRay {
             ray: ray,
             inside_objects: vec![],
+            cone: RayCone::default(),
         },
     );
     match intersection {
@@ -169,3 +466,132 @@ pub fn get_object<'a>(scene: &'a Scene, x: u16, y: u16) -> Option<usize> {
         None => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn matte() -> Material {
+        serde_json::from_value(serde_json::json!({
+            "ambientColor": {"Color": {"red": 1, "green": 1, "blue": 1}},
+            "ambientReflection": 0,
+            "diffuseColor": {"Color": {"red": 1, "green": 1, "blue": 1}},
+            "diffuseReflection": 1,
+            "specularColor": {"Color": {"red": 1, "green": 1, "blue": 1}},
+            "specularReflection": 0,
+            "shininess": 1,
+            "reflectivity": 0,
+            "transparency": 0,
+            "indexOfRefraction": 1,
+        }))
+        .unwrap()
+    }
+
+    fn sphere(x: f64, y: f64, z: f64, radius: f64) -> Object {
+        Object::Sphere(Sphere {
+            center: Point3 { x, y, z },
+            radius,
+        })
+    }
+
+    // Scene seen by a camera at the origin looking along z.
+    fn get_scene(geometries: Vec<Object>, lights: Vec<Light>) -> Scene {
+        let objects = geometries
+            .into_iter()
+            .enumerate()
+            .map(|(id, geometry)| SceneObject {
+                id,
+                geometry,
+                material: matte(),
+            })
+            .collect();
+        Scene {
+            objects: SceneObjects::initialize(objects),
+            ambient_light: AmbientLight {
+                color: WHITE,
+                intensity: 0f64,
+            },
+            lights,
+            camera: Camera::look_at(
+                Point3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z: 0f64,
+                },
+                &Point3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z: 1f64,
+                },
+                Vector3 {
+                    x: 0f64,
+                    y: 1f64,
+                    z: 0f64,
+                },
+                PI / 2f64,
+                9,
+                9,
+            ),
+        }
+    }
+
+    #[test]
+    fn auto_focus_picks_the_object_at_the_center() {
+        let mut scene = get_scene(vec![sphere(0f64, 0f64, 7f64, 1f64)], vec![]);
+        assert_eq!(get_focal_distance(&scene), 0f64);
+
+        scene.camera.lens = Some(Lens {
+            aperture_radius: 0.1f64,
+            focal_distance: 3f64,
+            aperture_shape: ApertureShape::Circle,
+            auto_focus: false,
+        });
+        assert_eq!(get_focal_distance(&scene), 3f64);
+
+        scene.camera.lens.as_mut().unwrap().auto_focus = true;
+        assert!((get_focal_distance(&scene) - 6f64).abs() < 1e-9);
+
+        // nothing to focus on
+        scene.camera.direction = scene.camera.direction.times(-1f64);
+        assert_eq!(get_focal_distance(&scene), 3f64);
+    }
+
+    #[test]
+    fn objects_beyond_the_light_cast_no_shadow() {
+        let point_light = |z: f64| {
+            Light::PointLight(PointLight {
+                origin: Point3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z,
+                },
+                intensity: 1f64,
+                color: WHITE,
+                attenuation: Attenuation::default(),
+                cutoff_radius: None,
+            })
+        };
+        let point = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 0f64,
+        };
+        let geometries = vec![
+            sphere(0f64, 0f64, 10f64, 1f64),
+            sphere(5f64, 0f64, 5f64, 1f64),
+        ];
+        let mut scene = get_scene(geometries, vec![point_light(5f64), point_light(15f64)]);
+        let accelerations: Vec<fn(&mut SceneObjects)> =
+            vec![|_| (), SceneObjects::build_kd_tree, SceneObjects::build_bvh];
+        for build in accelerations {
+            build(&mut scene.objects);
+            let shadowed = |light: &Light| {
+                let samples = light.get_samples(&point);
+                is_in_shadow(&point, &samples[0], &scene)
+            };
+            assert!(!shadowed(&scene.lights[0]));
+            assert!(shadowed(&scene.lights[1]));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::color::{Color, ColorSpace, BLACK};
+use crate::display::DisplaySettings;
+use crate::scheduler::RenderedTile;
+
+/// Dense, row-major image produced by the engine: pixel `(x, y)` lives at
+/// index `y * width + x`. Pixels hold linear radiance; the byte conversions
+/// go through the display settings.
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    width: u16,
+    height: u16,
+    pixels: Vec<Color>,
+    sample_counts: Vec<u32>,
+    display: DisplaySettings,
+}
+
+impl Framebuffer {
+    pub fn new(width: u16, height: u16) -> Self {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![BLACK; width as usize * height as usize],
+            sample_counts: vec![0; width as usize * height as usize],
+            display: DisplaySettings::default(),
+        }
+    }
+
+    pub fn display(&self) -> &DisplaySettings {
+        &self.display
+    }
+
+    pub fn set_display(&mut self, display: DisplaySettings) {
+        self.display = display;
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        debug_assert!(x < self.width && y < self.height);
+        y as usize * self.width as usize + x as usize
+    }
+
+    fn coordinates(&self, index: usize) -> (u16, u16) {
+        (
+            (index % self.width as usize) as u16,
+            (index / self.width as usize) as u16,
+        )
+    }
+
+    pub fn get_pixel(&self, x: u16, y: u16) -> Color {
+        self.pixels[self.index(x, y)]
+    }
+
+    pub fn set_pixel(&mut self, x: u16, y: u16, color: Color) {
+        let index = self.index(x, y);
+        self.pixels[index] = color;
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Color] {
+        &mut self.pixels
+    }
+
+    pub fn row(&self, y: u16) -> &[Color] {
+        let start = self.index(0, y);
+        &self.pixels[start..start + self.width as usize]
+    }
+
+    pub fn write_tile(&mut self, rendered_tile: &RenderedTile) {
+        let tile = &rendered_tile.tile;
+        for (row, pixels) in rendered_tile
+            .pixels
+            .chunks_exact(tile.width as usize)
+            .enumerate()
+        {
+            let start = self.index(tile.x, tile.y + row as u16);
+            self.pixels[start..start + pixels.len()].copy_from_slice(pixels);
+            let tile_start = row * tile.width as usize;
+            self.sample_counts[start..start + pixels.len()].copy_from_slice(
+                &rendered_tile.sample_counts[tile_start..tile_start + pixels.len()],
+            );
+        }
+    }
+
+    /// Number of camera samples traced for each pixel, row-major.
+    pub fn sample_counts(&self) -> &[u32] {
+        &self.sample_counts
+    }
+
+    /// Debug view of the sample counts, from black (fewest samples) through
+    /// red and yellow to white (most samples).
+    pub fn sample_count_heatmap(&self) -> Framebuffer {
+        let min = self.sample_counts.iter().cloned().min().unwrap_or(0);
+        let max = self.sample_counts.iter().cloned().max().unwrap_or(0);
+        let range = (max - min).max(1) as f64;
+        let mut heatmap = Framebuffer::new(self.width, self.height);
+        // The heatmap colors are picked as display values already.
+        heatmap.set_display(DisplaySettings {
+            encoding: ColorSpace::Linear,
+            ..DisplaySettings::default()
+        });
+        for (index, &count) in self.sample_counts.iter().enumerate() {
+            let t = (count - min) as f64 / range;
+            heatmap.pixels[index] = Color {
+                red: (3f64 * t).min(1f64),
+                green: (3f64 * t - 1f64).clamp(0f64, 1f64),
+                blue: (3f64 * t - 2f64).clamp(0f64, 1f64),
+            };
+            heatmap.sample_counts[index] = count;
+        }
+        heatmap
+    }
+
+    /// Writes the pixels as packed `r, g, b` bytes into `output`, which must
+    /// hold at least `width * height * 3` bytes.
+    pub fn write_rgb8(&self, output: &mut [u8]) {
+        for (index, (pixel, chunk)) in self
+            .pixels
+            .iter()
+            .zip(output.chunks_exact_mut(3))
+            .enumerate()
+        {
+            let (x, y) = self.coordinates(index);
+            chunk.copy_from_slice(&self.display.to_rgb8(pixel, x, y));
+        }
+    }
+
+    /// Writes the pixels as packed `r, g, b, a` bytes into `output`, which must
+    /// hold at least `width * height * 4` bytes. Alpha is always opaque.
+    pub fn write_rgba8(&self, output: &mut [u8]) {
+        for (index, (pixel, chunk)) in self
+            .pixels
+            .iter()
+            .zip(output.chunks_exact_mut(4))
+            .enumerate()
+        {
+            let (x, y) = self.coordinates(index);
+            let [r, g, b] = self.display.to_rgb8(pixel, x, y);
+            chunk.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let mut output = vec![0u8; self.pixels.len() * 3];
+        self.write_rgb8(&mut output);
+        output
+    }
+
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut output = vec![0u8; self.pixels.len() * 4];
+        self.write_rgba8(&mut output);
+        output
+    }
+
+    /// Linear radiance as packed `r, g, b` floats, before any display
+    /// transform.
+    pub fn to_f32(&self) -> Vec<f32> {
+        let mut output = Vec::with_capacity(self.pixels.len() * 3);
+        for pixel in self.pixels.iter() {
+            output.push(pixel.red as f32);
+            output.push(pixel.green as f32);
+            output.push(pixel.blue as f32);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::WHITE;
+
+    #[test]
+    fn framebuffer_is_row_major() {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        framebuffer.set_pixel(2, 1, WHITE);
+
+        assert_eq!(framebuffer.get_pixel(2, 1), WHITE);
+        assert_eq!(framebuffer.pixels()[5], WHITE);
+        assert_eq!(framebuffer.row(1)[2], WHITE);
+        assert_eq!(framebuffer.row(0)[2], BLACK);
+    }
+
+    #[test]
+    fn framebuffer_byte_conversions() {
+        let mut framebuffer = Framebuffer::new(2, 1);
+        framebuffer.set_pixel(1, 0, WHITE);
+
+        assert_eq!(framebuffer.to_rgb8(), vec![0, 0, 0, 255, 255, 255]);
+        assert_eq!(
+            framebuffer.to_rgba8(),
+            vec![0, 0, 0, 255, 255, 255, 255, 255]
+        );
+        assert_eq!(
+            framebuffer.to_f32(),
+            vec![0f32, 0f32, 0f32, 1f32, 1f32, 1f32]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use crate::parser::parse_obj;
+use crate::sampler::uniform_sphere;
 use serde;
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -115,6 +116,39 @@ impl Object {
             Object::MeshTriangle(ref mut _obj) => (),
         }
     }
+
+    /// Surface area, infinite for planes.
+    pub fn area(&self) -> f64 {
+        match *self {
+            Object::Sphere(ref obj) => 4f64 * std::f64::consts::PI * obj.radius * obj.radius,
+            Object::Plane(ref _obj) => f64::INFINITY,
+            Object::MeshTriangle(ref obj) => {
+                let [a, b, c] = obj.get_vertices();
+                (&b - &a).cross(&(&c - &a)).norm() / 2f64
+            }
+        }
+    }
+
+    /// Point of the surface for a sample of the unit square, uniformly
+    /// distributed over the area, with the geometric normal there. Planes
+    /// are unbounded and cannot be sampled.
+    pub fn sample_surface(&self, sample: &Point2) -> Option<(Point3, Vector3)> {
+        match *self {
+            Object::Sphere(ref obj) => {
+                let normal = uniform_sphere(sample);
+                Some((obj.center.add(&normal.times(obj.radius)), normal))
+            }
+            Object::Plane(ref _obj) => None,
+            Object::MeshTriangle(ref obj) => {
+                let [a, b, c] = obj.get_vertices();
+                let root = sample.x.sqrt();
+                let point = a
+                    .add(&(&b - &a).times(root * (1f64 - sample.y)))
+                    .add(&(&c - &a).times(root * sample.y));
+                Some((point, obj.get_normal(&point)))
+            }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -146,6 +180,68 @@ pub struct Mesh {
     pub texture_mapping: Vec<Point2>,
     pub normals: Vec<Vector3>,
     pub triangles: Vec<MeshPlainTriangle>,
+    /// Per triangle, the unit directions in which the texture coordinates
+    /// x and y grow, for normal mapping. Only `new` fills them, in step with
+    /// `triangles`.
+    tangents: Vec<(Vector3, Vector3)>,
+}
+
+impl Mesh {
+    pub fn new(
+        vertices: Vec<Point3>,
+        texture_mapping: Vec<Point2>,
+        normals: Vec<Vector3>,
+        triangles: Vec<MeshPlainTriangle>,
+    ) -> Mesh {
+        let tangents = triangles
+            .iter()
+            .map(|triangle| get_tangents(&vertices, &texture_mapping, triangle))
+            .collect();
+        Mesh {
+            vertices,
+            texture_mapping,
+            normals,
+            triangles,
+            tangents,
+        }
+    }
+
+    pub fn tangents(&self, triangle_index: usize) -> (Vector3, Vector3) {
+        self.tangents[triangle_index]
+    }
+}
+
+// Solves the edges of the triangle for the gradients of the texture
+// coordinates. Triangles without usable coordinates get any tangents.
+fn get_tangents(
+    vertices: &[Point3],
+    texture_mapping: &[Point2],
+    triangle: &MeshPlainTriangle,
+) -> (Vector3, Vector3) {
+    let a = vertices[triangle.vertex_a.vertex_index];
+    let ab = &vertices[triangle.vertex_b.vertex_index] - &a;
+    let ac = &vertices[triangle.vertex_c.vertex_index] - &a;
+    let coordinates = [
+        texture_mapping.get(triangle.vertex_a.texture_index),
+        texture_mapping.get(triangle.vertex_b.texture_index),
+        texture_mapping.get(triangle.vertex_c.texture_index),
+    ];
+    if let [Some(a_t), Some(b_t), Some(c_t)] = coordinates {
+        let (ab_t, ac_t) = (b_t - a_t, c_t - a_t);
+        let determinant = ab_t.x * ac_t.y - ac_t.x * ab_t.y;
+        if determinant.abs() > 1e-12 {
+            let tangent = ab.times(ac_t.y).minus(&ac.times(ab_t.y));
+            let bitangent = ac.times(ab_t.x).minus(&ab.times(ac_t.x));
+            let sign = determinant.signum();
+            if tangent.norm() > 1e-12 && bitangent.norm() > 1e-12 {
+                return (
+                    tangent.times(sign).normalize(),
+                    bitangent.times(sign).normalize(),
+                );
+            }
+        }
+    }
+    ac.cross(&ab).normalize().orthonormal_basis()
 }
 
 pub fn get_triangles<'a>(mesh: Arc<Mesh>) -> Vec<MeshTriangle> {
@@ -179,6 +275,15 @@ pub fn get_triangle_normal(triangle_mesh: &MeshTriangle, uv: &Point2, point: &Po
 }
 
 impl MeshTriangle {
+    pub fn get_vertices(&self) -> [Point3; 3] {
+        let triangle = &self.mesh.triangles[self.triangle_index];
+        [
+            self.mesh.vertices[triangle.vertex_a.vertex_index],
+            self.mesh.vertices[triangle.vertex_b.vertex_index],
+            self.mesh.vertices[triangle.vertex_c.vertex_index],
+        ]
+    }
+
     pub fn get_normal(&self, _point: &Point3) -> Vector3 {
         let triangle = &self.mesh.triangles[self.triangle_index];
 
@@ -223,6 +328,30 @@ pub struct Ray {
     pub direction: Vector3,
 }
 
+/// Cone around a ray, standing for the pixel it is traced for: its width at
+/// the ray origin, and how much wider it gets per unit of distance.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RayCone {
+    pub width: f64,
+    pub spread: f64,
+}
+
+impl RayCone {
+    pub fn width_at(&self, distance: f64) -> f64 {
+        self.width + self.spread * distance
+    }
+
+    /// Cone of a ray leaving the surface hit `distance` away, opened to at
+    /// least `spread` by a rough bounce. The curvature of the surface is
+    /// ignored.
+    pub fn bounced(&self, distance: f64, spread: f64) -> RayCone {
+        RayCone {
+            width: self.width_at(distance),
+            spread: self.spread.max(spread),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct Vector3 {
     pub x: f64,
@@ -285,6 +414,36 @@ impl Vector3 {
             .times(2f64 * self.dot(reference))
             .plus(&(self.times(-1f64)))
     }
+
+    // Rodrigues' rotation formula, `axis` has to be normalized
+    pub fn rotate(&self, axis: &Vector3, angle: f64) -> Vector3 {
+        let cos = angle.cos();
+        let sin = angle.sin();
+        self.times(cos)
+            .plus(&axis.cross(self).times(sin))
+            .plus(&axis.times(axis.dot(self) * (1f64 - cos)))
+    }
+
+    /// Two unit vectors completing this normalized vector into an
+    /// orthonormal basis.
+    pub fn orthonormal_basis(&self) -> (Vector3, Vector3) {
+        let helper = if self.x.abs() > 0.9f64 {
+            Vector3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0f64,
+            }
+        } else {
+            Vector3 {
+                x: 1f64,
+                y: 0f64,
+                z: 0f64,
+            }
+        };
+        let tangent = self.cross(&helper).normalize();
+        let bitangent = self.cross(&tangent);
+        (tangent, bitangent)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
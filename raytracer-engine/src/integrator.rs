@@ -0,0 +1,566 @@
+use crate::color::{Color, BLACK, WHITE};
+use crate::engine::{get_closest_intersection, get_closest_light, is_in_shadow, Scene, TracedRay};
+use crate::geometry::Ray;
+use crate::light::{EmissiveObjects, LightSample};
+use crate::material::{medium_transmittance, ShadingPoint};
+use crate::sampler::Rng;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// How the color seen along a camera ray is computed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Direct Phong lighting plus ideal reflection and refraction, fast
+    /// enough for the interactive frontends.
+    #[default]
+    Whitted,
+    /// Monte Carlo path tracing: a random walk sampling the BSDF at each
+    /// bounce, with next event estimation on the lights weighted against it
+    /// by multiple importance sampling. Paths longer than
+    /// `russian_roulette_depth` may be stopped early, none goes beyond
+    /// `max_depth` bounces. The ambient light becomes a uniform environment
+    /// lighting the scene, and is not seen directly.
+    #[serde(rename_all = "camelCase")]
+    PathTracing {
+        #[serde(default = "default_max_depth")]
+        max_depth: u8,
+        #[serde(default = "default_russian_roulette_depth")]
+        russian_roulette_depth: u8,
+    },
+}
+
+fn default_max_depth() -> u8 {
+    8
+}
+
+fn default_russian_roulette_depth() -> u8 {
+    3
+}
+
+/// Radiance reaching the origin of the camera ray `ray`, estimated from one
+/// random path.
+pub fn trace_path(
+    scene: &Scene,
+    ray: Ray,
+    max_depth: u8,
+    russian_roulette_depth: u8,
+    rng: &mut Rng,
+) -> Color {
+    let objects = &scene.objects.objects;
+    let environment = &scene.ambient_light.color * scene.ambient_light.intensity;
+    let mut radiance = BLACK;
+    let mut throughput = WHITE;
+    let mut traced_ray = TracedRay {
+        ray,
+        inside_objects: vec![],
+        cone: scene.camera.pixel_cone(),
+    };
+    // Density of the BSDF sample the ray comes from, and the number of
+    // emitter samples taken at its origin; `None` for camera rays and delta
+    // lobes, which lights cannot be sampled against. Area lights always take
+    // `Light::sample_count` samples.
+    let mut previous_bounce: Option<(f64, usize)> = None;
+
+    for depth in 0..max_depth {
+        let intersection = get_closest_intersection(scene, &traced_ray);
+        let object_distance = intersection
+            .as_ref()
+            .map_or(f64::INFINITY, |i| i.intersection.distance);
+        if let Some(light) = get_closest_light(scene, &traced_ray.ray, object_distance) {
+            let light_distance = light.intersect(&traced_ray.ray).unwrap_or(object_distance);
+            throughput = &throughput
+                * &medium_transmittance(scene, &traced_ray.inside_objects, light_distance);
+            let weight = match previous_bounce {
+                None => 1f64,
+                Some((bsdf_pdf, _)) => {
+                    let light_point = traced_ray
+                        .ray
+                        .origin
+                        .add(&traced_ray.ray.direction.times(light_distance));
+                    let light_pdf = light.pdf(&traced_ray.ray.origin, &light_point);
+                    bsdf_sample_weight(bsdf_pdf, light_pdf, light.sample_count())
+                }
+            };
+            radiance = &radiance + &(&throughput * &(&light.get_radiance() * weight));
+            break;
+        }
+        throughput =
+            &throughput * &medium_transmittance(scene, &traced_ray.inside_objects, object_distance);
+        let intersection = match intersection {
+            Some(intersection) => intersection,
+            None => {
+                if depth > 0 {
+                    radiance = &radiance + &(&throughput * &environment);
+                }
+                break;
+            }
+        };
+
+        let object = intersection.object;
+        let material = &object.material;
+        let shading = ShadingPoint::at(&traced_ray, &intersection, scene);
+        let point_precise = shading.point;
+
+        if let Some(emission) = material.emission() {
+            let weight = match previous_bounce {
+                None => 1f64,
+                Some((bsdf_pdf, sample_count)) => {
+                    let light_pdf = scene.objects.emitters.pdf(
+                        &traced_ray.ray.origin,
+                        object.id,
+                        &point_precise,
+                        &object.geometry.get_normal(&point_precise),
+                    );
+                    bsdf_sample_weight(bsdf_pdf, light_pdf, sample_count)
+                }
+            };
+            radiance = &radiance + &(&throughput * &(&emission.radiance() * weight));
+        }
+
+        let normal = shading.facing_normal();
+        // rays leave from the side of the geometry, which normal mapping
+        // does not move
+        let side = shading.facing_geometric_normal();
+        let point = point_precise.add(&side.times(1e-6));
+        let behind = point_precise.add(&side.times(-1e-6));
+        let mut sample_count = 0;
+        if material.has_non_delta_lobes() {
+            // lights behind the surface only show through transmission lobes
+            let mut add_light = |light_sample: &LightSample, light_color: &Color, weight: f64| {
+                let to_light = light_sample.direction.times(-1f64);
+                let cosine = normal.dot(&to_light);
+                if light_sample.intensity <= 0f64 || cosine == 0f64 {
+                    return;
+                }
+                let bsdf = material.evaluate(&shading, &to_light);
+                let origin = if side.dot(&to_light) > 0f64 {
+                    &point
+                } else {
+                    &behind
+                };
+                if bsdf == BLACK || is_in_shadow(origin, light_sample, scene) {
+                    return;
+                }
+                let light_color =
+                    light_color * (light_sample.intensity * PI * cosine.abs() * weight);
+                radiance = &radiance + &(&(&bsdf * &light_color) * &throughput);
+            };
+            for light in &scene.lights {
+                let color = light.get_color();
+                let light_samples = light.get_samples(&point);
+                for light_sample in light_samples.iter() {
+                    let to_light = light_sample.direction.times(-1f64);
+                    let light_point = point.add(&to_light.times(light_sample.distance));
+                    // zero for point and directional lights, which the BSDF never finds
+                    let light_pdf = light.pdf(&point, &light_point);
+                    let weight = light_sample_weight(
+                        light_pdf,
+                        light_samples.len(),
+                        material.pdf(&shading, &to_light),
+                    );
+                    add_light(light_sample, &color, weight);
+                }
+            }
+            let emitter_samples = scene
+                .objects
+                .emitters
+                .get_samples_with(&point, objects, rng);
+            sample_count = emitter_samples.len();
+            for (light_sample, emitted) in emitter_samples.iter() {
+                let to_light = light_sample.direction.times(-1f64);
+                let light_pdf = EmissiveObjects::solid_angle_density(light_sample, sample_count);
+                let weight =
+                    light_sample_weight(light_pdf, sample_count, material.pdf(&shading, &to_light));
+                add_light(light_sample, emitted, weight);
+            }
+        }
+
+        let sample = match material.sample(&shading, rng) {
+            Some(sample) => sample,
+            None => break,
+        };
+        throughput = &throughput * &sample.weight;
+        previous_bounce = if sample.delta {
+            None
+        } else {
+            Some((sample.pdf, sample_count))
+        };
+
+        let leaving = sample.direction.dot(&side) > 0f64;
+        let mut inside_objects = traced_ray.inside_objects;
+        if !leaving {
+            if shading.geometric_normal.dot(&traced_ray.ray.direction) < 0f64 {
+                inside_objects.push(object.id);
+            } else {
+                inside_objects.retain(|&id| id != object.id);
+            }
+        }
+        // a rough bounce blurs what the path sees next, by about the width
+        // of the lobe it was sampled from
+        let spread = if sample.delta {
+            0f64
+        } else {
+            (1f64 / sample.pdf).sqrt()
+        };
+        traced_ray = TracedRay {
+            ray: Ray {
+                origin: point_precise.add(&side.times(if leaving { 1e-6 } else { -1e-6 })),
+                direction: sample.direction,
+            },
+            inside_objects,
+            cone: traced_ray
+                .cone
+                .bounced(intersection.intersection.distance, spread),
+        };
+
+        if depth + 1 >= russian_roulette_depth {
+            let survival = throughput
+                .red
+                .max(throughput.green)
+                .max(throughput.blue)
+                .min(0.95f64);
+            if survival <= 0f64 || rng.next_f64() >= survival {
+                break;
+            }
+            throughput = &throughput * (1f64 / survival);
+        }
+    }
+    radiance
+}
+
+/// MIS weight of one of `sample_count` light samples drawn with density
+/// `light_pdf`, against the BSDF sample of density `bsdf_pdf` which could
+/// have found the same point. Lights of null density are not found by the
+/// BSDF and keep their full weight.
+fn light_sample_weight(light_pdf: f64, sample_count: usize, bsdf_pdf: f64) -> f64 {
+    if light_pdf <= 0f64 {
+        return 1f64;
+    }
+    power_heuristic(light_pdf * sample_count as f64, bsdf_pdf)
+}
+
+/// MIS weight of a BSDF sample of density `bsdf_pdf` hitting a light, against
+/// the `sample_count` light samples of density `light_pdf` which could have
+/// found the same point.
+fn bsdf_sample_weight(bsdf_pdf: f64, light_pdf: f64, sample_count: usize) -> f64 {
+    power_heuristic(bsdf_pdf, light_pdf * sample_count as f64)
+}
+
+/// Veach's power heuristic, weighting the strategy with density `pdf`
+/// against the one with density `other_pdf`.
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    if pdf.is_infinite() {
+        return 1f64;
+    }
+    let pdf_squared = pdf * pdf;
+    let sum = pdf_squared + other_pdf * other_pdf;
+    if sum <= 0f64 {
+        0f64
+    } else {
+        pdf_squared / sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::engine::{cast_ray, SceneObject, SceneObjects};
+    use crate::geometry::{Object, Plane, Point3, RayCone, Sphere, Vector3};
+    use crate::light::{AmbientLight, Light, RectangleLight};
+    use crate::material::{Coloration, Emission, Material, PhongMaterial, Scalar, SurfaceDetail};
+
+    fn material(diffuse_reflection: f64, emission: Option<Emission>) -> Material {
+        Material::new(PhongMaterial {
+            ambient_color: Coloration::Color(WHITE),
+            ambient_reflection: Scalar::Value(0f64),
+            diffuse_color: Coloration::Color(WHITE),
+            diffuse_reflection: Scalar::Value(diffuse_reflection),
+            specular_color: Coloration::Color(WHITE),
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(1f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(1f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission,
+        })
+    }
+
+    #[test]
+    fn path_tracing_agrees_with_whitted_on_direct_light() {
+        let origin = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 0f64,
+        };
+        let up = Vector3 {
+            x: 0f64,
+            y: 1f64,
+            z: 0f64,
+        };
+        let objects = vec![
+            SceneObject {
+                id: 0,
+                geometry: Object::Plane(Plane {
+                    point: origin,
+                    normal: up,
+                }),
+                material: material(0.8f64, None),
+            },
+            SceneObject {
+                id: 1,
+                geometry: Object::Sphere(Sphere {
+                    center: Point3 {
+                        x: 1f64,
+                        y: 1.5f64,
+                        z: 0f64,
+                    },
+                    radius: 0.5f64,
+                }),
+                material: material(
+                    0f64,
+                    Some(Emission {
+                        color: WHITE,
+                        strength: 2f64,
+                    }),
+                ),
+            },
+        ];
+        let mut scene = Scene {
+            objects: SceneObjects::initialize(objects),
+            ambient_light: AmbientLight {
+                color: WHITE,
+                intensity: 0f64,
+            },
+            lights: vec![],
+            camera: Camera::look_at(
+                Point3 {
+                    x: 0f64,
+                    y: 1f64,
+                    z: -1f64,
+                },
+                &origin,
+                up,
+                1f64,
+                1,
+                1,
+            ),
+        };
+        let ray = Ray {
+            origin: scene.camera.position,
+            direction: (&origin - &scene.camera.position).normalize(),
+        };
+
+        scene.objects.emitters.samples = 4096;
+        let traced_ray = TracedRay {
+            ray: ray.clone(),
+            inside_objects: vec![],
+            cone: RayCone::default(),
+        };
+        let whitted = cast_ray(&scene, &traced_ray, 0).red;
+
+        scene.objects.emitters.samples = 16;
+        let paths = 4000;
+        let mut path_traced = 0f64;
+        for seed in 0..paths {
+            let mut rng = Rng::new(seed);
+            path_traced += trace_path(&scene, ray.clone(), 4, 4, &mut rng).red;
+        }
+        path_traced /= paths as f64;
+
+        assert!(whitted > 0f64);
+        assert!(
+            (path_traced / whitted - 1f64).abs() < 0.03f64,
+            "{} {}",
+            path_traced,
+            whitted
+        );
+    }
+
+    // Diffuse floor at y = 0 under an emissive sphere and a rectangle light,
+    // seen from above.
+    fn get_lit_floor(emission: Option<Emission>, lights: Vec<Light>) -> Scene {
+        let origin = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 0f64,
+        };
+        let up = Vector3 {
+            x: 0f64,
+            y: 1f64,
+            z: 0f64,
+        };
+        let mut objects = vec![SceneObject {
+            id: 0,
+            geometry: Object::Plane(Plane {
+                point: origin,
+                normal: up,
+            }),
+            material: material(0.8f64, None),
+        }];
+        if emission.is_some() {
+            objects.push(SceneObject {
+                id: 1,
+                geometry: Object::Sphere(Sphere {
+                    center: Point3 {
+                        x: -1f64,
+                        y: 1.5f64,
+                        z: 0f64,
+                    },
+                    radius: 0.5f64,
+                }),
+                material: material(0f64, emission),
+            });
+        }
+        Scene {
+            objects: SceneObjects::initialize(objects),
+            ambient_light: AmbientLight {
+                color: WHITE,
+                intensity: 0f64,
+            },
+            lights,
+            camera: Camera::look_at(
+                Point3 {
+                    x: 0f64,
+                    y: 1f64,
+                    z: -1f64,
+                },
+                &origin,
+                up,
+                1f64,
+                1,
+                1,
+            ),
+        }
+    }
+
+    fn rectangle_light() -> Light {
+        Light::RectangleLight(RectangleLight {
+            corner: Point3 {
+                x: 0.5f64,
+                y: 1.5f64,
+                z: -0.5f64,
+            },
+            edge_u: Vector3 {
+                x: 1f64,
+                y: 0f64,
+                z: 0f64,
+            },
+            edge_v: Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+            radiance: 2f64,
+            color: WHITE,
+            samples: 16,
+        })
+    }
+
+    fn camera_ray(scene: &Scene) -> Ray {
+        Ray {
+            origin: scene.camera.position,
+            direction: scene.camera.direction,
+        }
+    }
+
+    #[test]
+    fn light_and_bsdf_samples_of_a_point_have_complementary_weights() {
+        let emission = Emission {
+            color: WHITE,
+            strength: 2f64,
+        };
+        let scene = get_lit_floor(Some(emission), vec![rectangle_light()]);
+        let traced_ray = TracedRay {
+            ray: camera_ray(&scene),
+            inside_objects: vec![],
+            cone: RayCone::default(),
+        };
+        let intersection = get_closest_intersection(&scene, &traced_ray).unwrap();
+        let shading = ShadingPoint::at(&traced_ray, &intersection, &scene);
+        let point = shading.point.add(&shading.normal.times(1e-6));
+        let material = &intersection.object.material;
+        // whichever strategy finds a point of a light, the weights the
+        // path gives it sum to one
+        let assert_complementary = |light_sample: &LightSample,
+                                    light_pdf: f64,
+                                    sample_count: usize,
+                                    bsdf_light_pdf: f64| {
+            let bsdf_pdf = material.pdf(&shading, &light_sample.direction.times(-1f64));
+            assert!(bsdf_pdf > 0f64 && light_pdf > 0f64);
+            let light_weight = light_sample_weight(light_pdf, sample_count, bsdf_pdf);
+            let bsdf_weight = bsdf_sample_weight(bsdf_pdf, bsdf_light_pdf, sample_count);
+            assert!(
+                (light_weight + bsdf_weight - 1f64).abs() < 1e-6,
+                "{} {}",
+                light_weight,
+                bsdf_weight
+            );
+        };
+
+        let objects = &scene.objects.objects;
+        let emitters = &scene.objects.emitters;
+        let samples = emitters.get_samples(&point, objects);
+        assert_eq!(samples.len(), 16);
+        for (light_sample, _) in samples.iter() {
+            let to_light = light_sample.direction.times(-1f64);
+            let light_point = point.add(&to_light.times(light_sample.distance + 1e-6));
+            let light_normal = objects[1].geometry.get_normal(&light_point);
+            assert_complementary(
+                light_sample,
+                EmissiveObjects::solid_angle_density(light_sample, samples.len()),
+                samples.len(),
+                emitters.pdf(&point, 1, &light_point, &light_normal),
+            );
+        }
+
+        let light = &scene.lights[0];
+        let samples = light.get_samples(&point);
+        assert_eq!(samples.len(), light.sample_count());
+        for light_sample in samples.iter() {
+            let to_light = light_sample.direction.times(-1f64);
+            let ray = Ray {
+                origin: point,
+                direction: to_light,
+            };
+            let distance = light.intersect(&ray).unwrap();
+            assert!((distance - light_sample.distance).abs() < 1e-9);
+            let light_point = point.add(&to_light.times(distance));
+            let light_pdf = light.pdf(&point, &light_point);
+            // the density also follows from the intensity of the sample
+            let expected = 2f64 / (PI * light_sample.intensity * samples.len() as f64);
+            assert!((light_pdf / expected - 1f64).abs() < 1e-9);
+            assert_complementary(light_sample, light_pdf, samples.len(), light_pdf);
+        }
+    }
+
+    #[test]
+    fn path_tracing_agrees_with_whitted_on_area_lights() {
+        let scene = get_lit_floor(None, vec![rectangle_light()]);
+        let ray = camera_ray(&scene);
+        let traced_ray = TracedRay {
+            ray: ray.clone(),
+            inside_objects: vec![],
+            cone: RayCone::default(),
+        };
+        let whitted = cast_ray(&scene, &traced_ray, 0).red;
+
+        let paths = 2000;
+        let mut path_traced = 0f64;
+        for seed in 0..paths {
+            let mut rng = Rng::new(seed);
+            path_traced += trace_path(&scene, ray.clone(), 4, 4, &mut rng).red;
+        }
+        path_traced /= paths as f64;
+
+        assert!(whitted > 0f64);
+        assert!(
+            (path_traced / whitted - 1f64).abs() < 0.03f64,
+            "{} {}",
+            path_traced,
+            whitted
+        );
+    }
+}
@@ -203,8 +203,8 @@ mod tests {
 
     #[test]
     fn triangle_intersection() {
-        let mesh = Arc::new(Mesh {
-            vertices: vec![
+        let mesh = Arc::new(Mesh::new(
+            vec![
                 Point3 {
                     x: 0f64,
                     y: 0f64,
@@ -221,9 +221,9 @@ mod tests {
                     z: 0f64,
                 },
             ],
-            texture_mapping: vec![],
-            normals: vec![],
-            triangles: vec![MeshPlainTriangle {
+            vec![],
+            vec![],
+            vec![MeshPlainTriangle {
                 vertex_a: MeshVertex {
                     vertex_index: 0,
                     normal_index: 0,
@@ -240,7 +240,7 @@ mod tests {
                     texture_index: 0,
                 },
             }],
-        });
+        ));
         let ray = Ray {
             origin: Point3 {
                 x: 0.5f64,
@@ -2,11 +2,13 @@ use crate::engine::SceneObject;
 use crate::engine::SceneObjectId;
 use crate::geometry::MeshTriangle;
 use crate::geometry::Object;
+use crate::geometry::Point3;
 use crate::geometry::Ray;
 use crate::geometry::Sphere;
-use std::collections::HashSet;
+use crate::geometry::Vector3;
+use crate::intersectable::Intersection;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub struct AxisAlignedBoundingBox {
     pub min_x: f64,
     pub max_x: f64,
@@ -30,313 +32,364 @@ impl AxisAlignedBoundingBoxable for Object {
     }
 }
 
-pub fn build_kd_tree(objects: &Vec<SceneObject>) -> KDTree {
-    let mut kd_tree = initialize_kd_tree(objects);
-    split_kd_tree(&mut kd_tree.tree, &kd_tree.objects_aabb, Direction::X, 0);
-    //println!("{:?}", kd_tree.tree);
-    kd_tree
+/// Cost model of the Surface Area Heuristic used to choose the kd-tree
+/// splits. Costs are relative to each other, only their ratio matters.
+#[derive(Debug, Clone)]
+pub struct KDTreeSettings {
+    /// Cost of stepping through an interior node.
+    pub traversal_cost: f64,
+    /// Cost of testing the ray against one object.
+    pub intersection_cost: f64,
+    /// Fraction of the cost saved when one side of a split is empty.
+    pub empty_bonus: f64,
+    /// Nodes holding at most this many objects are not split any further.
+    pub max_leaf_size: usize,
+    /// Depth limit, derived from the number of objects when `None`.
+    pub max_depth: Option<u8>,
 }
 
-pub fn initialize_kd_tree(objects: &Vec<SceneObject>) -> KDTree {
-    let mut min_x: f64 = std::f64::MAX;
-    let mut max_x: f64 = std::f64::MIN;
-    let mut min_y: f64 = std::f64::MAX;
-    let mut max_y: f64 = std::f64::MIN;
-    let mut min_z: f64 = std::f64::MAX;
-    let mut max_z: f64 = std::f64::MIN;
+impl Default for KDTreeSettings {
+    fn default() -> Self {
+        KDTreeSettings {
+            traversal_cost: 1f64,
+            intersection_cost: 80f64,
+            empty_bonus: 0.5f64,
+            max_leaf_size: 1,
+            max_depth: None,
+        }
+    }
+}
 
+pub fn build_kd_tree(objects: &[SceneObject], settings: &KDTreeSettings) -> KDTree {
     let objects_aabb: Vec<Option<AxisAlignedBoundingBox>> = objects
         .iter()
         .map(|object| object.geometry.get_aabb())
         .collect();
 
-    for object_aabb in objects_aabb.iter() {
-        match object_aabb {
-            Option::Some(aabb) => {
-                if aabb.min_x < min_x {
-                    min_x = aabb.min_x;
-                }
-                if aabb.min_y < min_y {
-                    min_y = aabb.min_y;
-                }
-                if aabb.min_z < min_z {
-                    min_z = aabb.min_z;
-                }
-                if aabb.max_x > max_x {
-                    max_x = aabb.max_x;
-                }
-                if aabb.max_y > max_y {
-                    max_y = aabb.max_y;
-                }
-                if aabb.max_z > max_z {
-                    max_z = aabb.max_z;
-                }
+    let mut bounds = AxisAlignedBoundingBox::empty();
+    let mut bounded_objects: Vec<SceneObjectId> = vec![];
+    let mut unbounded_objects: Vec<SceneObjectId> = vec![];
+    for object in objects.iter() {
+        match &objects_aabb[object.id] {
+            Some(aabb) => {
+                bounds = bounds.union(aabb);
+                bounded_objects.push(object.id);
             }
-            None => continue,
+            None => unbounded_objects.push(object.id),
         }
     }
-    let scene_aabb: AxisAlignedBoundingBox = AxisAlignedBoundingBox {
-        min_x: min_x,
-        max_x: max_x,
-        min_y: min_y,
-        max_y: max_y,
-        min_z: min_z,
-        max_z: max_z,
-    };
-
-    let objects_indices: HashSet<SceneObjectId> = objects.iter().map(|object| object.id).collect();
 
-    KDTree {
-        objects_aabb: objects_aabb,
-        tree: KDTreeNode {
-            aabb: scene_aabb,
-            objects: objects_indices,
-            left: None,
-            right: None,
-        },
-    }
+    let max_depth = settings.max_depth.unwrap_or_else(|| {
+        (8f64 + 1.3f64 * (bounded_objects.len().max(1) as f64).log2()).round() as u8
+    });
+
+    let mut kd_tree = KDTree {
+        objects_aabb,
+        bounds,
+        unbounded_objects,
+        nodes: vec![],
+        leaf_objects: vec![],
+        settings: settings.clone(),
+    };
+    kd_tree.build_node(bounds, bounded_objects, settings, max_depth);
+    kd_tree
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Direction {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
     X,
     Y,
     Z,
 }
 
+pub const DIRECTIONS: [Direction; 3] = [Direction::X, Direction::Y, Direction::Z];
+
 impl Direction {
-    pub fn next(&self) -> Direction {
+    pub fn of_point(&self, point: &Point3) -> f64 {
         match self {
-            Direction::X => Direction::Y,
-            Direction::Y => Direction::Z,
-            Direction::Z => Direction::X,
+            Direction::X => point.x,
+            Direction::Y => point.y,
+            Direction::Z => point.z,
         }
     }
-}
 
-fn split_kd_tree(
-    kd_tree: &mut KDTreeNode,
-    aabb: &Vec<Option<AxisAlignedBoundingBox>>,
-    direction: Direction,
-    depth: u8,
-) {
-    if depth > 5 {
-        return;
+    pub fn of_vector(&self, vector: &Vector3) -> f64 {
+        match self {
+            Direction::X => vector.x,
+            Direction::Y => vector.y,
+            Direction::Z => vector.z,
+        }
     }
+}
 
-    let mut left: HashSet<SceneObjectId> = HashSet::new();
-    let mut right: HashSet<SceneObjectId> = HashSet::new();
-    let left_aabb: AxisAlignedBoundingBox;
-    let right_aabb: AxisAlignedBoundingBox;
-    let split: f64;
-    match direction {
-        Direction::X => {
-            split = (kd_tree.aabb.min_x + kd_tree.aabb.max_x) / 2f64;
-
-            left_aabb = AxisAlignedBoundingBox {
-                min_x: kd_tree.aabb.min_x,
-                max_x: split,
-                min_y: kd_tree.aabb.min_y,
-                max_y: kd_tree.aabb.max_y,
-                min_z: kd_tree.aabb.min_z,
-                max_z: kd_tree.aabb.max_z,
-            };
-            right_aabb = AxisAlignedBoundingBox {
-                min_x: split,
-                max_x: kd_tree.aabb.max_x,
-                min_y: kd_tree.aabb.min_y,
-                max_y: kd_tree.aabb.max_y,
-                min_z: kd_tree.aabb.min_z,
-                max_z: kd_tree.aabb.max_z,
-            };
+/// Nodes are stored depth first: the child below the split plane directly
+/// follows its parent, the index of the one above is kept in the node.
+/// Leaves point to a range of `KDTree::leaf_objects`.
+#[derive(Debug, Copy, Clone)]
+pub enum KDTreeNode {
+    Split {
+        direction: Direction,
+        position: f64,
+        above_child: usize,
+    },
+    Leaf {
+        first: usize,
+        count: usize,
+    },
+}
+
+pub struct KDTree {
+    pub objects_aabb: Vec<Option<AxisAlignedBoundingBox>>,
+    pub bounds: AxisAlignedBoundingBox,
+    /// Objects without a bounding box (planes), tested against every ray.
+    pub unbounded_objects: Vec<SceneObjectId>,
+    pub nodes: Vec<KDTreeNode>,
+    pub leaf_objects: Vec<SceneObjectId>,
+    pub settings: KDTreeSettings,
+}
+
+impl KDTree {
+    fn build_node(
+        &mut self,
+        bounds: AxisAlignedBoundingBox,
+        objects: Vec<SceneObjectId>,
+        settings: &KDTreeSettings,
+        depth_left: u8,
+    ) {
+        let split = if objects.len() <= settings.max_leaf_size || depth_left == 0 {
+            None
+        } else {
+            find_split(&self.objects_aabb, &bounds, &objects, settings)
+        };
+
+        let (direction, position) = match split {
+            Some(split) => split,
+            None => {
+                self.nodes.push(KDTreeNode::Leaf {
+                    first: self.leaf_objects.len(),
+                    count: objects.len(),
+                });
+                self.leaf_objects.extend(objects);
+                return;
+            }
+        };
+
+        let mut below: Vec<SceneObjectId> = vec![];
+        let mut above: Vec<SceneObjectId> = vec![];
+        for &index in objects.iter() {
+            let (min, max) = self.object_extent(index, direction);
+            if min <= position {
+                below.push(index);
+            }
+            if max >= position {
+                above.push(index);
+            }
         }
-        Direction::Y => {
-            split = (kd_tree.aabb.min_y + kd_tree.aabb.max_y) / 2f64;
-
-            left_aabb = AxisAlignedBoundingBox {
-                min_x: kd_tree.aabb.min_x,
-                max_x: kd_tree.aabb.max_x,
-                min_y: kd_tree.aabb.min_y,
-                max_y: split,
-                min_z: kd_tree.aabb.min_z,
-                max_z: kd_tree.aabb.max_z,
-            };
-            right_aabb = AxisAlignedBoundingBox {
-                min_x: kd_tree.aabb.min_x,
-                max_x: kd_tree.aabb.max_x,
-                min_y: split,
-                max_y: kd_tree.aabb.max_y,
-                min_z: kd_tree.aabb.min_z,
-                max_z: kd_tree.aabb.max_z,
-            };
+
+        let node_index = self.nodes.len();
+        self.nodes.push(KDTreeNode::Split {
+            direction,
+            position,
+            above_child: 0,
+        });
+        let (below_bounds, above_bounds) = bounds.split(direction, position);
+        self.build_node(below_bounds, below, settings, depth_left - 1);
+        let above_index = self.nodes.len();
+        if let KDTreeNode::Split { above_child, .. } = &mut self.nodes[node_index] {
+            *above_child = above_index;
         }
-        Direction::Z => {
-            split = (kd_tree.aabb.min_z + kd_tree.aabb.max_z) / 2f64;
-
-            left_aabb = AxisAlignedBoundingBox {
-                min_x: kd_tree.aabb.min_x,
-                max_x: kd_tree.aabb.max_x,
-                min_y: kd_tree.aabb.min_y,
-                max_y: kd_tree.aabb.max_y,
-                min_z: kd_tree.aabb.min_z,
-                max_z: split,
-            };
-            right_aabb = AxisAlignedBoundingBox {
-                min_x: kd_tree.aabb.min_x,
-                max_x: kd_tree.aabb.max_x,
-                min_y: kd_tree.aabb.min_y,
-                max_y: kd_tree.aabb.max_y,
-                min_z: split,
-                max_z: kd_tree.aabb.max_z,
-            };
+        self.build_node(above_bounds, above, settings, depth_left - 1);
+    }
+
+    fn object_extent(&self, index: SceneObjectId, direction: Direction) -> (f64, f64) {
+        match &self.objects_aabb[index] {
+            Some(aabb) => aabb.extent(direction),
+            None => (f64::NEG_INFINITY, f64::INFINITY),
         }
     }
 
-    let mut unboud_objects: HashSet<SceneObjectId> = HashSet::new();
-    let initial_objects_count = kd_tree.objects.len();
-
-    for &aabb_index in kd_tree.objects.iter() {
-        let aabb_option: &Option<AxisAlignedBoundingBox> = &aabb[aabb_index];
-        match aabb_option {
-            Some(aabb) => match direction {
-                Direction::X => {
-                    if aabb.min_x > split {
-                        right.insert(aabb_index);
-                    } else if aabb.max_x < split {
-                        left.insert(aabb_index);
-                    } else {
-                        right.insert(aabb_index);
-                        left.insert(aabb_index);
+    /// Closest intersection along the ray, as given by `intersect` for the
+    /// objects the ray may hit. Leaves are visited front to back, and the
+    /// traversal stops once a hit lies before the end of the current leaf.
+    pub fn get_closest_intersection<F>(
+        &self,
+        ray: &Ray,
+        mut intersect: F,
+    ) -> Option<(SceneObjectId, Intersection)>
+    where
+        F: FnMut(SceneObjectId) -> Option<Intersection>,
+    {
+        let mut closest: Option<(SceneObjectId, Intersection)> = None;
+        let mut test =
+            |index: SceneObjectId, closest: &mut Option<(SceneObjectId, Intersection)>| {
+                if let Some(intersection) = intersect(index) {
+                    let is_closer = closest
+                        .as_ref()
+                        .is_none_or(|(_, best)| intersection.distance < best.distance);
+                    if is_closer {
+                        *closest = Some((index, intersection));
                     }
                 }
-                Direction::Y => {
-                    if aabb.min_y > split {
-                        right.insert(aabb_index);
-                    } else if aabb.max_y < split {
-                        left.insert(aabb_index);
-                    } else {
-                        right.insert(aabb_index);
-                        left.insert(aabb_index);
-                    }
-                }
-                Direction::Z => {
-                    if aabb.min_z > split {
-                        right.insert(aabb_index);
-                    } else if aabb.max_z < split {
-                        left.insert(aabb_index);
-                    } else {
-                        right.insert(aabb_index);
-                        left.insert(aabb_index);
-                    }
-                }
-            },
-            None => {
-                unboud_objects.insert(aabb_index);
-            }
+            };
+
+        for &index in self.unbounded_objects.iter() {
+            test(index, &mut closest);
         }
+        self.traverse(ray, f64::INFINITY, |objects, leaf_exit| {
+            for &index in objects.iter() {
+                test(index, &mut closest);
+            }
+            closest
+                .as_ref()
+                .is_some_and(|(_, best)| best.distance <= leaf_exit)
+        });
+        closest
     }
 
-    let stable = left.len() == initial_objects_count && right.len() < initial_objects_count;
-    if stable {
-        return;
+    /// Whether `predicate` holds for an object that the ray may hit within
+    /// `max_distance`. Stops as soon as one is found.
+    pub fn any_object_intersecting<F>(&self, ray: &Ray, max_distance: f64, mut predicate: F) -> bool
+    where
+        F: FnMut(SceneObjectId) -> bool,
+    {
+        self.unbounded_objects.iter().any(|&index| predicate(index))
+            || self.traverse(ray, max_distance, |objects, _leaf_exit| {
+                objects.iter().any(|&index| predicate(index))
+            })
     }
 
-    kd_tree.objects = unboud_objects;
-    if !left.is_empty() {
-        let mut left_kd_tree = KDTreeNode {
-            aabb: left_aabb,
-            objects: left,
-            left: None,
-            right: None,
+    /// Walks the leaves crossed by the ray in front to back order, handing
+    /// `visit` their objects and the distance at which the ray leaves them.
+    /// Returns true as soon as `visit` does.
+    fn traverse<F>(&self, ray: &Ray, max_distance: f64, mut visit: F) -> bool
+    where
+        F: FnMut(&[SceneObjectId], f64) -> bool,
+    {
+        let (mut t_min, mut t_max) = match self.bounds.intersection_range(ray) {
+            Some(range) => range,
+            None => return false,
         };
-        split_kd_tree(&mut left_kd_tree, aabb, direction.next(), depth + 1);
-        kd_tree.left = Some(Box::new(left_kd_tree));
-    }
+        let mut pending: Vec<(usize, f64, f64)> = vec![];
+        let mut node_index = 0usize;
+        loop {
+            if t_min > max_distance {
+                return false;
+            }
+            match self.nodes[node_index] {
+                KDTreeNode::Split {
+                    direction,
+                    position,
+                    above_child,
+                } => {
+                    let origin = direction.of_point(&ray.origin);
+                    let ray_direction = direction.of_vector(&ray.direction);
+                    let t_split = (position - origin) / ray_direction;
+                    let below_first =
+                        origin < position || (origin == position && ray_direction <= 0f64);
+                    let (first, second) = if below_first {
+                        (node_index + 1, above_child)
+                    } else {
+                        (above_child, node_index + 1)
+                    };
 
-    if !right.is_empty() {
-        let mut right_kd_tree = KDTreeNode {
-            aabb: right_aabb,
-            objects: right,
-            left: None,
-            right: None,
-        };
-        split_kd_tree(&mut right_kd_tree, aabb, direction.next(), depth + 1);
-        kd_tree.right = Some(Box::new(right_kd_tree));
+                    if t_split.is_nan() || t_split > t_max || t_split <= 0f64 {
+                        node_index = first;
+                    } else if t_split < t_min {
+                        node_index = second;
+                    } else {
+                        pending.push((second, t_split, t_max));
+                        node_index = first;
+                        t_max = t_split;
+                    }
+                }
+                KDTreeNode::Leaf { first, count } => {
+                    if visit(&self.leaf_objects[first..first + count], t_max) {
+                        return true;
+                    }
+                    match pending.pop() {
+                        Some((next, next_min, next_max)) => {
+                            node_index = next;
+                            t_min = next_min;
+                            t_max = next_max;
+                        }
+                        None => return false,
+                    }
+                }
+            }
+        }
     }
 }
 
-pub struct KDTree {
-    pub objects_aabb: Vec<Option<AxisAlignedBoundingBox>>,
-    pub tree: KDTreeNode,
-}
-
-#[derive(Debug)]
-pub struct KDTreeNode {
-    pub aabb: AxisAlignedBoundingBox,
-    pub objects: HashSet<SceneObjectId>,
-    pub left: Option<Box<KDTreeNode>>,
-    pub right: Option<Box<KDTreeNode>>,
-}
-
-impl KDTree {
-    pub fn get_leafs_intersecting(&self, ray: &Ray) -> HashSet<SceneObjectId> {
-        let mut leafs = vec![];
-        self.tree.fill_leafs_intersecting(ray, &mut leafs);
-        let objects: HashSet<SceneObjectId> = leafs
-            .iter()
-            .map(|node| node.objects.iter())
-            .flatten()
-            .map(|&x| x)
-            .collect();
-        objects
+/// Split plane minimizing the SAH cost, if splitting is cheaper than
+/// testing every object of the node.
+fn find_split(
+    objects_aabb: &[Option<AxisAlignedBoundingBox>],
+    bounds: &AxisAlignedBoundingBox,
+    objects: &[SceneObjectId],
+    settings: &KDTreeSettings,
+) -> Option<(Direction, f64)> {
+    let total_area = bounds.surface_area();
+    if total_area <= 0f64 {
+        return None;
     }
-}
+    let leaf_cost = settings.intersection_cost * objects.len() as f64;
+    let mut best: Option<(f64, Direction, f64)> = None;
 
-impl KDTreeNode {
-    fn fill_leafs_intersecting<'a>(&'a self, ray: &Ray, leafs: &mut Vec<&'a KDTreeNode>) -> () {
-        if self.aabb.intersects(ray) {
-            let mut left_leaf = false;
-            let mut right_leaf = true;
-            match &self.left {
-                Some(node) => node.fill_leafs_intersecting(ray, leafs),
-                None => left_leaf = true,
+    for &direction in DIRECTIONS.iter() {
+        let (node_min, node_max) = bounds.extent(direction);
+        if node_max <= node_min {
+            continue;
+        }
+        let mut mins: Vec<f64> = vec![];
+        let mut maxs: Vec<f64> = vec![];
+        for &index in objects.iter() {
+            if let Some(aabb) = &objects_aabb[index] {
+                let (min, max) = aabb.extent(direction);
+                mins.push(min);
+                maxs.push(max);
             }
-            match &self.right {
-                Some(node) => node.fill_leafs_intersecting(ray, leafs),
-                None => right_leaf = true,
+        }
+        mins.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        maxs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        for &position in mins.iter().chain(maxs.iter()) {
+            if position <= node_min || position >= node_max {
+                continue;
             }
-            if left_leaf && right_leaf {
-                leafs.push(self);
+            // same rule as the partition in `build_node`
+            let below_count = mins.partition_point(|&min| min <= position);
+            let above_count = maxs.len() - maxs.partition_point(|&max| max < position);
+            let (below, above) = bounds.split(direction, position);
+            let bonus = if below_count == 0 || above_count == 0 {
+                settings.empty_bonus
+            } else {
+                0f64
+            };
+            let cost = settings.traversal_cost
+                + settings.intersection_cost
+                    * (1f64 - bonus)
+                    * (below.surface_area() * below_count as f64
+                        + above.surface_area() * above_count as f64)
+                    / total_area;
+            if best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, direction, position));
             }
         }
     }
+
+    best.filter(|&(cost, _, _)| cost < leaf_cost)
+        .map(|(_, direction, position)| (direction, position))
 }
 
 macro_rules! min {
     ($x:expr, $y:expr, $z:expr) => {
-        if $y < $x {
-            if $z < $y {
-                $z
-            } else {
-                $y
-            }
-        } else {
-            $x
-        }
+        $x.min($y).min($z)
     };
 }
 
 macro_rules! max {
     ($x:expr, $y:expr, $z:expr) => {
-        if $y > $x {
-            if $z > $y {
-                $z
-            } else {
-                $y
-            }
-        } else {
-            $x
-        }
+        $x.max($y).max($z)
     };
 }
 
@@ -371,6 +424,124 @@ impl AxisAlignedBoundingBoxable for Sphere {
 }
 
 impl AxisAlignedBoundingBox {
+    /// Box containing nothing, the neutral element of `union`.
+    pub fn empty() -> Self {
+        AxisAlignedBoundingBox {
+            min_x: f64::INFINITY,
+            max_x: f64::NEG_INFINITY,
+            min_y: f64::INFINITY,
+            max_y: f64::NEG_INFINITY,
+            min_z: f64::INFINITY,
+            max_z: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn from_point(point: &Point3) -> Self {
+        AxisAlignedBoundingBox {
+            min_x: point.x,
+            max_x: point.x,
+            min_y: point.y,
+            max_y: point.y,
+            min_z: point.z,
+            max_z: point.z,
+        }
+    }
+
+    pub fn centroid(&self) -> Point3 {
+        Point3 {
+            x: (self.min_x + self.max_x) / 2f64,
+            y: (self.min_y + self.max_y) / 2f64,
+            z: (self.min_z + self.max_z) / 2f64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_x > self.max_x || self.min_y > self.max_y || self.min_z > self.max_z
+    }
+
+    pub fn union(&self, other: &AxisAlignedBoundingBox) -> AxisAlignedBoundingBox {
+        AxisAlignedBoundingBox {
+            min_x: self.min_x.min(other.min_x),
+            max_x: self.max_x.max(other.max_x),
+            min_y: self.min_y.min(other.min_y),
+            max_y: self.max_y.max(other.max_y),
+            min_z: self.min_z.min(other.min_z),
+            max_z: self.max_z.max(other.max_z),
+        }
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        if self.is_empty() {
+            return 0f64;
+        }
+        let dx = self.max_x - self.min_x;
+        let dy = self.max_y - self.min_y;
+        let dz = self.max_z - self.min_z;
+        2f64 * (dx * dy + dy * dz + dz * dx)
+    }
+
+    pub fn extent(&self, direction: Direction) -> (f64, f64) {
+        match direction {
+            Direction::X => (self.min_x, self.max_x),
+            Direction::Y => (self.min_y, self.max_y),
+            Direction::Z => (self.min_z, self.max_z),
+        }
+    }
+
+    /// The two halves of the box on each side of the plane at `position`.
+    pub fn split(
+        &self,
+        direction: Direction,
+        position: f64,
+    ) -> (AxisAlignedBoundingBox, AxisAlignedBoundingBox) {
+        let mut below = *self;
+        let mut above = *self;
+        match direction {
+            Direction::X => {
+                below.max_x = position;
+                above.min_x = position;
+            }
+            Direction::Y => {
+                below.max_y = position;
+                above.min_y = position;
+            }
+            Direction::Z => {
+                below.max_z = position;
+                above.min_z = position;
+            }
+        }
+        (below, above)
+    }
+
+    /// Distances along the ray at which it enters and leaves the box, the
+    /// part behind the origin excluded.
+    pub fn intersection_range(&self, ray: &Ray) -> Option<(f64, f64)> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut t_min = 0f64;
+        let mut t_max = f64::INFINITY;
+        for &direction in DIRECTIONS.iter() {
+            let origin = direction.of_point(&ray.origin);
+            let ray_direction = direction.of_vector(&ray.direction);
+            let (min, max) = self.extent(direction);
+            if ray_direction == 0f64 {
+                if origin < min || origin > max {
+                    return None;
+                }
+                continue;
+            }
+            let near = (min - origin) / ray_direction;
+            let far = (max - origin) / ray_direction;
+            t_min = t_min.max(near.min(far));
+            t_max = t_max.min(near.max(far));
+            if t_min > t_max {
+                return None;
+            }
+        }
+        Some((t_min, t_max))
+    }
+
     // http://www.ics.uci.edu/~arvo/EECS204/code/latest/aabb.cpp
     pub fn intersects(&self, ray: &Ray) -> bool {
         let mut r: f64;
@@ -484,8 +655,40 @@ impl AxisAlignedBoundingBox {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::geometry::Point3;
-    use crate::geometry::Vector3;
+    use crate::intersectable::Intersectable;
+    use crate::sample::get_mesh;
+
+    #[test]
+    fn kd_tree_finds_the_closest_intersection() {
+        let scene = get_mesh();
+        let objects = &scene.objects.objects;
+        let kd_tree = build_kd_tree(objects, &KDTreeSettings::default());
+        let camera = &scene.camera;
+        for y in (0..camera.y_resolution).step_by(3) {
+            for x in (0..camera.x_resolution).step_by(3) {
+                let ray = camera.get_ray(x, y);
+                let expected = objects
+                    .iter()
+                    .filter_map(|object| object.geometry.intersect(&ray))
+                    .map(|intersection| intersection.distance)
+                    .fold(f64::INFINITY, f64::min);
+                let found = kd_tree
+                    .get_closest_intersection(&ray, |index| objects[index].geometry.intersect(&ray))
+                    .map_or(f64::INFINITY, |(_, intersection)| intersection.distance);
+                assert_eq!(found, expected, "pixel {} {}", x, y);
+                assert_eq!(
+                    kd_tree.any_object_intersecting(&ray, f64::INFINITY, |index| objects[index]
+                        .geometry
+                        .intersect(&ray)
+                        .is_some()),
+                    expected.is_finite(),
+                    "pixel {} {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
 
     #[test]
     fn aabb_ray_intersection_straight() {
@@ -1,12 +1,22 @@
+pub mod bvh;
 pub mod camera;
 pub mod color;
+pub mod display;
 pub mod engine;
+pub mod framebuffer;
 pub mod geometry;
+pub mod integrator;
 mod intersectable;
-mod kdtree;
+pub mod kdtree;
 mod light;
-mod material;
+pub mod material;
+mod microfacet;
 pub mod parser;
+mod phong;
+mod procedural;
 mod renderer;
 pub mod sample;
+pub mod sampler;
+pub mod scheduler;
+mod shader;
 mod texture;
@@ -1,12 +1,27 @@
-use crate::color::Color;
+use crate::color::{Color, BLACK};
+use crate::engine::{SceneObject, SceneObjectId};
+use crate::geometry::Point2;
 use crate::geometry::Point3;
+use crate::geometry::Ray;
+use crate::geometry::Sphere;
 use crate::geometry::Vector3;
+use crate::intersectable::Intersectable;
+use crate::parser::{parse_ies, parse_ies_string};
+use crate::sampler::{concentric_disk, stratified, stratified_count, uniform_sphere, Rng};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::path::Path;
 
 #[derive(Debug, Deserialize, Serialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum Light {
     DirectionalLight(DirectionalLight),
     PointLight(PointLight),
+    SpotLight(SpotLight),
+    RectangleLight(RectangleLight),
+    DiskLight(DiskLight),
+    SphereLight(SphereLight),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -17,10 +32,288 @@ pub struct DirectionalLight {
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct PointLight {
     pub origin: Point3,
     pub intensity: f64,
     pub color: Color,
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    /// Distance beyond which the light has no effect. The light fades out
+    /// smoothly when getting close to it.
+    #[serde(default)]
+    pub cutoff_radius: Option<f64>,
+}
+
+/// How the light of a point or spot light decreases with distance.
+/// `intensity` is the intensity received at a distance of 1.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub enum Attenuation {
+    /// Same intensity at any distance.
+    None,
+    #[default]
+    InverseSquare,
+    /// Divided by `constant + linear * d + quadratic * d * d`.
+    #[serde(rename_all = "camelCase")]
+    Polynomial {
+        constant: f64,
+        linear: f64,
+        quadratic: f64,
+    },
+}
+
+impl Attenuation {
+    pub fn get_factor(&self, distance: f64, cutoff_radius: Option<f64>) -> f64 {
+        let factor = match *self {
+            Attenuation::None => 1f64,
+            Attenuation::InverseSquare => 1f64 / (distance * distance).max(1e-12),
+            Attenuation::Polynomial {
+                constant,
+                linear,
+                quadratic,
+            } => 1f64 / (constant + linear * distance + quadratic * distance * distance).max(1e-12),
+        };
+        match cutoff_radius {
+            Some(radius) => {
+                let ratio = distance / radius;
+                let window = (1f64 - ratio * ratio * ratio * ratio).clamp(0f64, 1f64);
+                factor * window * window
+            }
+            None => factor,
+        }
+    }
+}
+
+/// Cone of light from `origin` around `direction`: full intensity up to
+/// `inner_angle` from the axis, fading smoothly to nothing at `outer_angle`.
+/// Angles are half angles, in radians.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpotLight {
+    pub origin: Point3,
+    pub direction: Vector3,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+    pub intensity: f64,
+    pub color: Color,
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    #[serde(default)]
+    pub cutoff_radius: Option<f64>,
+    /// Measured light distribution of the fixture, given as the content of
+    /// an IES file or as `{"path": ...}` to read one with `load_resources`.
+    /// Its vertical angles are taken from `direction`.
+    #[serde(default)]
+    pub profile: Option<IesProfile>,
+}
+
+/// Angular intensity table read from an IES (LM-63) photometric file.
+/// Angles are in degrees, intensities relative to the brightest direction.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "SerdeIesProfile", into = "SerdeIesProfile")]
+pub struct IesProfile {
+    pub vertical_angles: Vec<f64>,
+    pub horizontal_angles: Vec<f64>,
+    /// One row of intensities over the vertical angles per horizontal angle.
+    pub intensities: Vec<Vec<f64>>,
+    pub source: String,
+    /// File the profile is read from, if any. The tables stay empty until
+    /// `load_resources`.
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum SerdeIesProfile {
+    Source(String),
+    File { path: String },
+}
+
+/// Parallelogram spanned by `edge_u` and `edge_v` from `corner`, emitting on
+/// both sides.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RectangleLight {
+    pub corner: Point3,
+    pub edge_u: Vector3,
+    pub edge_v: Vector3,
+    /// Radiance leaving each point of the surface, scaled by `color`.
+    pub radiance: f64,
+    pub color: Color,
+    /// Number of points of the light shading is computed from.
+    #[serde(default = "default_light_samples")]
+    pub samples: u32,
+}
+
+/// Disk emitting on both sides.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskLight {
+    pub center: Point3,
+    pub normal: Vector3,
+    pub radius: f64,
+    pub radiance: f64,
+    pub color: Color,
+    #[serde(default = "default_light_samples")]
+    pub samples: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SphereLight {
+    pub center: Point3,
+    pub radius: f64,
+    pub radiance: f64,
+    pub color: Color,
+    #[serde(default = "default_light_samples")]
+    pub samples: u32,
+}
+
+impl SpotLight {
+    pub fn get_intensity(&self, point: &Point3) -> f64 {
+        let axis = self.direction.normalize();
+        let to_point = (point - &self.origin).normalize();
+        let cosine = axis.dot(&to_point);
+        let falloff = smoothstep(self.outer_angle.cos(), self.inner_angle.cos(), cosine);
+        let profile = match &self.profile {
+            Some(profile) => {
+                let (tangent, bitangent) = axis.orthonormal_basis();
+                let vertical = cosine.clamp(-1f64, 1f64).acos().to_degrees();
+                let horizontal = to_point
+                    .dot(&bitangent)
+                    .atan2(to_point.dot(&tangent))
+                    .to_degrees();
+                profile.get_relative_intensity(vertical, horizontal)
+            }
+            None => 1f64,
+        };
+        let distance = (point - &self.origin).norm();
+        self.intensity
+            * falloff
+            * profile
+            * self.attenuation.get_factor(distance, self.cutoff_radius)
+    }
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1f64 } else { 0f64 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0f64, 1f64);
+    t * t * (3f64 - 2f64 * t)
+}
+
+impl IesProfile {
+    /// Reads the file of the profile, its path being relative to
+    /// `directory`, unless it was read already.
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) if self.intensities.is_empty() => path.clone(),
+            _ => return Ok(()),
+        };
+        let file = directory.join(&path).to_string_lossy().into_owned();
+        *self = parse_ies(file)?;
+        self.path = Some(path);
+        Ok(())
+    }
+
+    /// Intensity towards the given angles, interpolated in the table. Tables
+    /// covering part of the horizontal circle are symmetric, as the format
+    /// specifies.
+    pub fn get_relative_intensity(&self, vertical: f64, horizontal: f64) -> f64 {
+        let last_horizontal = *self.horizontal_angles.last().unwrap_or(&0f64);
+        let mut horizontal = horizontal.rem_euclid(360f64);
+        if last_horizontal <= 0f64 {
+            horizontal = 0f64;
+        } else if last_horizontal <= 180f64 && horizontal > 180f64 {
+            horizontal = 360f64 - horizontal;
+        }
+        if last_horizontal <= 90f64 && horizontal > 90f64 {
+            horizontal = 180f64 - horizontal;
+        }
+
+        let (h0, h1, h_t) = match bracket(&self.horizontal_angles, horizontal) {
+            Some(bracket) => bracket,
+            None => return 0f64,
+        };
+        let (v0, v1, v_t) = match bracket(&self.vertical_angles, vertical) {
+            Some(bracket) => bracket,
+            None => return 0f64,
+        };
+        let at = |h: usize| {
+            let row = &self.intensities[h];
+            row[v0] * (1f64 - v_t) + row[v1] * v_t
+        };
+        at(h0) * (1f64 - h_t) + at(h1) * h_t
+    }
+}
+
+/// Indices of the angles around `angle` and where it lies between them.
+fn bracket(angles: &[f64], angle: f64) -> Option<(usize, usize, f64)> {
+    let first = *angles.first()?;
+    let last = *angles.last()?;
+    if angles.len() == 1 {
+        return Some((0, 0, 0f64));
+    }
+    if angle < first || angle > last {
+        return None;
+    }
+    let upper = angles
+        .partition_point(|&a| a <= angle)
+        .clamp(1, angles.len() - 1);
+    let lower = upper - 1;
+    let span = angles[upper] - angles[lower];
+    let t = if span > 0f64 {
+        (angle - angles[lower]) / span
+    } else {
+        0f64
+    };
+    Some((lower, upper, t))
+}
+
+impl TryFrom<String> for IesProfile {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        parse_ies_string(&source)
+    }
+}
+
+impl TryFrom<SerdeIesProfile> for IesProfile {
+    type Error = String;
+
+    fn try_from(profile: SerdeIesProfile) -> Result<Self, Self::Error> {
+        match profile {
+            SerdeIesProfile::Source(source) => parse_ies_string(&source),
+            SerdeIesProfile::File { path } => Ok(IesProfile {
+                vertical_angles: vec![],
+                horizontal_angles: vec![],
+                intensities: vec![],
+                source: String::new(),
+                path: Some(path),
+            }),
+        }
+    }
+}
+
+impl From<IesProfile> for SerdeIesProfile {
+    fn from(profile: IesProfile) -> Self {
+        match profile.path {
+            Some(path) => SerdeIesProfile::File { path },
+            None => SerdeIesProfile::Source(profile.source),
+        }
+    }
+}
+
+impl RectangleLight {
+    pub fn center(&self) -> Point3 {
+        self.corner
+            .add(&self.edge_u.plus(&self.edge_v).times(0.5f64))
+    }
+}
+
+fn default_light_samples() -> u32 {
+    16
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -29,18 +322,55 @@ pub struct AmbientLight {
     pub intensity: f64,
 }
 
+/// Point of a light as seen from a shaded point. Area lights are shaded as
+/// many such samples, each carrying its share of the light intensity.
+#[derive(Debug, Clone)]
+pub struct LightSample {
+    /// Same convention as `Light::get_direction`: shadow rays go against it.
+    pub direction: Vector3,
+    pub distance: f64,
+    pub intensity: f64,
+}
+
 impl Light {
     pub fn get_direction(&self, point: &Point3) -> Vector3 {
         match *self {
             Light::DirectionalLight(ref light) => light.direction,
-            Light::PointLight(ref light) => (&light.origin - &point).normalize(),
+            Light::PointLight(ref light) => (point - &light.origin).normalize(),
+            Light::SpotLight(ref light) => (point - &light.origin).normalize(),
+            Light::RectangleLight(ref light) => (point - &light.center()).normalize(),
+            Light::DiskLight(ref light) => (point - &light.center).normalize(),
+            Light::SphereLight(ref light) => (point - &light.center).normalize(),
+        }
+    }
+
+    /// Distance from `point` to the light, infinite for directional lights.
+    pub fn get_distance(&self, point: &Point3) -> f64 {
+        match *self {
+            Light::DirectionalLight(ref _light) => f64::INFINITY,
+            Light::PointLight(ref light) => (&light.origin - point).norm(),
+            Light::SpotLight(ref light) => (&light.origin - point).norm(),
+            Light::RectangleLight(ref light) => (&light.center() - point).norm(),
+            Light::DiskLight(ref light) => (&light.center - point).norm(),
+            Light::SphereLight(ref light) => (&light.center - point).norm(),
         }
     }
 
-    pub fn get_intensity(&self) -> f64 {
+    /// Intensity of the light reaching `point`, before any occlusion. Area
+    /// lights give their radiance, which `get_samples` spreads over them.
+    pub fn get_intensity(&self, point: &Point3) -> f64 {
         match *self {
             Light::DirectionalLight(ref light) => light.intensity,
-            Light::PointLight(ref light) => light.intensity,
+            Light::PointLight(ref light) => {
+                light.intensity
+                    * light
+                        .attenuation
+                        .get_factor(self.get_distance(point), light.cutoff_radius)
+            }
+            Light::SpotLight(ref light) => light.get_intensity(point),
+            Light::RectangleLight(ref light) => light.radiance,
+            Light::DiskLight(ref light) => light.radiance,
+            Light::SphereLight(ref light) => light.radiance,
         }
     }
 
@@ -48,6 +378,688 @@ impl Light {
         match *self {
             Light::DirectionalLight(ref light) => light.color,
             Light::PointLight(ref light) => light.color,
+            Light::SpotLight(ref light) => light.color,
+            Light::RectangleLight(ref light) => light.color,
+            Light::DiskLight(ref light) => light.color,
+            Light::SphereLight(ref light) => light.color,
+        }
+    }
+
+    /// Radiance seen by rays hitting the light, black for the lights which
+    /// cannot be hit.
+    pub fn get_radiance(&self) -> Color {
+        match *self {
+            Light::DirectionalLight(_) | Light::PointLight(_) | Light::SpotLight(_) => BLACK,
+            Light::RectangleLight(ref light) => &light.color * light.radiance,
+            Light::DiskLight(ref light) => &light.color * light.radiance,
+            Light::SphereLight(ref light) => &light.color * light.radiance,
+        }
+    }
+
+    /// Reads the files the light refers to, their paths being relative to
+    /// `directory`.
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        match self {
+            Light::SpotLight(SpotLight {
+                profile: Some(profile),
+                ..
+            }) => profile.load_resources(directory),
+            _ => Ok(()),
         }
     }
+
+    pub fn color_mut(&mut self) -> &mut Color {
+        match *self {
+            Light::DirectionalLight(ref mut light) => &mut light.color,
+            Light::PointLight(ref mut light) => &mut light.color,
+            Light::SpotLight(ref mut light) => &mut light.color,
+            Light::RectangleLight(ref mut light) => &mut light.color,
+            Light::DiskLight(ref mut light) => &mut light.color,
+            Light::SphereLight(ref mut light) => &mut light.color,
+        }
+    }
+
+    /// Points of the light illuminating `point`. Directional and point lights
+    /// give a single sample, area lights a stratified set over their surface
+    /// whose intensities, like those of emissive objects, are the irradiance
+    /// they bring divided by pi.
+    pub fn get_samples(&self, point: &Point3) -> Vec<LightSample> {
+        match *self {
+            Light::DirectionalLight(ref light) => vec![LightSample {
+                direction: light.direction,
+                distance: f64::INFINITY,
+                intensity: light.intensity,
+            }],
+            Light::PointLight(_) | Light::SpotLight(_) => vec![LightSample {
+                direction: self.get_direction(point),
+                distance: self.get_distance(point),
+                intensity: self.get_intensity(point),
+            }],
+            Light::RectangleLight(ref light) => {
+                let normal = light.edge_u.cross(&light.edge_v);
+                let area = normal.norm();
+                let normal = normal.times(1f64 / area);
+                area_samples(point, light.samples, light.radiance, area, true, |sample| {
+                    let position = light
+                        .corner
+                        .add(&light.edge_u.times(sample.x))
+                        .add(&light.edge_v.times(sample.y));
+                    (position, normal)
+                })
+            }
+            Light::DiskLight(ref light) => {
+                let normal = light.normal.normalize();
+                let (tangent, bitangent) = normal.orthonormal_basis();
+                let area = PI * light.radius * light.radius;
+                area_samples(point, light.samples, light.radiance, area, true, |sample| {
+                    let disk = concentric_disk(sample);
+                    let position = light
+                        .center
+                        .add(&tangent.times(disk.x * light.radius))
+                        .add(&bitangent.times(disk.y * light.radius));
+                    (position, normal)
+                })
+            }
+            Light::SphereLight(ref light) => {
+                let to_point = point - &light.center;
+                // only the hemisphere facing the point can light it
+                let area = 2f64 * PI * light.radius * light.radius;
+                area_samples(
+                    point,
+                    light.samples,
+                    light.radiance,
+                    area,
+                    false,
+                    |sample| {
+                        let mut normal = uniform_sphere(sample);
+                        if normal.dot(&to_point) < 0f64 {
+                            normal = normal.times(-1f64);
+                        }
+                        (light.center.add(&normal.times(light.radius)), normal)
+                    },
+                )
+            }
+        }
+    }
+
+    /// Distance along the ray to the surface of an area light, for rays
+    /// seeing it directly. Point and directional lights cannot be hit.
+    pub fn intersect(&self, ray: &Ray) -> Option<f64> {
+        match *self {
+            Light::DirectionalLight(ref _light) => None,
+            Light::PointLight(ref _light) => None,
+            Light::SpotLight(ref _light) => None,
+            Light::RectangleLight(ref light) => {
+                let normal = light.edge_u.cross(&light.edge_v);
+                let distance = intersect_plane(ray, &light.corner, &normal)?;
+                let offset = &ray.origin.add(&ray.direction.times(distance)) - &light.corner;
+                let area = normal.dot(&normal);
+                let u = normal.dot(&offset.cross(&light.edge_v)) / area;
+                let v = normal.dot(&light.edge_u.cross(&offset)) / area;
+                if (0f64..=1f64).contains(&u) && (0f64..=1f64).contains(&v) {
+                    Some(distance)
+                } else {
+                    None
+                }
+            }
+            Light::DiskLight(ref light) => {
+                let distance = intersect_plane(ray, &light.center, &light.normal)?;
+                let offset = &ray.origin.add(&ray.direction.times(distance)) - &light.center;
+                if offset.norm() <= light.radius {
+                    Some(distance)
+                } else {
+                    None
+                }
+            }
+            Light::SphereLight(ref light) => Sphere {
+                center: light.center,
+                radius: light.radius,
+            }
+            .intersect(ray)
+            .map(|intersection| intersection.distance),
+        }
+    }
+
+    /// Number of samples `get_samples` returns.
+    pub fn sample_count(&self) -> usize {
+        match *self {
+            Light::DirectionalLight(_) | Light::PointLight(_) | Light::SpotLight(_) => 1,
+            Light::RectangleLight(ref light) => stratified_count(light.samples),
+            Light::DiskLight(ref light) => stratified_count(light.samples),
+            Light::SphereLight(ref light) => stratified_count(light.samples),
+        }
+    }
+
+    /// Density, per solid angle seen from `point`, with which one of the
+    /// samples of `get_samples` lands on `light_point` of the surface of an
+    /// area light; zero for the other lights.
+    pub fn pdf(&self, point: &Point3, light_point: &Point3) -> f64 {
+        let (normal, area) = match *self {
+            Light::DirectionalLight(_) | Light::PointLight(_) | Light::SpotLight(_) => return 0f64,
+            Light::RectangleLight(ref light) => {
+                let normal = light.edge_u.cross(&light.edge_v);
+                let area = normal.norm();
+                (normal.times(1f64 / area), area)
+            }
+            Light::DiskLight(ref light) => {
+                (light.normal.normalize(), PI * light.radius * light.radius)
+            }
+            Light::SphereLight(ref light) => {
+                // samples only cover the hemisphere facing the point
+                let normal = (light_point - &light.center).normalize();
+                if normal.dot(&(point - &light.center)) < 0f64 {
+                    return 0f64;
+                }
+                (normal, 2f64 * PI * light.radius * light.radius)
+            }
+        };
+        let to_point = point - light_point;
+        let distance_squared = to_point.dot(&to_point);
+        let cosine = normal.dot(&to_point.normalize()).abs();
+        if cosine <= 0f64 {
+            return 0f64;
+        }
+        distance_squared / (area * cosine)
+    }
+}
+
+/// Scene objects with an emissive material, lighting the scene like an area
+/// light spread over all of them. Samples pick an object with a probability
+/// proportional to its area times its emitted power, then a point uniformly
+/// on its surface. Unbounded planes can only be seen, not sampled.
+#[derive(Debug, Clone, Default)]
+pub struct EmissiveObjects {
+    ids: Vec<SceneObjectId>,
+    areas: Vec<f64>,
+    /// Normalized cumulative distribution over `ids`.
+    cumulative_weights: Vec<f64>,
+    /// Number of samples taken per shaded point, over all the emitters.
+    pub samples: u32,
+}
+
+impl EmissiveObjects {
+    pub fn new(objects: &[SceneObject]) -> Self {
+        let mut emitters = EmissiveObjects {
+            samples: default_light_samples(),
+            ..EmissiveObjects::default()
+        };
+        let mut total = 0f64;
+        for object in objects.iter() {
+            let power = match object.material.emission() {
+                Some(emission) => emission.radiance().luminance(),
+                None => continue,
+            };
+            let area = object.geometry.area();
+            if power <= 0f64 || !area.is_finite() || area <= 0f64 {
+                continue;
+            }
+            total += area * power;
+            emitters.ids.push(object.id);
+            emitters.areas.push(area);
+            emitters.cumulative_weights.push(total);
+        }
+        for weight in emitters.cumulative_weights.iter_mut() {
+            *weight /= total;
+        }
+        emitters
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Points of the emitters illuminating `point`, each with the radiance of
+    /// its object. Like for the other lights, intensities are irradiance
+    /// divided by pi, which is what Phong shading of a diffuse surface
+    /// expects: here the emitter cosine over the squared distance, divided by
+    /// the sampling density. Degenerate samples are kept with a null
+    /// intensity, see `solid_angle_density`.
+    pub fn get_samples(
+        &self,
+        point: &Point3,
+        objects: &[SceneObject],
+    ) -> Vec<(LightSample, Color)> {
+        self.get_samples_with(point, objects, &mut Rng::for_point(point, 5))
+    }
+
+    /// Same as `get_samples`, drawing the random numbers from `rng`.
+    pub fn get_samples_with(
+        &self,
+        point: &Point3,
+        objects: &[SceneObject],
+        rng: &mut Rng,
+    ) -> Vec<(LightSample, Color)> {
+        if self.is_empty() {
+            return vec![];
+        }
+        let positions = stratified(self.samples, rng);
+        let count = positions.len() as f64;
+        positions
+            .iter()
+            .filter_map(|sample| {
+                let choice = rng.next_f64();
+                let index = self
+                    .cumulative_weights
+                    .partition_point(|&weight| weight <= choice)
+                    .min(self.ids.len() - 1);
+                let object = &objects[self.ids[index]];
+                let emission = object.material.emission()?;
+                let (light_point, normal) = object.geometry.sample_surface(sample)?;
+                let to_point = point - &light_point;
+                let distance = to_point.norm().max(1e-9);
+                let direction = to_point.times(1f64 / distance);
+                let cosine = normal.dot(&direction).abs();
+                let density = self.probability(index) / self.areas[index];
+                Some((
+                    LightSample {
+                        direction,
+                        // stops shadow rays just before the emitter itself
+                        distance: distance - 1e-6,
+                        intensity: cosine / (PI * distance * distance * density * count),
+                    },
+                    emission.radiance(),
+                ))
+            })
+            .collect()
+    }
+
+    /// Density, per solid angle seen from the shaded point, with which one
+    /// of `sample_count` samples returned together by `get_samples` was
+    /// drawn.
+    pub fn solid_angle_density(light_sample: &LightSample, sample_count: usize) -> f64 {
+        if light_sample.intensity <= 0f64 {
+            return f64::INFINITY;
+        }
+        1f64 / (PI * light_sample.intensity * sample_count as f64)
+    }
+
+    /// Density, per solid angle seen from `point`, with which one sample
+    /// lands on `light_point` of the object `id`; zero for objects that are
+    /// not sampled.
+    pub fn pdf(
+        &self,
+        point: &Point3,
+        id: SceneObjectId,
+        light_point: &Point3,
+        light_normal: &Vector3,
+    ) -> f64 {
+        let index = match self.ids.binary_search(&id) {
+            Ok(index) => index,
+            Err(_) => return 0f64,
+        };
+        let to_point = point - light_point;
+        let distance_squared = to_point.dot(&to_point);
+        let cosine = light_normal.normalize().dot(&to_point.normalize()).abs();
+        if cosine <= 0f64 {
+            return 0f64;
+        }
+        self.probability(index) / self.areas[index] * distance_squared / cosine
+    }
+
+    fn probability(&self, index: usize) -> f64 {
+        if index > 0 {
+            self.cumulative_weights[index] - self.cumulative_weights[index - 1]
+        } else {
+            self.cumulative_weights[0]
+        }
+    }
+}
+
+/// Samples at the positions given by `position` for points of the unit
+/// square, along with the surface normal there, spread uniformly over
+/// `area`. One sided surfaces emit on the side of their normal only.
+fn area_samples<F>(
+    point: &Point3,
+    count: u32,
+    radiance: f64,
+    area: f64,
+    two_sided: bool,
+    position: F,
+) -> Vec<LightSample>
+where
+    F: Fn(&Point2) -> (Point3, Vector3),
+{
+    let mut rng = Rng::for_point(point, 3);
+    let positions = stratified(count, &mut rng);
+    // radiance over the density of each sample, per unit area
+    let share = radiance * area / positions.len() as f64;
+    positions
+        .iter()
+        .map(|sample| {
+            let (light_point, normal) = position(sample);
+            let to_point = point - &light_point;
+            let distance = to_point.norm();
+            let direction = to_point.times(1f64 / distance);
+            let cosine = normal.dot(&direction);
+            let cosine = if two_sided {
+                cosine.abs()
+            } else {
+                cosine.max(0f64)
+            };
+            LightSample {
+                direction,
+                distance,
+                intensity: share * cosine / (PI * distance * distance),
+            }
+        })
+        .collect()
+}
+
+fn intersect_plane(ray: &Ray, point: &Point3, normal: &Vector3) -> Option<f64> {
+    let denom = normal.dot(&ray.direction);
+    if denom.abs() < 1e-12 {
+        return None;
+    }
+    let distance = normal.dot(&(point - &ray.origin)) / denom;
+    if distance > 0f64 {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::WHITE;
+    use crate::geometry::{get_triangles, Object};
+    use crate::material::{Coloration, Emission, Material, PhongMaterial, Scalar, SurfaceDetail};
+    use crate::parser::parse_obj_string;
+    use std::sync::Arc;
+
+    fn rectangle_light() -> Light {
+        Light::RectangleLight(RectangleLight {
+            corner: Point3 {
+                x: -1f64,
+                y: 10f64,
+                z: -1f64,
+            },
+            edge_u: Vector3 {
+                x: 2f64,
+                y: 0f64,
+                z: 0f64,
+            },
+            edge_v: Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 2f64,
+            },
+            radiance: 2f64,
+            color: WHITE,
+            samples: 16,
+        })
+    }
+
+    #[test]
+    fn area_light_samples_add_up_to_the_irradiance() {
+        let origin = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 0f64,
+        };
+        let total = |light: &Light, point: &Point3| -> f64 {
+            light
+                .get_samples(point)
+                .iter()
+                .map(|sample| sample.intensity)
+                .sum()
+        };
+
+        // seen from afar, the light is a point of intensity radiance * area
+        let point = Point3 {
+            x: 0f64,
+            y: -1000f64,
+            z: 0f64,
+        };
+        let samples = rectangle_light().get_samples(&point);
+        assert_eq!(samples.len(), 16);
+        let expected = 2f64 * 4f64 / (PI * 1010f64 * 1010f64);
+        assert!((total(&rectangle_light(), &point) / expected - 1f64).abs() < 1e-3);
+        for sample in samples.iter() {
+            assert!(sample.direction.y < 0f64);
+        }
+
+        // closer, samples add up to the radiance over pi times the solid
+        // angle of the light, shading then adding the cosine at the point
+        let disk = Light::DiskLight(DiskLight {
+            center: Point3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0f64,
+            },
+            normal: Vector3 {
+                x: 0f64,
+                y: -1f64,
+                z: 0f64,
+            },
+            radius: 2f64,
+            radiance: 3f64,
+            color: WHITE,
+            samples: 256,
+        });
+        let expected = 3f64 / PI * 2f64 * PI * (1f64 - 1f64 / 5f64.sqrt());
+        assert!((total(&disk, &origin) / expected - 1f64).abs() < 0.02f64);
+
+        let sphere = Light::SphereLight(SphereLight {
+            center: Point3 {
+                x: 0f64,
+                y: 0f64,
+                z: 3f64,
+            },
+            radius: 1f64,
+            radiance: 3f64,
+            color: WHITE,
+            samples: 256,
+        });
+        let expected = 3f64 / PI * 2f64 * PI * (1f64 - (8f64 / 9f64).sqrt());
+        assert!((total(&sphere, &origin) / expected - 1f64).abs() < 0.02f64);
+    }
+
+    #[test]
+    fn spot_light_fades_between_its_cones() {
+        let light = Light::SpotLight(SpotLight {
+            origin: Point3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0f64,
+            },
+            direction: Vector3 {
+                x: 0f64,
+                y: -1f64,
+                z: 0f64,
+            },
+            inner_angle: 0.2f64,
+            outer_angle: 0.4f64,
+            intensity: 3f64,
+            color: WHITE,
+            attenuation: Attenuation::None,
+            cutoff_radius: None,
+            profile: None,
+        });
+        let at = |x: f64| {
+            light.get_intensity(&Point3 {
+                x: x.tan(),
+                y: 0f64,
+                z: 0f64,
+            })
+        };
+        assert!((at(0.1f64) - 3f64).abs() < 1e-9);
+        assert!(at(0.3f64) > 0f64 && at(0.3f64) < 3f64);
+        assert_eq!(at(0.5f64), 0f64);
+    }
+
+    #[test]
+    fn ies_profile_is_interpolated_and_symmetric() {
+        let profile = IesProfile::try_from(
+            "IESNA:LM-63-2002\nTILT=NONE\n1 1000 1 3 2 1 1 0 0 0\n1 1 100\n\
+             0 45 90\n0 90\n200 100 0\n100 50 0\n"
+                .to_string(),
+        )
+        .unwrap();
+        assert_eq!(profile.get_relative_intensity(0f64, 0f64), 1f64);
+        assert!((profile.get_relative_intensity(22.5f64, 0f64) - 0.75f64).abs() < 1e-9);
+        assert!((profile.get_relative_intensity(0f64, 45f64) - 0.75f64).abs() < 1e-9);
+        assert_eq!(
+            profile.get_relative_intensity(30f64, 120f64),
+            profile.get_relative_intensity(30f64, 60f64)
+        );
+        assert_eq!(profile.get_relative_intensity(120f64, 0f64), 0f64);
+    }
+
+    #[test]
+    fn ies_profile_with_garbage_counts_is_an_error() {
+        let profile = |tilt: &str, vertical_count: &str| {
+            IesProfile::try_from(format!(
+                "IESNA:LM-63-2002\nTILT={}\n1 1000 1 {} 1 1 1 0 0 0\n1 1 100\n0 90\n0\n200 50\n",
+                tilt, vertical_count
+            ))
+        };
+        assert!(profile("NONE", "2").is_ok());
+        for count in &["1e30", "NaN", "-2", "1.5", "20"] {
+            let error = profile("NONE", count).err().unwrap();
+            assert!(error.contains("invalid count"), "{}", error);
+        }
+        let error = profile("INCLUDE\n1 1e30", "2").err().unwrap();
+        assert!(error.contains("invalid count"), "{}", error);
+    }
+
+    #[test]
+    fn ies_profile_is_read_from_a_file() {
+        let source =
+            "IESNA:LM-63-2002\nTILT=NONE\n1 1000 1 2 1 1 1 0 0 0\n1 1 100\n0 90\n0\n200 50\n";
+        let name = format!("profile-{}.ies", std::process::id());
+        let directory = std::env::temp_dir();
+        std::fs::write(directory.join(&name), source).unwrap();
+        let json = serde_json::json!({ "path": name });
+        let mut profile: IesProfile = serde_json::from_value(json.clone()).unwrap();
+        assert!(profile.intensities.is_empty());
+        let loaded = profile.load_resources(&directory);
+        std::fs::remove_file(directory.join(&name)).unwrap();
+
+        assert_eq!(loaded, Ok(()));
+        assert!((profile.get_relative_intensity(45f64, 0f64) - 0.625f64).abs() < 1e-9);
+        assert_eq!(serde_json::to_value(&profile).unwrap(), json);
+        let inline: IesProfile = serde_json::from_value(serde_json::json!(source)).unwrap();
+        assert_eq!(inline.intensities, profile.intensities);
+
+        let mut missing: IesProfile = serde_json::from_value(json).unwrap();
+        assert!(missing.load_resources(&directory).is_err());
+    }
+
+    #[test]
+    fn point_light_attenuation() {
+        let light = |attenuation: Attenuation, cutoff_radius: Option<f64>| {
+            Light::PointLight(PointLight {
+                origin: Point3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z: 0f64,
+                },
+                intensity: 2f64,
+                color: WHITE,
+                attenuation,
+                cutoff_radius,
+            })
+        };
+        let at = |distance: f64| Point3 {
+            x: distance,
+            y: 0f64,
+            z: 0f64,
+        };
+        let constant = light(Attenuation::None, None);
+        assert_eq!(constant.get_intensity(&at(1000f64)), 2f64);
+        let physical = light(Attenuation::InverseSquare, None);
+        assert!((physical.get_intensity(&at(10f64)) - 0.02f64).abs() < 1e-12);
+        let polynomial = light(
+            Attenuation::Polynomial {
+                constant: 1f64,
+                linear: 1f64,
+                quadratic: 0f64,
+            },
+            Some(4f64),
+        );
+        assert!(polynomial.get_intensity(&at(1f64)) < 1f64);
+        assert!(polynomial.get_intensity(&at(3.9f64)) > 0f64);
+        assert_eq!(polynomial.get_intensity(&at(4f64)), 0f64);
+    }
+
+    #[test]
+    fn camera_rays_see_area_lights() {
+        let light = rectangle_light();
+        let mut ray = Ray {
+            origin: Point3 {
+                x: 0.5f64,
+                y: 0f64,
+                z: 0.5f64,
+            },
+            direction: Vector3 {
+                x: 0f64,
+                y: 1f64,
+                z: 0f64,
+            },
+        };
+        assert_eq!(light.intersect(&ray), Some(10f64));
+        ray.origin.x = 2f64;
+        assert_eq!(light.intersect(&ray), None);
+    }
+
+    fn emissive_material(strength: f64) -> Material {
+        Material::new(PhongMaterial {
+            ambient_color: Coloration::Color(BLACK),
+            ambient_reflection: Scalar::Value(0f64),
+            diffuse_color: Coloration::Color(BLACK),
+            diffuse_reflection: Scalar::Value(0f64),
+            specular_color: Coloration::Color(BLACK),
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(0f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(0f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: Some(Emission {
+                color: WHITE,
+                strength,
+            }),
+        })
+    }
+
+    #[test]
+    fn emissive_triangles_light_like_their_power() {
+        // a 0.2 x 0.2 square far above the origin, its halves of strength 1 and 3
+        let mesh = Arc::new(parse_obj_string(
+            "# square\nv -0.1 100 -0.1\nv 0.1 100 -0.1\nv 0.1 100 0.1\nv -0.1 100 0.1\nf 1 2 3\nf 1 3 4",
+        ));
+        let objects: Vec<SceneObject> = get_triangles(mesh)
+            .into_iter()
+            .enumerate()
+            .map(|(id, triangle)| SceneObject {
+                id,
+                geometry: Object::MeshTriangle(triangle),
+                material: emissive_material(1f64 + 2f64 * id as f64),
+            })
+            .collect();
+        let mut emitters = EmissiveObjects::new(&objects);
+        emitters.samples = 4096;
+        let point = Point3 {
+            x: 0f64,
+            y: 0f64,
+            z: 0f64,
+        };
+
+        let samples = emitters.get_samples(&point, &objects);
+        let irradiance: f64 = samples
+            .iter()
+            .map(|(sample, radiance)| sample.intensity * radiance.luminance())
+            .sum();
+        let expected = (0.02f64 * 1f64 + 0.02f64 * 3f64) / (PI * 100f64 * 100f64);
+        assert!(
+            (irradiance / expected - 1f64).abs() < 1e-3,
+            "{}",
+            irradiance
+        );
+
+        let brighter = samples.iter().filter(|(_, radiance)| radiance.red > 2f64);
+        assert!((brighter.count() as f64 / samples.len() as f64 - 0.75f64).abs() < 0.05f64);
+    }
 }
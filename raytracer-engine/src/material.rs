@@ -1,168 +1,700 @@
-use crate::color::{Color, BLACK};
-use crate::engine::{cast_ray, is_in_shadow, Scene, SceneIntersection, TracedRay};
+use crate::color::{Color, BLACK, WHITE};
+use crate::engine::{cast_ray, is_in_shadow, Scene, SceneIntersection, SceneObjectId, TracedRay};
 use crate::geometry::{Object, Point3, Ray, Vector3};
-use crate::texture::Texture;
-use serde::{Deserialize, Serialize};
+use crate::light::LightSample;
+pub use crate::microfacet::MicrofacetMaterial;
+pub use crate::phong::PhongMaterial;
+use crate::procedural::Procedural;
+use crate::sampler::Rng;
+use crate::shader::ShaderNode;
+use crate::texture::{Texturable, Texture};
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
 use std::mem::swap;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
 
+/// Shading model of a material: how it looks to the Whitted integrator, and
+/// its BSDF for the path tracer. Models other than the built-in `phong` and
+/// `microfacet` become available to scene files through `register_material`.
+pub trait MaterialModel: fmt::Debug + Send + Sync {
+    /// Value of the `type` field naming the model in scene files.
+    fn type_tag(&self) -> &str;
+
+    /// The model as written in scene files, without its `type` field.
+    fn to_json(&self) -> serde_json::Value;
+
+    fn clone_box(&self) -> Box<dyn MaterialModel>;
+
+    fn emission(&self) -> Option<&Emission> {
+        None
+    }
+
+    /// Index of refraction of the material at `point` of `object`.
+    fn index_of_refraction(&self, _object: &Object, _point: &Point3) -> f64 {
+        1f64
+    }
+
+    /// Beer-Lambert attenuation coefficients, per unit of distance, of light
+    /// travelling inside objects of this material.
+    fn absorption(&self) -> Color {
+        BLACK
+    }
+
+    /// Normal and bump maps tilting the surface normal before shading.
+    fn surface_detail(&self) -> Option<&SurfaceDetail> {
+        None
+    }
+
+    /// Whether some lobe is wide enough for lights to be sampled against it.
+    fn has_non_delta_lobes(&self) -> bool {
+        true
+    }
+
+    /// Applies `f` to every color of the model, see `Scene::decode_colors`.
+    fn map_colors(&mut self, _f: &dyn Fn(&Color) -> Color) {}
+
+    /// Reads the files the model refers to, their paths being relative to
+    /// `directory`.
+    fn load_resources(&mut self, _directory: &Path) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Color seen along `ray`, which hits the material in `intersection`.
+    /// Defaults to the emission plus the direct lighting through `evaluate`.
+    fn render_color(
+        &self,
+        ray: &TracedRay,
+        intersection: &SceneIntersection,
+        scene: &Scene,
+        _max_bounces: u8,
+    ) -> Color {
+        direct_lighting(self, &ShadingPoint::at(ray, intersection, scene), scene)
+    }
+
+    /// BSDF for light arriving along `to_light` and leaving towards the eye.
+    /// Delta lobes never match a given direction and are left out.
+    fn evaluate(&self, shading: &ShadingPoint, to_light: &Vector3) -> Color;
+
+    /// Solid angle density with which `sample` picks `to_light`, counting
+    /// the non delta lobes only.
+    fn pdf(&self, shading: &ShadingPoint, to_light: &Vector3) -> f64;
+
+    /// Picks the direction to continue a path in. Returns `None` when the
+    /// path is absorbed.
+    fn sample(&self, shading: &ShadingPoint, rng: &mut Rng) -> Option<BsdfSample>;
+}
+
+/// Material of a scene object. Scene files name its model in a `type`
+/// field, and for compatibility may leave it out of Phong materials, or of
+/// microfacet ones recognized by their `baseColor`.
+#[derive(Debug)]
+pub struct Material(Box<dyn MaterialModel>);
+
+impl Material {
+    pub fn new<M: MaterialModel + 'static>(model: M) -> Self {
+        Material(Box::new(model))
+    }
+
+    pub fn map_colors<F>(&mut self, f: &F)
+    where
+        F: Fn(&Color) -> Color,
+    {
+        self.0.map_colors(f)
+    }
+
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        self.0.load_resources(directory)
+    }
+}
+
+impl Deref for Material {
+    type Target = dyn MaterialModel;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+impl Clone for Material {
+    fn clone(&self) -> Self {
+        Material(self.0.clone_box())
+    }
+}
+
+type MaterialConstructor = fn(serde_json::Value) -> serde_json::Result<Box<dyn MaterialModel>>;
+
+fn construct<M>(value: serde_json::Value) -> serde_json::Result<Box<dyn MaterialModel>>
+where
+    M: MaterialModel + DeserializeOwned + 'static,
+{
+    Ok(Box::new(serde_json::from_value::<M>(value)?))
+}
+
+fn registry() -> &'static RwLock<HashMap<String, MaterialConstructor>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, MaterialConstructor>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut constructors: HashMap<String, MaterialConstructor> = HashMap::new();
+        constructors.insert("phong".to_string(), construct::<PhongMaterial>);
+        constructors.insert("microfacet".to_string(), construct::<MicrofacetMaterial>);
+        RwLock::new(constructors)
+    })
+}
+
+/// Lets scene files use `M` for materials of type `type_tag`, replacing any
+/// model registered under that name before.
+pub fn register_material<M>(type_tag: &str)
+where
+    M: MaterialModel + DeserializeOwned + 'static,
+{
+    registry()
+        .write()
+        .unwrap()
+        .insert(type_tag.to_string(), construct::<M>);
+}
+
+impl Serialize for Material {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut value = self.0.to_json();
+        if let serde_json::Value::Object(fields) = &mut value {
+            fields.insert("type".to_string(), self.0.type_tag().into());
+        }
+        value.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Material {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = serde_json::Value::deserialize(deserializer)?;
+        let fields = value
+            .as_object_mut()
+            .ok_or_else(|| de::Error::custom("a material must be an object"))?;
+        let type_tag = match fields.remove("type") {
+            Some(serde_json::Value::String(type_tag)) => type_tag,
+            Some(_) => return Err(de::Error::custom("a material type must be a string")),
+            None if fields.contains_key("baseColor") => "microfacet".to_string(),
+            None => "phong".to_string(),
+        };
+        let constructor = registry()
+            .read()
+            .unwrap()
+            .get(&type_tag)
+            .copied()
+            .ok_or_else(|| de::Error::custom(format!("unknown material type `{}`", type_tag)))?;
+        constructor(value).map(Material).map_err(de::Error::custom)
+    }
+}
+
+/// Light given off by the surface itself, on both of its sides.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct Material {
-    pub ambient_color: Coloration,
-    pub ambient_reflection: f64,
-    pub diffuse_color: Coloration,
-    pub diffuse_reflection: f64,
-    pub specular_color: Coloration,
-    pub specular_reflection: f64,
-    pub shininess: f64,
-    pub reflectivity: f64,
-    pub transparency: f64,
-    pub index_of_refraction: f64,
+pub struct Emission {
+    pub color: Color,
+    pub strength: f64,
+}
+
+impl Emission {
+    pub fn radiance(&self) -> Color {
+        &self.color * self.strength
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub enum Coloration {
     Color(Color),
     Texture(Texture),
+    Procedural(Procedural),
+    Graph(ShaderNode),
 }
 
 impl Coloration {
-    pub fn color(&self, object: &Object, point: &Point3) -> Color {
+    /// Color at `point`, averaged over `footprint` when textures filter it.
+    pub fn color(&self, object: &Object, point: &Point3, footprint: f64) -> Color {
         match self {
             Coloration::Color(c) => c.clone(),
-            Coloration::Texture(t) => t.get_color(object, point),
+            Coloration::Texture(t) => t.get_color(object, point, footprint),
+            Coloration::Procedural(p) => p.color(object, point),
+            Coloration::Graph(g) => g.color(object, point, footprint),
+        }
+    }
+
+    pub fn map_colors<F>(&mut self, f: &F)
+    where
+        F: Fn(&Color) -> Color,
+    {
+        match self {
+            Coloration::Color(c) => *c = f(c),
+            Coloration::Texture(t) => t.map_colors(f),
+            Coloration::Procedural(p) => p.map_colors(f),
+            Coloration::Graph(g) => g.map_colors(f),
+        }
+    }
+
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        match self {
+            Coloration::Texture(t) => t.load_resources(directory),
+            Coloration::Graph(g) => g.load_resources(directory),
+            Coloration::Color(_) | Coloration::Procedural(_) => Ok(()),
         }
     }
 }
 
-impl Material {
-    pub fn render_color(
-        &self,
-        ray: &TracedRay,
-        intersection: &SceneIntersection,
-        scene: &Scene,
-        max_bounces: u8,
-    ) -> Color {
-        let point_precise = ray
-            .ray
-            .origin
-            .add(&ray.ray.direction.times(intersection.intersection.distance));
-        let normal = intersection.get_normal(&point_precise);
-        let point = point_precise.add(&normal.times(1e-6));
-
-        let mut rendered_color = &(&scene.ambient_light.color
-            * &(&self
-                .ambient_color
-                .color(&intersection.object.geometry, &point_precise)
-                * self.ambient_reflection))
-            * scene.ambient_light.intensity;
-        for light in &scene.lights {
-            if is_in_shadow(&point, &light, scene) {
-                continue;
-            }
+/// Scalar material input, such as a roughness. Scene files may give a
+/// `Value` as a plain number.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(remote = "Self")]
+pub enum Scalar {
+    Value(f64),
+    /// One channel of a texture, read as data without any color decoding:
+    /// image files are always decoded as `Linear`.
+    #[serde(rename_all = "camelCase")]
+    Texture {
+        texture: Texture,
+        #[serde(default)]
+        channel: Channel,
+    },
+    /// Luminance of the output of a shader graph, whose textures are read
+    /// as data too.
+    Graph(ShaderNode),
+}
 
-            let to_light = &light.get_direction(&point).times(-1f64);
-            let to_eye = ray.ray.direction.times(-1f64);
-            let light_normal_reflection = to_light.symmetry(&normal);
-            let diffuse = self.diffuse_reflection * normal.dot(to_light).max(0f64);
-
-            let specular = self.specular_reflection
-                * (&light_normal_reflection.dot(&to_eye).max(0f64)).powf(self.shininess);
-
-            rendered_color = &rendered_color
-                + &(diffuse
-                    * &(&(&light.get_color() * light.get_intensity())
-                        * &self
-                            .diffuse_color
-                            .color(&intersection.object.geometry, &point_precise)));
-            rendered_color = &rendered_color
-                + &(specular
-                    * &(&(&light.get_color() * light.get_intensity())
-                        * &self
-                            .specular_color
-                            .color(&intersection.object.geometry, &point_precise)));
-        }
-
-        if self.reflectivity > 1e-6 && max_bounces > 0 {
-            let reflected_ray = Ray {
-                origin: point,
-                direction: ray.ray.direction.times(-1f64).symmetry(&normal),
-            };
-            let reflected_traced_ray = TracedRay {
-                ray: reflected_ray,
-                inside_objects: ray.inside_objects.clone(),
-            };
-            let reflected_color = cast_ray(scene, &reflected_traced_ray, max_bounces - 1);
-            rendered_color = &rendered_color + &(self.reflectivity * &reflected_color);
+#[derive(Debug, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    #[default]
+    Luminance,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerdeScalar {
+    Number(f64),
+    Input(#[serde(with = "Scalar")] Scalar),
+}
+
+impl<'de> Deserialize<'de> for Scalar {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match SerdeScalar::deserialize(deserializer)? {
+            SerdeScalar::Number(value) => Scalar::Value(value),
+            SerdeScalar::Input(scalar) => scalar,
+        })
+    }
+}
+
+impl Serialize for Scalar {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Scalar::Value(value) => value.serialize(serializer),
+            _ => Scalar::serialize(self, serializer),
         }
-        if self.transparency > 1e-6 && max_bounces > 0 {
-            let outside_index_of_refraction = match ray.inside_objects.last() {
-                Some(x) => scene.objects.objects[*x].material.index_of_refraction,
-                None => 1f64,
-            };
+    }
+}
 
-            let kr = fresnel(
-                &ray.ray.direction,
-                &normal,
-                self.index_of_refraction,
-                outside_index_of_refraction,
-            );
-            let outside = ray.ray.direction.dot(&normal) < 0f64;
-
-            let mut refracted_color = BLACK;
-            if kr < 1f64 {
-                let refracted_ray_origin;
-                let mut new_inside_objects = ray.inside_objects.clone();
-                if outside {
-                    refracted_ray_origin = point_precise.add(&normal.times(-1e-6));
-                    new_inside_objects.push(intersection.object.id);
-                } else {
-                    refracted_ray_origin = point_precise.add(&normal.times(1e-6));
-                    new_inside_objects.retain(|&x| x != intersection.object.id);
-                };
-                let refracted_direction = refract(
-                    &ray.ray.direction,
-                    &normal,
-                    self.index_of_refraction,
-                    outside_index_of_refraction,
-                );
-                let refracted_ray = Ray {
-                    origin: refracted_ray_origin,
-                    direction: refracted_direction,
-                };
-                let refracted_traced_ray = TracedRay {
-                    ray: refracted_ray,
-                    inside_objects: new_inside_objects,
-                };
-                refracted_color = cast_ray(scene, &refracted_traced_ray, max_bounces - 1);
+impl Scalar {
+    /// The value everywhere, if the input is a constant.
+    pub fn constant(&self) -> Option<f64> {
+        match self {
+            Scalar::Value(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self, object: &Object, point: &Point3, footprint: f64) -> f64 {
+        match self {
+            Scalar::Value(value) => *value,
+            Scalar::Texture { texture, channel } => {
+                channel.of(&texture.get_color(object, point, footprint))
             }
+            Scalar::Graph(graph) => graph.color(object, point, footprint).luminance(),
+        }
+    }
 
-            let reflected_direction = ray.ray.direction.times(-1f64).symmetry(&normal);
-            let reflected_ray_origin = if outside {
-                point_precise.add(&normal.times(1e-6))
-            } else {
-                point_precise.add(&normal.times(-1e-6))
-            };
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        match self {
+            Scalar::Value(_) => Ok(()),
+            Scalar::Texture { texture, .. } => texture.load_data_resources(directory),
+            Scalar::Graph(graph) => graph.load_data_resources(directory),
+        }
+    }
+}
 
-            let reflected_ray = Ray {
-                origin: reflected_ray_origin,
-                direction: reflected_direction,
-            };
-            let reflected_traced_ray = TracedRay {
-                ray: reflected_ray,
-                inside_objects: ray.inside_objects.clone(),
+/// Small scale shape of a surface, changing its shading normal but not its
+/// geometry.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SurfaceDetail {
+    /// Tangent space normals encoded as colors: red along the texture x
+    /// axis, green up the image as in OpenGL, blue away from the surface.
+    /// Read as data, so image files are always decoded as `Linear`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<Texture>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bump: Option<Bump>,
+}
+
+/// Height field whose slopes tilt the normal, such as a noise graph.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Bump {
+    /// Height above the surface, in scene units.
+    pub height: Scalar,
+    #[serde(default = "unit_strength")]
+    pub strength: f64,
+}
+
+fn unit_strength() -> f64 {
+    1f64
+}
+
+impl SurfaceDetail {
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        if let Some(normal_map) = &mut self.normal_map {
+            normal_map.load_data_resources(directory)?;
+        }
+        if let Some(bump) = &mut self.bump {
+            bump.height.load_resources(directory)?;
+        }
+        Ok(())
+    }
+
+    /// `normal` of `object` at `point`, tilted by the normal map then the
+    /// bump. The bump slopes are measured over the `footprint`.
+    pub fn apply(
+        &self,
+        object: &Object,
+        point: &Point3,
+        normal: &Vector3,
+        footprint: f64,
+    ) -> Vector3 {
+        if self.normal_map.is_none() && self.bump.is_none() {
+            return *normal;
+        }
+        let (along_x, along_y) = object.get_tangents(point);
+        let tangent = along_x.minus(&normal.times(normal.dot(&along_x)));
+        let tangent = if tangent.norm() > 1e-9 {
+            tangent.normalize()
+        } else {
+            normal.orthonormal_basis().0
+        };
+        // up the image, texture coordinates y going down
+        let mut bitangent = normal.cross(&tangent);
+        if bitangent.dot(&along_y) > 0f64 {
+            bitangent = bitangent.times(-1f64);
+        }
+
+        let mut detailed = *normal;
+        if let Some(normal_map) = &self.normal_map {
+            let encoded = normal_map.get_color(object, point, footprint);
+            detailed = tangent
+                .times(2f64 * encoded.red - 1f64)
+                .plus(&bitangent.times(2f64 * encoded.green - 1f64))
+                .plus(&normal.times(2f64 * encoded.blue - 1f64))
+                .normalize();
+        }
+        if let Some(bump) = &self.bump {
+            let step = footprint.max(1e-4f64);
+            let height = bump.height.value(object, point, footprint);
+            let slope = |direction: &Vector3| {
+                let moved = point.add(&direction.times(step));
+                (bump.height.value(object, &moved, footprint) - height) / step * bump.strength
             };
-            let reflected_color = cast_ray(scene, &reflected_traced_ray, max_bounces - 1);
+            detailed = detailed
+                .minus(&tangent.times(slope(&tangent)))
+                .minus(&bitangent.times(slope(&bitangent)))
+                .normalize();
+        }
+        detailed
+    }
+}
+
+impl Channel {
+    pub fn of(&self, color: &Color) -> f64 {
+        match self {
+            Channel::Red => color.red,
+            Channel::Green => color.green,
+            Channel::Blue => color.blue,
+            Channel::Luminance => color.luminance(),
+        }
+    }
+}
+
+/// Index of refraction of the medium surrounding `object` where a ray inside
+/// `inside_objects` hits it at `point`: the innermost other object the ray
+/// is in.
+pub(crate) fn outside_index_of_refraction(
+    scene: &Scene,
+    inside_objects: &[SceneObjectId],
+    object: SceneObjectId,
+    point: &Point3,
+) -> f64 {
+    match inside_objects.iter().rev().find(|&&id| id != object) {
+        Some(id) => {
+            let outside = &scene.objects.objects[*id];
+            outside
+                .material
+                .index_of_refraction(&outside.geometry, point)
+        }
+        None => 1f64,
+    }
+}
+
+pub(crate) fn no_absorption() -> Color {
+    BLACK
+}
+
+/// Share of the light left after travelling `distance` inside the innermost
+/// of `inside_objects`.
+pub(crate) fn medium_transmittance(
+    scene: &Scene,
+    inside_objects: &[SceneObjectId],
+    distance: f64,
+) -> Color {
+    let absorption = match inside_objects.last() {
+        Some(id) => scene.objects.objects[*id].material.absorption(),
+        None => return WHITE,
+    };
+    let transmittance = |coefficient: f64| {
+        if coefficient > 0f64 {
+            (-coefficient * distance).exp()
+        } else {
+            1f64
+        }
+    };
+    Color {
+        red: transmittance(absorption.red),
+        green: transmittance(absorption.green),
+        blue: transmittance(absorption.blue),
+    }
+}
+
+/// Every light sample seen from `point`, with the color of its light: the
+/// scene lights, then the emissive objects.
+pub(crate) fn light_samples<'a>(
+    scene: &'a Scene,
+    point: &'a Point3,
+) -> impl Iterator<Item = (LightSample, Color)> + 'a {
+    scene
+        .lights
+        .iter()
+        .flat_map(move |light| {
+            let color = light.get_color();
+            light
+                .get_samples(point)
+                .into_iter()
+                .map(move |light_sample| (light_sample, color))
+        })
+        .chain(
+            scene
+                .objects
+                .emitters
+                .get_samples(point, &scene.objects.objects),
+        )
+}
+
+/// Emission of `material` plus the light reaching it directly from every
+/// light sample, as reflected towards the eye by its BSDF.
+pub fn direct_lighting<M>(material: &M, shading: &ShadingPoint, scene: &Scene) -> Color
+where
+    M: MaterialModel + ?Sized,
+{
+    let normal = shading.facing_normal();
+    let side = shading.facing_geometric_normal();
+    let point = shading.point.add(&side.times(1e-6));
+    let mut rendered_color = material.emission().map_or(BLACK, Emission::radiance);
+    for (light_sample, color) in light_samples(scene, &point) {
+        let to_light = light_sample.direction.times(-1f64);
+        let cosine = normal.dot(&to_light);
+        if cosine <= 0f64
+            || side.dot(&to_light) <= 0f64
+            || is_in_shadow(&point, &light_sample, scene)
+        {
+            continue;
+        }
+        let light_color = &color * (light_sample.intensity * cosine * PI);
+        rendered_color = &rendered_color + &(&material.evaluate(shading, &to_light) * &light_color);
+    }
+    rendered_color
+}
+
+/// Color seen through the surface hit by `ray`, along the ideal refraction,
+/// which must not be a total internal reflection. `max_bounces` must be
+/// positive.
+pub(crate) fn trace_refraction(
+    ray: &TracedRay,
+    intersection: &SceneIntersection,
+    scene: &Scene,
+    max_bounces: u8,
+    index_of_refraction: f64,
+    outside_index_of_refraction: f64,
+) -> Color {
+    let point_precise = ray
+        .ray
+        .origin
+        .add(&ray.ray.direction.times(intersection.intersection.distance));
+    let normal = intersection.get_normal(&point_precise);
+    let outside = ray.ray.direction.dot(&normal) < 0f64;
+
+    let refracted_ray_origin;
+    let mut new_inside_objects = ray.inside_objects.clone();
+    if outside {
+        refracted_ray_origin = point_precise.add(&normal.times(-1e-6));
+        new_inside_objects.push(intersection.object.id);
+    } else {
+        refracted_ray_origin = point_precise.add(&normal.times(1e-6));
+        new_inside_objects.retain(|&x| x != intersection.object.id);
+    };
+    let refracted_direction = refract(
+        &ray.ray.direction,
+        &normal,
+        index_of_refraction,
+        outside_index_of_refraction,
+    );
+    let refracted_ray = Ray {
+        origin: refracted_ray_origin,
+        direction: refracted_direction,
+    };
+    let refracted_traced_ray = TracedRay {
+        ray: refracted_ray,
+        inside_objects: new_inside_objects,
+        cone: ray.cone.bounced(intersection.intersection.distance, 0f64),
+    };
+    cast_ray(scene, &refracted_traced_ray, max_bounces - 1)
+}
+
+/// Color seen in the ideal mirror reflection off the surface hit by `ray`,
+/// on whichever side it was hit. `max_bounces` must be positive.
+pub(crate) fn trace_reflection(
+    ray: &TracedRay,
+    intersection: &SceneIntersection,
+    scene: &Scene,
+    max_bounces: u8,
+) -> Color {
+    let point_precise = ray
+        .ray
+        .origin
+        .add(&ray.ray.direction.times(intersection.intersection.distance));
+    let normal = intersection.get_normal(&point_precise);
+    let outside = ray.ray.direction.dot(&normal) < 0f64;
+
+    let reflected_direction = ray.ray.direction.times(-1f64).symmetry(&normal);
+    let reflected_ray_origin = if outside {
+        point_precise.add(&normal.times(1e-6))
+    } else {
+        point_precise.add(&normal.times(-1e-6))
+    };
+
+    let reflected_ray = Ray {
+        origin: reflected_ray_origin,
+        direction: reflected_direction,
+    };
+    let reflected_traced_ray = TracedRay {
+        ray: reflected_ray,
+        inside_objects: ray.inside_objects.clone(),
+        cone: ray.cone.bounced(intersection.intersection.distance, 0f64),
+    };
+    cast_ray(scene, &reflected_traced_ray, max_bounces - 1)
+}
+
+/// Ray hit as seen by the BSDF methods of `MaterialModel`.
+pub struct ShadingPoint<'a> {
+    pub geometry: &'a Object,
+    pub point: Point3,
+    /// Surface normal, on whichever side the geometry defines it, tilted
+    /// by the surface detail of the material.
+    pub normal: Vector3,
+    /// Normal of the geometry itself, which rays leave the surface along.
+    pub geometric_normal: Vector3,
+    /// Unit vector towards where the ray came from.
+    pub to_eye: Vector3,
+    /// Index of refraction of the medium surrounding the object.
+    pub outside_index_of_refraction: f64,
+    /// Width of the surface area the ray stands for, for texture filtering.
+    pub footprint: f64,
+}
+
+impl<'a> ShadingPoint<'a> {
+    /// Where `ray` hits the object of `intersection`.
+    pub fn at(ray: &TracedRay, intersection: &SceneIntersection<'a>, scene: &Scene) -> Self {
+        let point = ray
+            .ray
+            .origin
+            .add(&ray.ray.direction.times(intersection.intersection.distance));
+        let geometry = &intersection.object.geometry;
+        let geometric_normal = intersection.get_normal(&point);
+        let footprint = ray.footprint(intersection.intersection.distance, &geometric_normal);
+        let normal = match intersection.object.material.surface_detail() {
+            Some(detail) => detail.apply(geometry, &point, &geometric_normal, footprint),
+            None => geometric_normal,
+        };
+        ShadingPoint {
+            geometry,
+            point,
+            normal,
+            geometric_normal,
+            to_eye: ray.ray.direction.times(-1f64),
+            outside_index_of_refraction: outside_index_of_refraction(
+                scene,
+                &ray.inside_objects,
+                intersection.object.id,
+                &point,
+            ),
+            footprint,
+        }
+    }
+
+    /// `scalar` at the point, filtered over the footprint.
+    pub fn value(&self, scalar: &Scalar) -> f64 {
+        scalar.value(self.geometry, &self.point, self.footprint)
+    }
+
+    /// The normal turned towards the side of the surface the eye is on.
+    pub fn facing_normal(&self) -> Vector3 {
+        if self.geometric_normal.dot(&self.to_eye) < 0f64 {
+            self.normal.times(-1f64)
+        } else {
+            self.normal
+        }
+    }
 
-            rendered_color = &rendered_color
-                + &(self.transparency
-                    * &(&(&reflected_color * kr) + &(&refracted_color * (1f64 - kr))));
+    /// The geometric normal turned towards the eye.
+    pub fn facing_geometric_normal(&self) -> Vector3 {
+        if self.geometric_normal.dot(&self.to_eye) < 0f64 {
+            self.geometric_normal.times(-1f64)
+        } else {
+            self.geometric_normal
         }
-        rendered_color
     }
 }
 
-fn fresnel(
+/// Direction picked by `MaterialModel::sample`.
+pub struct BsdfSample {
+    pub direction: Vector3,
+    /// BSDF value times the cosine, over the density the direction was
+    /// picked with.
+    pub weight: Color,
+    /// Solid angle density, meaningless for delta lobes.
+    pub pdf: f64,
+    /// Whether the direction comes from the mirror or glass lobe, which no
+    /// other sampling strategy can pick.
+    pub delta: bool,
+}
+
+/// Expresses `local`, given in a frame where +z is `axis`, in world space.
+pub(crate) fn around(axis: &Vector3, local: &Vector3) -> Vector3 {
+    let (tangent, bitangent) = axis.orthonormal_basis();
+    tangent
+        .times(local.x)
+        .plus(&bitangent.times(local.y))
+        .plus(&axis.times(local.z))
+}
+
+pub(crate) fn fresnel(
     incident: &Vector3,
     normal: &Vector3,
     index_of_refraction: f64,
@@ -188,7 +720,7 @@ fn fresnel(
     }
 }
 
-fn refract(
+pub(crate) fn refract(
     incident: &Vector3,
     normal: &Vector3,
     index_of_refraction: f64,
@@ -220,3 +752,422 @@ fn refract(
             .normalize()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::engine::{SceneObject, SceneObjects};
+    use crate::geometry::{Plane, RayCone, Sphere};
+    use crate::light::AmbientLight;
+    use crate::texture::test_plane;
+
+    /// Shows the facing normal as a color, ignoring every light.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    struct DebugNormals {
+        brightness: f64,
+    }
+
+    impl MaterialModel for DebugNormals {
+        fn type_tag(&self) -> &str {
+            "debugNormals"
+        }
+
+        fn to_json(&self) -> serde_json::Value {
+            serde_json::to_value(self).unwrap()
+        }
+
+        fn clone_box(&self) -> Box<dyn MaterialModel> {
+            Box::new(self.clone())
+        }
+
+        fn render_color(
+            &self,
+            ray: &TracedRay,
+            intersection: &SceneIntersection,
+            scene: &Scene,
+            _max_bounces: u8,
+        ) -> Color {
+            let normal = ShadingPoint::at(ray, intersection, scene).facing_normal();
+            let color = Color {
+                red: (normal.x + 1f64) / 2f64,
+                green: (normal.y + 1f64) / 2f64,
+                blue: (normal.z + 1f64) / 2f64,
+            };
+            &color * self.brightness
+        }
+
+        fn evaluate(&self, _shading: &ShadingPoint, _to_light: &Vector3) -> Color {
+            BLACK
+        }
+
+        fn pdf(&self, _shading: &ShadingPoint, _to_light: &Vector3) -> f64 {
+            0f64
+        }
+
+        fn sample(&self, _shading: &ShadingPoint, _rng: &mut Rng) -> Option<BsdfSample> {
+            None
+        }
+    }
+
+    const ORIGIN: Point3 = Point3 {
+        x: 0f64,
+        y: 0f64,
+        z: 0f64,
+    };
+
+    // Scene lit by a white ambient light only, seen from z = -5.
+    fn scene(objects: Vec<(Object, Material)>) -> Scene {
+        let objects = objects
+            .into_iter()
+            .enumerate()
+            .map(|(id, (geometry, material))| SceneObject {
+                id,
+                geometry,
+                material,
+            })
+            .collect();
+        Scene {
+            objects: SceneObjects::initialize(objects),
+            ambient_light: AmbientLight {
+                color: WHITE,
+                intensity: 1f64,
+            },
+            lights: vec![],
+            camera: Camera::look_at(
+                Point3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z: -5f64,
+                },
+                &ORIGIN,
+                Vector3 {
+                    x: 0f64,
+                    y: 1f64,
+                    z: 0f64,
+                },
+                1f64,
+                1,
+                1,
+            ),
+        }
+    }
+
+    fn ray_along_z() -> TracedRay {
+        TracedRay {
+            ray: Ray {
+                origin: Point3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z: -5f64,
+                },
+                direction: Vector3 {
+                    x: 0f64,
+                    y: 0f64,
+                    z: 1f64,
+                },
+            },
+            inside_objects: vec![],
+            cone: RayCone::default(),
+        }
+    }
+
+    #[test]
+    fn scene_files_name_registered_materials() {
+        register_material::<DebugNormals>("debugNormals");
+        let white = r#"{"Color": {"red": 1, "green": 1, "blue": 1}}"#;
+        let materials: Vec<Material> = serde_json::from_str(&format!(
+            r#"[
+                {{"type": "debugNormals", "brightness": 0.5}},
+                {{"type": "microfacet", "baseColor": {0},
+                  "metallic": {{"Value": 1}}, "roughness": {{"Value": 0.5}}}},
+                {{"baseColor": {0}, "metallic": {{"Value": 0}}, "roughness": {{"Value": 1}}}},
+                {{"ambientColor": {0}, "ambientReflection": 0, "diffuseColor": {0},
+                  "diffuseReflection": 1, "specularColor": {0}, "specularReflection": 0,
+                  "shininess": 1, "reflectivity": 0, "transparency": 0,
+                  "indexOfRefraction": 1}}
+            ]"#,
+            white
+        ))
+        .unwrap();
+        let type_tags = |materials: &[Material]| -> Vec<String> {
+            materials.iter().map(|m| m.type_tag().to_string()).collect()
+        };
+        let expected = ["debugNormals", "microfacet", "microfacet", "phong"];
+        assert_eq!(type_tags(&materials), expected);
+
+        let serialized = serde_json::to_string(&materials).unwrap();
+        let deserialized: Vec<Material> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(type_tags(&deserialized), expected);
+
+        let unknown = serde_json::from_str::<Material>(r#"{"type": "toon"}"#).unwrap_err();
+        assert!(unknown.to_string().contains("toon"), "{}", unknown);
+
+        let scene = scene(vec![(
+            Object::Sphere(Sphere {
+                center: ORIGIN,
+                radius: 1f64,
+            }),
+            materials[0].clone(),
+        )]);
+        let color = cast_ray(&scene, &ray_along_z(), 1);
+        assert!((color.red - 0.25f64).abs() < 1e-6, "{:?}", color);
+        assert!((color.green - 0.25f64).abs() < 1e-6, "{:?}", color);
+        assert!(color.blue.abs() < 1e-6, "{:?}", color);
+    }
+
+    #[test]
+    fn phong_inputs_take_numbers_or_textures() {
+        let white = serde_json::json!({"Color": {"red": 1, "green": 1, "blue": 1}});
+        let material: Material = serde_json::from_value(serde_json::json!({
+            "ambientColor": white, "ambientReflection": 0, "diffuseColor": white,
+            "diffuseReflection": {"Value": 1}, "specularColor": white, "specularReflection": 0,
+            "shininess": 1, "reflectivity": 0, "transparency": 0,
+            "indexOfRefraction": {"Texture": {"texture": {
+                "pixels": [
+                    [{"red": 1, "green": 1, "blue": 1}],
+                    [{"red": 1.5, "green": 1.5, "blue": 1.5}],
+                ],
+                "scale": 1,
+                "offset": {"x": 0, "y": 0},
+            }}},
+        }))
+        .unwrap();
+        let plane = test_plane();
+        let index_of_refraction = |x: f64| {
+            material.index_of_refraction(
+                &plane,
+                &Point3 {
+                    x,
+                    y: -0.5f64,
+                    z: 0f64,
+                },
+            )
+        };
+        assert_eq!(index_of_refraction(-0.5f64), 1f64);
+        assert_eq!(index_of_refraction(-1.5f64), 1.5f64);
+
+        let serialized = serde_json::to_value(&material).unwrap();
+        assert_eq!(serialized["diffuseReflection"], 1f64);
+        assert_eq!(serialized["shininess"], 1f64);
+        assert!(serialized["indexOfRefraction"]["Texture"].is_object());
+    }
+
+    #[test]
+    fn normal_map_files_are_read_as_linear_data() {
+        let path = std::env::temp_dir().join(format!("normal-map-{}.png", std::process::id()));
+        image::RgbImage::from_raw(1, 1, vec![255, 128, 128])
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let normal_map = |color_space: &str| {
+            let mut detail: SurfaceDetail = serde_json::from_str(&format!(
+                r#"{{"normalMap": {{"path": {:?}, {} "scale": 1, "offset": {{"x": 0, "y": 0}}}}}}"#,
+                path.to_str().unwrap(),
+                color_space
+            ))
+            .unwrap();
+            detail.load_resources(Path::new("")).map(|_| detail)
+        };
+        let detail = normal_map("");
+        let srgb = normal_map(r#""colorSpace": "Srgb","#);
+        std::fs::remove_file(&path).unwrap();
+
+        let detail = detail.unwrap();
+        let srgb = srgb.err().unwrap();
+        assert!(srgb.contains("Linear"), "{}", srgb);
+        let plane = test_plane();
+        let point = Point3 {
+            x: -0.5f64,
+            y: -0.5f64,
+            z: 0f64,
+        };
+        let along_x = detail.apply(&plane, &point, &plane.get_normal(&point), 0f64);
+        assert!((along_x.x + 1f64).abs() < 1e-4, "{:?}", along_x);
+        assert!(
+            along_x.y.abs() < 1e-2 && along_x.z.abs() < 1e-2,
+            "{:?}",
+            along_x
+        );
+    }
+
+    #[test]
+    fn scalar_texture_files_are_read_as_linear_data() {
+        let path = std::env::temp_dir().join(format!("roughness-{}.png", std::process::id()));
+        image::RgbImage::from_raw(1, 1, vec![128, 128, 128])
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let texture = |color_space: &str| {
+            serde_json::json!({
+                "path": path.to_str().unwrap(),
+                "colorSpace": color_space,
+                "scale": 1,
+                "offset": {"x": 0, "y": 0},
+            })
+        };
+        let roughness = |json: serde_json::Value| {
+            let mut roughness: Scalar = serde_json::from_value(json).unwrap();
+            roughness.load_resources(Path::new("")).map(|_| roughness)
+        };
+        let in_texture = roughness(serde_json::json!({"Texture": {"texture": texture("Linear")}}));
+        let in_graph = roughness(serde_json::json!({"Graph": {"Texture": texture("Linear")}}));
+        let mut unspecified = texture("Linear");
+        unspecified.as_object_mut().unwrap().remove("colorSpace");
+        let by_default = roughness(serde_json::json!({"Texture": {"texture": unspecified}}));
+        let srgb = roughness(serde_json::json!({"Graph": {"Texture": texture("Srgb")}}));
+        std::fs::remove_file(&path).unwrap();
+
+        let point = Point3 {
+            x: -0.5f64,
+            y: -0.5f64,
+            z: 0f64,
+        };
+        for roughness in [in_texture, in_graph, by_default] {
+            let value = roughness.unwrap().value(&test_plane(), &point, 0f64);
+            assert!((value - 128f64 / 255f64).abs() < 1e-9, "{}", value);
+        }
+        let srgb = srgb.err().unwrap();
+        assert!(srgb.contains("Linear"), "{}", srgb);
+    }
+
+    #[test]
+    fn thick_glass_absorbs_more_than_thin_glass() {
+        let glass = |absorption: Color| {
+            Material::new(PhongMaterial {
+                ambient_color: Coloration::Color(BLACK),
+                ambient_reflection: Scalar::Value(0f64),
+                diffuse_color: Coloration::Color(BLACK),
+                diffuse_reflection: Scalar::Value(0f64),
+                specular_color: Coloration::Color(BLACK),
+                specular_reflection: Scalar::Value(0f64),
+                shininess: Scalar::Value(1f64),
+                reflectivity: Scalar::Value(0f64),
+                transparency: Scalar::Value(1f64),
+                index_of_refraction: Scalar::Value(1f64),
+                absorption,
+                detail: SurfaceDetail::default(),
+                emission: None,
+            })
+        };
+        let backdrop = Material::new(PhongMaterial {
+            ambient_color: Coloration::Color(WHITE),
+            ambient_reflection: Scalar::Value(1f64),
+            diffuse_color: Coloration::Color(BLACK),
+            diffuse_reflection: Scalar::Value(0f64),
+            specular_color: Coloration::Color(BLACK),
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(1f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(1f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        });
+        let seen_through = |radius: f64| {
+            let scene = scene(vec![
+                (
+                    Object::Sphere(Sphere {
+                        center: ORIGIN,
+                        radius,
+                    }),
+                    glass(Color {
+                        red: 1f64,
+                        green: 0f64,
+                        blue: 0.5f64,
+                    }),
+                ),
+                (
+                    Object::Plane(Plane {
+                        point: Point3 {
+                            x: 0f64,
+                            y: 0f64,
+                            z: 5f64,
+                        },
+                        normal: Vector3 {
+                            x: 0f64,
+                            y: 0f64,
+                            z: -1f64,
+                        },
+                    }),
+                    backdrop.clone(),
+                ),
+            ]);
+            cast_ray(&scene, &ray_along_z(), 4)
+        };
+
+        let thin = seen_through(0.05f64);
+        let thick = seen_through(1f64);
+        assert!((thin.red - (-0.1f64).exp()).abs() < 1e-6, "{:?}", thin);
+        assert!((thick.red - (-2f64).exp()).abs() < 1e-6, "{:?}", thick);
+        assert!((thick.green - 1f64).abs() < 1e-6, "{:?}", thick);
+        assert!((thick.blue - (-1f64).exp()).abs() < 1e-6, "{:?}", thick);
+    }
+
+    #[test]
+    fn surface_detail_tilts_the_shading_normal() {
+        let plane = test_plane();
+        let point = Point3 {
+            x: 0.3f64,
+            y: -1.7f64,
+            z: 0f64,
+        };
+        let normal_map = |encoded: serde_json::Value| -> SurfaceDetail {
+            serde_json::from_value(serde_json::json!({"normalMap": {
+                "pixels": [[encoded]],
+                "scale": 1,
+                "offset": {"x": 0, "y": 0},
+            }}))
+            .unwrap()
+        };
+        let detailed =
+            |detail: &SurfaceDetail| detail.apply(&plane, &point, &plane.get_normal(&point), 0f64);
+        // texture coordinates grow along -x and -y on this plane
+        let along_x = detailed(&normal_map(
+            serde_json::json!({"red": 1, "green": 0.5, "blue": 0.5}),
+        ));
+        assert!((along_x.x + 1f64).abs() < 1e-9, "{:?}", along_x);
+        let up_the_image = detailed(&normal_map(
+            serde_json::json!({"red": 0.5, "green": 1, "blue": 0.5}),
+        ));
+        assert!((up_the_image.y - 1f64).abs() < 1e-9, "{:?}", up_the_image);
+        let flat = detailed(&normal_map(
+            serde_json::json!({"red": 0.5, "green": 0.5, "blue": 1}),
+        ));
+        assert!((flat.z - 1f64).abs() < 1e-9, "{:?}", flat);
+
+        let bump = |strength: f64| -> SurfaceDetail {
+            serde_json::from_value(serde_json::json!({"bump": {
+                "height": {"Graph": {"Noise": {
+                    "pattern": "Perlin",
+                    "colors": [
+                        {"red": 0, "green": 0, "blue": 0},
+                        {"red": 1, "green": 1, "blue": 1},
+                    ],
+                }}},
+                "strength": strength,
+            }}))
+            .unwrap()
+        };
+        let height = |point: &Point3| match &bump(1f64).bump.unwrap().height {
+            Scalar::Graph(graph) => graph.color(&plane, point, 0f64).red,
+            height => panic!("{:?}", height),
+        };
+        let bumped = detailed(&bump(0.2f64));
+        assert!((bumped.norm() - 1f64).abs() < 1e-9);
+        assert!(bumped.z < 1f64 - 1e-6, "{:?}", bumped);
+        // tilted away from the slope, downhill
+        let downhill = point.add(
+            &Vector3 {
+                x: bumped.x,
+                y: bumped.y,
+                z: 0f64,
+            }
+            .normalize()
+            .times(1e-3f64),
+        );
+        assert!(height(&downhill) < height(&point));
+        assert!((detailed(&bump(0f64)).z - 1f64).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,533 @@
+use crate::color::{Color, BLACK, WHITE};
+use crate::engine::{Scene, SceneIntersection, TracedRay};
+use crate::geometry::{Object, Point2, Point3, Vector3};
+use crate::material::{
+    around, direct_lighting, no_absorption, trace_reflection, trace_refraction, BsdfSample,
+    Coloration, Emission, MaterialModel, Scalar, ShadingPoint, SurfaceDetail,
+};
+use crate::sampler::{cosine_hemisphere, Rng};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::path::Path;
+
+/// Metallic-roughness material as found in glTF assets: a Cook-Torrance
+/// specular lobe with the GGX distribution, Smith shadowing and Schlick's
+/// Fresnel, over a Lambertian base for dielectrics. Dielectrics may also
+/// let light through, refracting it with the same rough microfacets.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MicrofacetMaterial {
+    /// Diffuse albedo of dielectrics, reflectance of metals at normal
+    /// incidence, and tint of the transmitted light.
+    pub base_color: Coloration,
+    /// 0 for a dielectric, 1 for a metal.
+    pub metallic: Scalar,
+    /// Perceptual roughness, squared into the GGX width.
+    pub roughness: Scalar,
+    /// Share of the dielectric base refracted through instead of diffused.
+    #[serde(default = "no_transmission")]
+    pub transmission: Scalar,
+    #[serde(default = "default_index_of_refraction")]
+    pub index_of_refraction: Scalar,
+    /// Beer-Lambert coefficients of the light travelling inside.
+    #[serde(default = "no_absorption")]
+    pub absorption: Color,
+    #[serde(flatten)]
+    pub detail: SurfaceDetail,
+    #[serde(default)]
+    pub emission: Option<Emission>,
+}
+
+fn no_transmission() -> Scalar {
+    Scalar::Value(0f64)
+}
+
+fn default_index_of_refraction() -> Scalar {
+    Scalar::Value(1.5f64)
+}
+
+/// Material inputs at a point, with the geometry of the hit.
+struct Lobes {
+    base_color: Color,
+    metallic: f64,
+    transmission: f64,
+    alpha: f64,
+    normal: Vector3,
+    to_eye: Vector3,
+    cos_eye: f64,
+    /// Indices of refraction on the side of the eye and on the other side.
+    eta_eye: f64,
+    eta_through: f64,
+}
+
+impl Lobes {
+    fn dielectric_reflectance(&self) -> f64 {
+        let ratio = (self.eta_through - self.eta_eye) / (self.eta_through + self.eta_eye);
+        ratio * ratio
+    }
+
+    // Schlick's approximation for the dielectric part, on the side of the
+    // eye, and total when light cannot get out of the denser medium.
+    fn dielectric_fresnel(&self, cosine: f64) -> f64 {
+        let mut cosine = cosine.abs().min(1f64);
+        if self.eta_eye > self.eta_through {
+            let ratio = self.eta_eye / self.eta_through;
+            let sine_squared = ratio * ratio * (1f64 - cosine * cosine);
+            if sine_squared >= 1f64 {
+                return 1f64;
+            }
+            cosine = (1f64 - sine_squared).sqrt();
+        }
+        schlick(self.dielectric_reflectance(), cosine)
+    }
+
+    // Schlick's approximation blending the dielectric reflectance into the
+    // base color as the surface turns metallic.
+    fn specular_fresnel(&self, cosine: f64) -> Color {
+        let dielectric = self.dielectric_fresnel(cosine);
+        let metal = schlick_color(&self.base_color, cosine.abs().min(1f64));
+        &((dielectric * (1f64 - self.metallic)) * &WHITE) + &(&metal * self.metallic)
+    }
+
+    fn distribution(&self, half: &Vector3) -> f64 {
+        let cosine = self.normal.dot(half);
+        if cosine <= 0f64 {
+            return 0f64;
+        }
+        let alpha_squared = self.alpha * self.alpha;
+        let denominator = cosine * cosine * (alpha_squared - 1f64) + 1f64;
+        alpha_squared / (PI * denominator * denominator)
+    }
+
+    fn masking(&self, direction: &Vector3) -> f64 {
+        let cosine = self.normal.dot(direction).abs();
+        let alpha_squared = self.alpha * self.alpha;
+        2f64 * cosine / (cosine + (alpha_squared + (1f64 - alpha_squared) * cosine * cosine).sqrt())
+    }
+
+    fn shadowing(&self, to_light: &Vector3) -> f64 {
+        self.masking(&self.to_eye) * self.masking(to_light)
+    }
+
+    // Relative chances of sampling the diffuse, reflected and transmitted
+    // lobes.
+    fn lobe_weights(&self) -> [f64; 3] {
+        let fresnel = self.dielectric_fresnel(self.cos_eye);
+        let dielectric = 1f64 - self.metallic;
+        [
+            dielectric
+                * (1f64 - self.transmission)
+                * (1f64 - fresnel)
+                * self.base_color.luminance().max(0f64),
+            self.specular_fresnel(self.cos_eye).luminance().max(0f64),
+            dielectric * self.transmission * (1f64 - fresnel),
+        ]
+    }
+
+    // Microfacet normal refracting `to_eye` into `to_light`, and the
+    // denominator of the change of variables between the two.
+    fn refraction_half(&self, to_light: &Vector3) -> Option<(Vector3, f64)> {
+        let eta = self.eta_through / self.eta_eye;
+        let half = self.to_eye.plus(&to_light.times(eta));
+        // index matched media let light straight through, a delta lobe
+        if half.norm() < 1e-9 {
+            return None;
+        }
+        let mut half = half.normalize();
+        if half.dot(&self.normal) < 0f64 {
+            half = half.times(-1f64);
+        }
+        let eye_half = self.to_eye.dot(&half);
+        let light_half = to_light.dot(&half);
+        if eye_half <= 0f64 || light_half >= 0f64 {
+            return None;
+        }
+        let denominator = eye_half + eta * light_half;
+        Some((half, denominator * denominator))
+    }
+
+    fn evaluate(&self, to_light: &Vector3) -> Color {
+        let cos_light = self.normal.dot(to_light);
+        if cos_light > 0f64 {
+            let half = self.to_eye.plus(to_light).normalize();
+            let eye_half = self.to_eye.dot(&half);
+            let specular = self.distribution(&half) * self.shadowing(to_light)
+                / (4f64 * cos_light * self.cos_eye);
+            let diffuse = (1f64 - self.metallic)
+                * (1f64 - self.transmission)
+                * (1f64 - self.dielectric_fresnel(eye_half))
+                / PI;
+            &(&self.specular_fresnel(eye_half) * specular) + &(&self.base_color * diffuse)
+        } else if cos_light < 0f64 && self.transmission > 0f64 && self.metallic < 1f64 {
+            let (half, denominator) = match self.refraction_half(to_light) {
+                Some(refraction) => refraction,
+                None => return BLACK,
+            };
+            let eta = self.eta_through / self.eta_eye;
+            let eye_half = self.to_eye.dot(&half);
+            let transmitted = (1f64 - self.metallic)
+                * self.transmission
+                * (1f64 - self.dielectric_fresnel(eye_half))
+                * self.distribution(&half)
+                * self.shadowing(to_light)
+                * eta
+                * eta
+                * (eye_half * to_light.dot(&half)).abs()
+                / (-cos_light * self.cos_eye * denominator);
+            &self.base_color * transmitted
+        } else {
+            BLACK
+        }
+    }
+
+    fn pdf(&self, to_light: &Vector3) -> f64 {
+        let weights = self.lobe_weights();
+        let total: f64 = weights.iter().sum();
+        let cos_light = self.normal.dot(to_light);
+        if total <= 0f64 {
+            return 0f64;
+        }
+        if cos_light > 0f64 {
+            let half = self.to_eye.plus(to_light).normalize();
+            let reflected =
+                self.distribution(&half) * self.normal.dot(&half) / (4f64 * self.to_eye.dot(&half));
+            (weights[0] * cos_light / PI + weights[1] * reflected) / total
+        } else {
+            match self.refraction_half(to_light) {
+                Some((half, denominator)) => {
+                    let eta = self.eta_through / self.eta_eye;
+                    let transmitted = self.distribution(&half)
+                        * self.normal.dot(&half)
+                        * eta
+                        * eta
+                        * to_light.dot(&half).abs()
+                        / denominator;
+                    weights[2] * transmitted / total
+                }
+                None => 0f64,
+            }
+        }
+    }
+
+    // Microfacet normal picked proportionally to its projected area.
+    fn sample_half(&self, sample: &Point2) -> Vector3 {
+        let alpha_squared = self.alpha * self.alpha;
+        let cosine_squared = (1f64 - sample.x) / (1f64 + (alpha_squared - 1f64) * sample.x);
+        let cosine = cosine_squared.sqrt();
+        let sine = (1f64 - cosine_squared).max(0f64).sqrt();
+        let angle = 2f64 * PI * sample.y;
+        around(
+            &self.normal,
+            &Vector3 {
+                x: sine * angle.cos(),
+                y: sine * angle.sin(),
+                z: cosine,
+            },
+        )
+    }
+
+    fn sample(&self, rng: &mut Rng) -> Option<BsdfSample> {
+        let weights = self.lobe_weights();
+        let total: f64 = weights.iter().sum();
+        if total <= 0f64 {
+            return None;
+        }
+        let mut choice = rng.next_f64() * total;
+        let mut lobe = 0;
+        while lobe < 2 && choice >= weights[lobe] {
+            choice -= weights[lobe];
+            lobe += 1;
+        }
+        let sample = Point2 {
+            x: rng.next_f64(),
+            y: rng.next_f64(),
+        };
+        let direction = match lobe {
+            0 => around(&self.normal, &cosine_hemisphere(&sample)),
+            1 => self.to_eye.symmetry(&self.sample_half(&sample)),
+            _ => {
+                let half = self.sample_half(&sample);
+                let cosine = self.to_eye.dot(&half);
+                let ratio = self.eta_eye / self.eta_through;
+                let sine_squared = ratio * ratio * (1f64 - cosine * cosine);
+                if cosine <= 0f64 || sine_squared >= 1f64 {
+                    return None;
+                }
+                self.to_eye
+                    .times(-ratio)
+                    .plus(&half.times(ratio * cosine - (1f64 - sine_squared).sqrt()))
+            }
+        };
+
+        // the lobes must not spill over to the other side of the surface,
+        // where `pdf` would not account for them
+        if (self.normal.dot(&direction) > 0f64) != (lobe < 2) {
+            return None;
+        }
+        let pdf = self.pdf(&direction);
+        if pdf <= 0f64 {
+            return None;
+        }
+        Some(BsdfSample {
+            weight: &self.evaluate(&direction) * (self.normal.dot(&direction).abs() / pdf),
+            direction,
+            pdf,
+            delta: false,
+        })
+    }
+}
+
+fn schlick(reflectance: f64, cosine: f64) -> f64 {
+    reflectance + (1f64 - reflectance) * (1f64 - cosine).powi(5)
+}
+
+fn schlick_color(reflectance: &Color, cosine: f64) -> Color {
+    Color {
+        red: schlick(reflectance.red, cosine),
+        green: schlick(reflectance.green, cosine),
+        blue: schlick(reflectance.blue, cosine),
+    }
+}
+
+impl MicrofacetMaterial {
+    fn lobes(&self, shading: &ShadingPoint) -> Lobes {
+        let normal = shading.facing_normal();
+        let entering = shading.geometric_normal.dot(&shading.to_eye) >= 0f64;
+        let index_of_refraction = shading.value(&self.index_of_refraction);
+        let (eta_eye, eta_through) = if entering {
+            (shading.outside_index_of_refraction, index_of_refraction)
+        } else {
+            (index_of_refraction, shading.outside_index_of_refraction)
+        };
+        let roughness = self
+            .roughness
+            .value(shading.geometry, &shading.point, shading.footprint)
+            .clamp(0f64, 1f64);
+        Lobes {
+            base_color: self
+                .base_color
+                .color(shading.geometry, &shading.point, shading.footprint),
+            metallic: self
+                .metallic
+                .value(shading.geometry, &shading.point, shading.footprint)
+                .clamp(0f64, 1f64),
+            transmission: self
+                .transmission
+                .value(shading.geometry, &shading.point, shading.footprint)
+                .clamp(0f64, 1f64),
+            // the distribution degenerates on perfectly smooth surfaces
+            alpha: (roughness * roughness).max(1e-3f64),
+            cos_eye: normal.dot(&shading.to_eye).max(1e-9f64),
+            normal,
+            to_eye: shading.to_eye,
+            eta_eye,
+            eta_through,
+        }
+    }
+}
+
+impl MaterialModel for MicrofacetMaterial {
+    fn type_tag(&self) -> &str {
+        "microfacet"
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap()
+    }
+
+    fn clone_box(&self) -> Box<dyn MaterialModel> {
+        Box::new(self.clone())
+    }
+
+    fn emission(&self) -> Option<&Emission> {
+        self.emission.as_ref()
+    }
+
+    fn index_of_refraction(&self, object: &Object, point: &Point3) -> f64 {
+        self.index_of_refraction.value(object, point, 0f64)
+    }
+
+    fn absorption(&self) -> Color {
+        self.absorption
+    }
+
+    fn surface_detail(&self) -> Option<&SurfaceDetail> {
+        Some(&self.detail)
+    }
+
+    fn map_colors(&mut self, f: &dyn Fn(&Color) -> Color) {
+        self.base_color.map_colors(&f);
+        if let Some(emission) = &mut self.emission {
+            emission.color = f(&emission.color);
+        }
+    }
+
+    fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        self.base_color.load_resources(directory)?;
+        for scalar in [
+            &mut self.metallic,
+            &mut self.roughness,
+            &mut self.transmission,
+            &mut self.index_of_refraction,
+        ] {
+            scalar.load_resources(directory)?;
+        }
+        self.detail.load_resources(directory)
+    }
+
+    /// Direct lighting through the full BSDF, but indirect light only along
+    /// the ideal reflection and refraction, fading out the reflection as
+    /// the surface gets rougher. Blurry reflections need the path tracer.
+    fn render_color(
+        &self,
+        ray: &TracedRay,
+        intersection: &SceneIntersection,
+        scene: &Scene,
+        max_bounces: u8,
+    ) -> Color {
+        let shading = ShadingPoint::at(ray, intersection, scene);
+        let lobes = self.lobes(&shading);
+
+        let diffuse_albedo = (1f64 - lobes.metallic) * (1f64 - lobes.transmission);
+        let ambient = &(&scene.ambient_light.color * &(&lobes.base_color * diffuse_albedo))
+            * scene.ambient_light.intensity;
+        let mut rendered_color = &ambient + &direct_lighting(self, &shading, scene);
+
+        if max_bounces == 0 {
+            return rendered_color;
+        }
+        let smoothness = (1f64 - lobes.alpha.sqrt()).powi(2);
+        let reflectance = &lobes.specular_fresnel(lobes.cos_eye) * smoothness;
+        if reflectance.luminance() > 1e-6 {
+            let reflected_color = trace_reflection(ray, intersection, scene, max_bounces);
+            rendered_color = &rendered_color + &(&reflected_color * &reflectance);
+        }
+        let fresnel = lobes.dielectric_fresnel(lobes.cos_eye);
+        let transmittance = (1f64 - lobes.metallic) * lobes.transmission * (1f64 - fresnel);
+        if transmittance > 1e-6 {
+            let refracted_color = trace_refraction(
+                ray,
+                intersection,
+                scene,
+                max_bounces,
+                shading.value(&self.index_of_refraction),
+                shading.outside_index_of_refraction,
+            );
+            rendered_color =
+                &rendered_color + &(&refracted_color * &(&lobes.base_color * transmittance));
+        }
+        rendered_color
+    }
+
+    fn evaluate(&self, shading: &ShadingPoint, to_light: &Vector3) -> Color {
+        self.lobes(shading).evaluate(to_light)
+    }
+
+    fn pdf(&self, shading: &ShadingPoint, to_light: &Vector3) -> f64 {
+        self.lobes(shading).pdf(to_light)
+    }
+
+    /// Picks the diffuse, reflected or transmitted lobe by its expected
+    /// contribution, then a microfacet normal following the GGX
+    /// distribution.
+    fn sample(&self, shading: &ShadingPoint, rng: &mut Rng) -> Option<BsdfSample> {
+        self.lobes(shading).sample(rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Sphere;
+    use crate::sampler::uniform_sphere;
+
+    fn material(metallic: f64, roughness: f64, transmission: f64) -> MicrofacetMaterial {
+        MicrofacetMaterial {
+            base_color: Coloration::Color(WHITE),
+            metallic: Scalar::Value(metallic),
+            roughness: Scalar::Value(roughness),
+            transmission: Scalar::Value(transmission),
+            index_of_refraction: Scalar::Value(1.5f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }
+    }
+
+    #[test]
+    fn sampling_agrees_with_evaluation() {
+        let geometry = Object::Sphere(Sphere {
+            center: Point3 {
+                x: 0f64,
+                y: 0f64,
+                z: -1f64,
+            },
+            radius: 1f64,
+        });
+        let shading = ShadingPoint {
+            geometry: &geometry,
+            point: Point3 {
+                x: 0f64,
+                y: 0f64,
+                z: 0f64,
+            },
+            normal: Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+            geometric_normal: Vector3 {
+                x: 0f64,
+                y: 0f64,
+                z: 1f64,
+            },
+            to_eye: Vector3 {
+                x: 0.6f64,
+                y: 0f64,
+                z: 0.8f64,
+            },
+            outside_index_of_refraction: 1f64,
+            footprint: 0f64,
+        };
+        for material in [
+            material(1f64, 0.5f64, 0f64),
+            material(0f64, 0.4f64, 0f64),
+            material(0f64, 0.5f64, 1f64),
+        ]
+        .iter()
+        {
+            let count = 200_000;
+            let mut rng = Rng::new(0);
+            let mut sampled = 0f64;
+            let mut uniform = 0f64;
+            for _ in 0..count {
+                if let Some(sample) = material.sample(&shading, &mut rng) {
+                    sampled += sample.weight.green;
+                }
+                let direction = uniform_sphere(&Point2 {
+                    x: rng.next_f64(),
+                    y: rng.next_f64(),
+                });
+                let cosine = direction.z.abs();
+                uniform += material.evaluate(&shading, &direction).green * cosine * 4f64 * PI;
+            }
+            sampled /= count as f64;
+            uniform /= count as f64;
+
+            // rough surfaces lose some light to shadowing, and never create any
+            assert!(
+                sampled < 1f64 && sampled > 0.75f64,
+                "{:?} {}",
+                material,
+                sampled
+            );
+            assert!(
+                (sampled / uniform - 1f64).abs() < 0.03f64,
+                "{:?} {} {}",
+                material,
+                sampled,
+                uniform
+            );
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::camera::Camera;
+use crate::color::{Color, ColorSpace};
 use crate::engine::Scene;
 use crate::engine::SceneObject;
 use crate::engine::SceneObjects;
@@ -6,12 +7,13 @@ use crate::geometry::{
     get_triangles, Mesh, MeshPlainTriangle, MeshVertex, Object, Plane, Point2, Point3, Sphere,
     Vector3,
 };
-use crate::light::{AmbientLight, Light};
+use crate::light::{AmbientLight, IesProfile, Light};
 use crate::material::Material;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
 use std::sync::Arc;
 
 pub fn parse_obj(filename: String) -> Mesh {
@@ -40,12 +42,7 @@ pub fn parse_obj(filename: String) -> Mesh {
             _ => continue,
         }
     }
-    Mesh {
-        vertices: vertices,
-        texture_mapping: texture_mapping,
-        normals: normals,
-        triangles: triangles,
-    }
+    Mesh::new(vertices, texture_mapping, normals, triangles)
 }
 
 pub fn parse_obj_string(serialized_scene: &str) -> Mesh {
@@ -70,12 +67,7 @@ pub fn parse_obj_string(serialized_scene: &str) -> Mesh {
             _ => continue,
         }
     }
-    Mesh {
-        vertices: vertices,
-        texture_mapping: texture_mapping,
-        normals: normals,
-        triangles: triangles,
-    }
+    Mesh::new(vertices, texture_mapping, normals, triangles)
 }
 
 pub fn parse_vertex(line: Vec<&str>) -> Point3 {
@@ -134,14 +126,166 @@ fn parse_face_vertex(raw_vertex: &str) -> MeshVertex {
     }
 }
 
-pub fn deserialize_scene(serialized_scene: &str) -> Scene {
-    let serde_scene: SerdeScene = serde_json::from_str(serialized_scene).unwrap();
-    Scene {
+pub fn parse_ies(filename: String) -> Result<IesProfile, String> {
+    let mut source = String::new();
+    open_scene_file(Path::new(&filename))?
+        .read_to_string(&mut source)
+        .map_err(|e| format!("{}: {}", filename, e))?;
+    let mut profile = parse_ies_string(&source)?;
+    profile.path = Some(filename);
+    Ok(profile)
+}
+
+/// Reads the candela table of an IES LM-63 photometric file. Intensities are
+/// normalized so that the brightest direction is 1.
+pub fn parse_ies_string(source: &str) -> Result<IesProfile, String> {
+    let mut lines = source.lines();
+    let tilt = loop {
+        match lines.next() {
+            Some(line) if line.trim_start().starts_with("TILT=") => {
+                break line.trim_start()["TILT=".len()..].trim().to_string();
+            }
+            Some(_) => continue,
+            None => return Err("missing TILT line".to_string()),
+        }
+    };
+    let rest: Vec<&str> = lines.collect();
+    let mut values: IesValues = rest
+        .iter()
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            token
+                .parse::<f64>()
+                .map_err(|_| format!("invalid number {}", token))
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    if tilt == "INCLUDE" {
+        // lamp to luminaire geometry, then the tilt angles and their factors
+        next_ies_value(&mut values)?;
+        let pairs = next_ies_count(&mut values)?;
+        for _ in 0..2 * pairs {
+            next_ies_value(&mut values)?;
+        }
+    }
+
+    let _lamp_count = next_ies_value(&mut values)?;
+    let _lumens_per_lamp = next_ies_value(&mut values)?;
+    let _candela_multiplier = next_ies_value(&mut values)?;
+    let vertical_count = next_ies_count(&mut values)?;
+    let horizontal_count = next_ies_count(&mut values)?;
+    // photometric type, units, width, length, height, ballast factor,
+    // future use and input watts do not change the relative intensities
+    for _ in 0..8 {
+        next_ies_value(&mut values)?;
+    }
+
+    let mut vertical_angles = Vec::with_capacity(vertical_count);
+    for _ in 0..vertical_count {
+        vertical_angles.push(next_ies_value(&mut values)?);
+    }
+    let mut horizontal_angles = Vec::with_capacity(horizontal_count);
+    for _ in 0..horizontal_count {
+        horizontal_angles.push(next_ies_value(&mut values)?);
+    }
+    let mut intensities = Vec::with_capacity(horizontal_count);
+    for _ in 0..horizontal_count {
+        let mut row = Vec::with_capacity(vertical_count);
+        for _ in 0..vertical_count {
+            row.push(next_ies_value(&mut values)?);
+        }
+        intensities.push(row);
+    }
+    if vertical_count == 0 || horizontal_count == 0 {
+        return Err("empty candela table".to_string());
+    }
+
+    let max = intensities.iter().flatten().cloned().fold(0f64, f64::max);
+    if max > 0f64 {
+        for value in intensities.iter_mut().flatten() {
+            *value /= max;
+        }
+    }
+
+    Ok(IesProfile {
+        vertical_angles,
+        horizontal_angles,
+        intensities,
+        source: source.to_string(),
+        path: None,
+    })
+}
+
+type IesValues = std::vec::IntoIter<Result<f64, String>>;
+
+fn next_ies_value(values: &mut IesValues) -> Result<f64, String> {
+    values
+        .next()
+        .unwrap_or_else(|| Err("truncated file".to_string()))
+}
+
+/// A number of entries of the file, which cannot exceed the values left.
+fn next_ies_count(values: &mut IesValues) -> Result<usize, String> {
+    let count = next_ies_value(values)?;
+    if count.is_finite() && count >= 0f64 && count.fract() == 0f64 && count <= values.len() as f64 {
+        Ok(count as usize)
+    } else {
+        Err(format!("invalid count {}", count))
+    }
+}
+
+/// Opens a file a scene refers to, which browsers do not let us do.
+pub(crate) fn open_scene_file(path: &Path) -> Result<File, String> {
+    if cfg!(target_arch = "wasm32") {
+        return Err(format!(
+            "{}: files cannot be read in the browser, give their content inline",
+            path.display()
+        ));
+    }
+    File::open(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Reads the scene file `filename`, along with the files it refers to.
+pub fn parse_scene(filename: String) -> Result<Scene, String> {
+    let path = Path::new(&filename);
+    let mut serialized_scene = String::new();
+    open_scene_file(path)?
+        .read_to_string(&mut serialized_scene)
+        .map_err(|e| format!("{}: {}", filename, e))?;
+    deserialize_scene(&serialized_scene, path.parent())
+}
+
+/// Builds the scene described by `serialized_scene`. Relative paths to
+/// textures and light profiles are read from `directory`, or from the
+/// working directory without one.
+pub fn deserialize_scene(
+    serialized_scene: &str,
+    directory: Option<&Path>,
+) -> Result<Scene, String> {
+    let mut serde_scene =
+        serde_json::from_str::<SerdeScene>(serialized_scene).map_err(|e| e.to_string())?;
+    serde_scene.load_resources(directory.unwrap_or_else(|| Path::new("")))?;
+    // before the triangles of meshes get copies of their material, so that
+    // they keep sharing its textures
+    let color_space = serde_scene.color_space;
+    for object in serde_scene.objects.iter_mut() {
+        object
+            .material
+            .map_colors(&|color: &Color| color_space.to_linear(color));
+    }
+    let mut scene = Scene {
         objects: SceneObjects::initialize(deserialize_object(&serde_scene.objects)),
         ambient_light: serde_scene.ambient_light,
         lights: serde_scene.lights,
         camera: serde_scene.camera,
+    };
+    scene.decode_light_colors(color_space);
+    if let Some(samples) = serde_scene.emission_samples {
+        scene.objects.emitters.samples = samples;
     }
+    Ok(scene)
 }
 
 pub fn deserialize_object(serde_scene_objects: &Vec<SerdeSceneObject>) -> Vec<SceneObject> {
@@ -188,6 +332,26 @@ pub struct SerdeScene {
     pub ambient_light: AmbientLight,
     pub lights: Vec<Light>,
     pub camera: Camera,
+    /// Color space of every color in the file, sRGB unless told otherwise.
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    /// Samples taken over the emissive objects for each shaded point.
+    #[serde(default)]
+    pub emission_samples: Option<u32>,
+}
+
+impl SerdeScene {
+    /// Reads the textures and light profiles the scene refers to, their
+    /// paths being relative to `directory`.
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        for object in self.objects.iter_mut() {
+            object.material.load_resources(directory)?;
+        }
+        for light in self.lights.iter_mut() {
+            light.load_resources(directory)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -208,3 +372,65 @@ pub enum SerdeObject {
 pub struct SerdeMesh {
     pub obj: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene_json(texture_path: &str) -> String {
+        let white = serde_json::json!({"Color": {"red": 1, "green": 1, "blue": 1}});
+        serde_json::json!({
+            "objects": [{
+                "geometry": {"sphere": {"center": {"x": 0, "y": 0, "z": 5}, "radius": 1}},
+                "material": {
+                    "ambientColor": white,
+                    "ambientReflection": 0,
+                    "diffuseColor": {"Texture": {
+                        "path": texture_path,
+                        "scale": 1,
+                        "offset": {"x": 0, "y": 0},
+                    }},
+                    "diffuseReflection": 1,
+                    "specularColor": white,
+                    "specularReflection": 0,
+                    "shininess": 1,
+                    "reflectivity": 0,
+                    "transparency": 0,
+                    "indexOfRefraction": 1,
+                },
+            }],
+            "ambientLight": {"color": {"red": 1, "green": 1, "blue": 1}, "intensity": 0.1},
+            "lights": [],
+            "camera": {
+                "position": {"x": 0, "y": 0, "z": 0},
+                "direction": {"x": 0, "y": 0, "z": 1},
+                "upDirection": {"x": 0, "y": 1, "z": 0},
+                "fieldOfView": 1,
+                "xResolution": 4,
+                "yResolution": 4,
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn scene_files_read_textures_next_to_them() {
+        let directory = std::env::temp_dir().join(format!("scene-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("textures")).unwrap();
+        image::RgbImage::from_raw(1, 1, vec![255, 0, 0])
+            .unwrap()
+            .save(directory.join("textures").join("red.png"))
+            .unwrap();
+        let scene_path = directory.join("scene.json");
+        std::fs::write(&scene_path, scene_json("textures/red.png")).unwrap();
+
+        let scene = parse_scene(scene_path.to_str().unwrap().to_string());
+        let elsewhere = deserialize_scene(&scene_json("textures/red.png"), None);
+        let missing = parse_scene(directory.join("none.json").to_str().unwrap().to_string());
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(scene.unwrap().objects.objects.len(), 1);
+        assert!(elsewhere.err().unwrap().contains("red.png"));
+        assert!(missing.err().unwrap().contains("none.json"));
+    }
+}
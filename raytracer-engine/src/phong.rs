@@ -0,0 +1,353 @@
+use crate::color::{Color, BLACK, WHITE};
+use crate::engine::{cast_ray, is_in_shadow, Scene, SceneIntersection, TracedRay};
+use crate::geometry::{Object, Point2, Point3, Ray, Vector3};
+use crate::material::{
+    around, fresnel, light_samples, no_absorption, outside_index_of_refraction, refract,
+    trace_reflection, trace_refraction, BsdfSample, Coloration, Emission, MaterialModel, Scalar,
+    ShadingPoint, SurfaceDetail,
+};
+use crate::sampler::{cosine_hemisphere, Rng};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::path::Path;
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PhongMaterial {
+    pub ambient_color: Coloration,
+    pub ambient_reflection: Scalar,
+    pub diffuse_color: Coloration,
+    pub diffuse_reflection: Scalar,
+    pub specular_color: Coloration,
+    pub specular_reflection: Scalar,
+    pub shininess: Scalar,
+    pub reflectivity: Scalar,
+    pub transparency: Scalar,
+    pub index_of_refraction: Scalar,
+    #[serde(default = "no_absorption")]
+    pub absorption: Color,
+    #[serde(flatten)]
+    pub detail: SurfaceDetail,
+    #[serde(default)]
+    pub emission: Option<Emission>,
+}
+
+/// Phong shading with ideal mirror reflection and Fresnel weighted glass.
+/// The path tracer sees it as normalized Lambert and Phong lobes, plus the
+/// mirror and glass of `reflectivity` and `transparency`, which are Dirac
+/// deltas.
+impl MaterialModel for PhongMaterial {
+    fn type_tag(&self) -> &str {
+        "phong"
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap()
+    }
+
+    fn clone_box(&self) -> Box<dyn MaterialModel> {
+        Box::new(self.clone())
+    }
+
+    fn emission(&self) -> Option<&Emission> {
+        self.emission.as_ref()
+    }
+
+    fn index_of_refraction(&self, object: &Object, point: &Point3) -> f64 {
+        self.index_of_refraction.value(object, point, 0f64)
+    }
+
+    fn absorption(&self) -> Color {
+        self.absorption
+    }
+
+    fn surface_detail(&self) -> Option<&SurfaceDetail> {
+        Some(&self.detail)
+    }
+
+    fn has_non_delta_lobes(&self) -> bool {
+        let positive = |scalar: &Scalar| scalar.constant().is_none_or(|value| value > 0f64);
+        positive(&self.diffuse_reflection) || positive(&self.specular_reflection)
+    }
+
+    fn map_colors(&mut self, f: &dyn Fn(&Color) -> Color) {
+        self.ambient_color.map_colors(&f);
+        self.diffuse_color.map_colors(&f);
+        self.specular_color.map_colors(&f);
+        if let Some(emission) = &mut self.emission {
+            emission.color = f(&emission.color);
+        }
+    }
+
+    fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        for coloration in [
+            &mut self.ambient_color,
+            &mut self.diffuse_color,
+            &mut self.specular_color,
+        ] {
+            coloration.load_resources(directory)?;
+        }
+        for scalar in [
+            &mut self.ambient_reflection,
+            &mut self.diffuse_reflection,
+            &mut self.specular_reflection,
+            &mut self.shininess,
+            &mut self.reflectivity,
+            &mut self.transparency,
+            &mut self.index_of_refraction,
+        ] {
+            scalar.load_resources(directory)?;
+        }
+        self.detail.load_resources(directory)
+    }
+
+    fn render_color(
+        &self,
+        ray: &TracedRay,
+        intersection: &SceneIntersection,
+        scene: &Scene,
+        max_bounces: u8,
+    ) -> Color {
+        let point_precise = ray
+            .ray
+            .origin
+            .add(&ray.ray.direction.times(intersection.intersection.distance));
+        let geometric_normal = intersection.get_normal(&point_precise);
+        let point = point_precise.add(&geometric_normal.times(1e-6));
+        let footprint = ray.footprint(intersection.intersection.distance, &geometric_normal);
+        let normal = self.detail.apply(
+            &intersection.object.geometry,
+            &point_precise,
+            &geometric_normal,
+            footprint,
+        );
+        let value = |scalar: &Scalar| {
+            scalar.value(&intersection.object.geometry, &point_precise, footprint)
+        };
+        let shininess = value(&self.shininess);
+        let reflectivity = value(&self.reflectivity);
+        let transparency = value(&self.transparency);
+
+        let mut rendered_color = &(&scene.ambient_light.color
+            * &(&self.ambient_color.color(
+                &intersection.object.geometry,
+                &point_precise,
+                footprint,
+            ) * value(&self.ambient_reflection)))
+            * scene.ambient_light.intensity;
+        if let Some(emission) = &self.emission {
+            rendered_color = &rendered_color + &emission.radiance();
+        }
+        let to_eye = ray.ray.direction.times(-1f64);
+        let diffuse_color =
+            self.diffuse_color
+                .color(&intersection.object.geometry, &point_precise, footprint);
+        let specular_color =
+            self.specular_color
+                .color(&intersection.object.geometry, &point_precise, footprint);
+        for (light_sample, color) in light_samples(scene, &point) {
+            if is_in_shadow(&point, &light_sample, scene) {
+                continue;
+            }
+
+            let to_light = &light_sample.direction.times(-1f64);
+            let light_normal_reflection = to_light.symmetry(&normal);
+            let diffuse = value(&self.diffuse_reflection) * normal.dot(to_light).max(0f64);
+
+            let specular = value(&self.specular_reflection)
+                * light_normal_reflection
+                    .dot(&to_eye)
+                    .max(0f64)
+                    .powf(shininess);
+
+            let light_color = &color * light_sample.intensity;
+            rendered_color = &rendered_color + &(diffuse * &(&light_color * &diffuse_color));
+            rendered_color = &rendered_color + &(specular * &(&light_color * &specular_color));
+        }
+
+        if reflectivity > 1e-6 && max_bounces > 0 {
+            let reflected_ray = Ray {
+                origin: point,
+                direction: ray.ray.direction.times(-1f64).symmetry(&normal),
+            };
+            let reflected_traced_ray = TracedRay {
+                ray: reflected_ray,
+                inside_objects: ray.inside_objects.clone(),
+                cone: ray.cone.bounced(intersection.intersection.distance, 0f64),
+            };
+            let reflected_color = cast_ray(scene, &reflected_traced_ray, max_bounces - 1);
+            rendered_color = &rendered_color + &(reflectivity * &reflected_color);
+        }
+        if transparency > 1e-6 && max_bounces > 0 {
+            let index_of_refraction = value(&self.index_of_refraction);
+            let outside_index_of_refraction = outside_index_of_refraction(
+                scene,
+                &ray.inside_objects,
+                intersection.object.id,
+                &point_precise,
+            );
+
+            let kr = fresnel(
+                &ray.ray.direction,
+                &normal,
+                index_of_refraction,
+                outside_index_of_refraction,
+            );
+
+            let mut refracted_color = BLACK;
+            if kr < 1f64 {
+                refracted_color = trace_refraction(
+                    ray,
+                    intersection,
+                    scene,
+                    max_bounces,
+                    index_of_refraction,
+                    outside_index_of_refraction,
+                );
+            }
+            let reflected_color = trace_reflection(ray, intersection, scene, max_bounces);
+
+            rendered_color = &rendered_color
+                + &(transparency * &(&(&reflected_color * kr) + &(&refracted_color * (1f64 - kr))));
+        }
+        rendered_color
+    }
+
+    fn evaluate(&self, shading: &ShadingPoint, to_light: &Vector3) -> Color {
+        let normal = shading.facing_normal();
+        if normal.dot(to_light) <= 0f64 {
+            return BLACK;
+        }
+        let diffuse_color =
+            self.diffuse_color
+                .color(shading.geometry, &shading.point, shading.footprint);
+        let specular_color =
+            self.specular_color
+                .color(shading.geometry, &shading.point, shading.footprint);
+        let alignment = to_light.symmetry(&normal).dot(&shading.to_eye).max(0f64);
+        let shininess = shading.value(&self.shininess);
+        let diffuse = shading.value(&self.diffuse_reflection) / PI;
+        let specular = shading.value(&self.specular_reflection) * (shininess + 2f64) / (2f64 * PI)
+            * alignment.powf(shininess);
+        &(diffuse * &diffuse_color) + &(specular * &specular_color)
+    }
+
+    fn pdf(&self, shading: &ShadingPoint, to_light: &Vector3) -> f64 {
+        let normal = shading.facing_normal();
+        let cosine = normal.dot(to_light);
+        let weights = self.lobe_weights(shading);
+        let total: f64 = weights.iter().sum();
+        if cosine <= 0f64 || total <= 0f64 {
+            return 0f64;
+        }
+        let alignment = to_light.symmetry(&normal).dot(&shading.to_eye).max(0f64);
+        let shininess = shading.value(&self.shininess);
+        let diffuse_pdf = cosine / PI;
+        let glossy_pdf = (shininess + 1f64) / (2f64 * PI) * alignment.powf(shininess);
+        (weights[0] * diffuse_pdf + weights[1] * glossy_pdf) / total
+    }
+
+    /// Picks the lobe proportionally to the lobe weights.
+    fn sample(&self, shading: &ShadingPoint, rng: &mut Rng) -> Option<BsdfSample> {
+        let weights = self.lobe_weights(shading);
+        let total: f64 = weights.iter().sum();
+        if total <= 0f64 {
+            return None;
+        }
+        let mut choice = rng.next_f64() * total;
+        let mut lobe = 0;
+        while lobe < 3 && choice >= weights[lobe] {
+            choice -= weights[lobe];
+            lobe += 1;
+        }
+        let sample = Point2 {
+            x: rng.next_f64(),
+            y: rng.next_f64(),
+        };
+        let normal = shading.facing_normal();
+        let mirrored = shading.to_eye.symmetry(&normal);
+
+        let direction = match lobe {
+            0 => around(&normal, &cosine_hemisphere(&sample)),
+            1 => {
+                let cosine = sample
+                    .x
+                    .powf(1f64 / (shading.value(&self.shininess) + 1f64));
+                let sine = (1f64 - cosine * cosine).max(0f64).sqrt();
+                let angle = 2f64 * PI * sample.y;
+                let local = Vector3 {
+                    x: sine * angle.cos(),
+                    y: sine * angle.sin(),
+                    z: cosine,
+                };
+                around(&mirrored, &local)
+            }
+            2 => {
+                return Some(BsdfSample {
+                    direction: mirrored,
+                    weight: &WHITE * total,
+                    pdf: 0f64,
+                    delta: true,
+                })
+            }
+            _ => {
+                let incident = shading.to_eye.times(-1f64);
+                let index_of_refraction = shading.value(&self.index_of_refraction);
+                let kr = fresnel(
+                    &incident,
+                    &shading.normal,
+                    index_of_refraction,
+                    shading.outside_index_of_refraction,
+                );
+                let refracted = refract(
+                    &incident,
+                    &shading.normal,
+                    index_of_refraction,
+                    shading.outside_index_of_refraction,
+                );
+                let reflects = rng.next_f64() < kr || refracted.norm() < 1e-9;
+                return Some(BsdfSample {
+                    direction: if reflects { mirrored } else { refracted },
+                    weight: &WHITE * total,
+                    pdf: 0f64,
+                    delta: true,
+                });
+            }
+        };
+
+        let cosine = normal.dot(&direction);
+        if cosine <= 0f64 {
+            return None;
+        }
+        let pdf = self.pdf(shading, &direction);
+        if pdf <= 0f64 {
+            return None;
+        }
+        Some(BsdfSample {
+            weight: &self.evaluate(shading, &direction) * (cosine / pdf),
+            direction,
+            pdf,
+            delta: false,
+        })
+    }
+}
+
+impl PhongMaterial {
+    // Relative chances of sampling the diffuse, glossy, mirror and glass lobes.
+    fn lobe_weights(&self, shading: &ShadingPoint) -> [f64; 4] {
+        let diffuse = self
+            .diffuse_color
+            .color(shading.geometry, &shading.point, shading.footprint)
+            .luminance();
+        let specular = self
+            .specular_color
+            .color(shading.geometry, &shading.point, shading.footprint)
+            .luminance();
+        [
+            (shading.value(&self.diffuse_reflection) * diffuse).max(0f64),
+            (shading.value(&self.specular_reflection) * specular).max(0f64),
+            shading.value(&self.reflectivity).max(0f64),
+            shading.value(&self.transparency).max(0f64),
+        ]
+    }
+}
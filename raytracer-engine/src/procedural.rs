@@ -0,0 +1,428 @@
+use crate::color::Color;
+use crate::geometry::{Object, Point3};
+use crate::sampler::Rng;
+use crate::texture::{Texturable, UvMap};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// Pattern computed where it is looked up rather than read from pixels,
+/// going from the first of `colors` at 0 to the second at 1. `scale` is the
+/// size of its features, in the units of `space`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Procedural {
+    pub pattern: Pattern,
+    pub colors: [Color; 2],
+    #[serde(default = "unit_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub space: PatternSpace,
+    /// Picks another variation of the random patterns.
+    #[serde(default)]
+    pub seed: u64,
+}
+
+fn unit_scale() -> f64 {
+    1f64
+}
+
+/// Coordinates patterns are laid out in.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub enum PatternSpace {
+    /// Scene coordinates: objects look carved out of the pattern.
+    #[default]
+    World,
+    /// Texture coordinates of the object, as image textures use them, in
+    /// the plane z = 0.
+    Uv,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Pattern {
+    /// Alternating unit cubes.
+    Checker,
+    /// Alternating unit slabs across the x axis.
+    Stripes,
+    /// Improved Perlin gradient noise.
+    Perlin,
+    /// Gradient noise on a grid of tetrahedra, with less of the axis
+    /// aligned look of `Perlin`.
+    Simplex,
+    /// Fractal Brownian motion: layers of Perlin noise, finer and fainter
+    /// with each octave.
+    Fbm(Octaves),
+    /// Like `Fbm`, adding up the absolute values of the layers, which gives
+    /// billowy noise creased where it crosses zero.
+    Turbulence(Octaves),
+    /// Distance to the closest of points scattered one per unit cube,
+    /// giving cells.
+    Worley,
+    /// Veins one unit apart across the x axis, bent by turbulence.
+    Marble { distortion: f64 },
+    /// Rings one unit apart around the y axis, bent by fBm.
+    Wood { distortion: f64 },
+}
+
+/// Layers of noise summed by fractal patterns, each `lacunarity` times finer
+/// and `gain` times fainter than the previous one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Octaves {
+    #[serde(default = "default_octaves")]
+    pub octaves: u8,
+    #[serde(default = "default_lacunarity")]
+    pub lacunarity: f64,
+    #[serde(default = "default_gain")]
+    pub gain: f64,
+}
+
+fn default_octaves() -> u8 {
+    5
+}
+
+fn default_lacunarity() -> f64 {
+    2f64
+}
+
+fn default_gain() -> f64 {
+    0.5f64
+}
+
+impl Default for Octaves {
+    fn default() -> Self {
+        Octaves {
+            octaves: default_octaves(),
+            lacunarity: default_lacunarity(),
+            gain: default_gain(),
+        }
+    }
+}
+
+impl Procedural {
+    pub fn color(&self, object: &Object, point: &Point3) -> Color {
+        self.mapped_color(object, point, &|coordinate| coordinate)
+    }
+
+    /// Same as `color`, with the texture coordinates of the object
+    /// transformed by `uv_map` when the pattern is laid out in them.
+    pub fn mapped_color(&self, object: &Object, point: &Point3, uv_map: &UvMap) -> Color {
+        let [low, high] = &self.colors;
+        low.mix(high, self.value(object, point, uv_map))
+    }
+
+    /// The pattern at `point`, in [0, 1].
+    fn value(&self, object: &Object, point: &Point3, uv_map: &UvMap) -> f64 {
+        let point = match self.space {
+            PatternSpace::World => *point,
+            PatternSpace::Uv => {
+                let coordinate = uv_map(object.get_2d_coordinate(point));
+                Point3 {
+                    x: coordinate.x,
+                    y: coordinate.y,
+                    z: 0f64,
+                }
+            }
+        };
+        let point = Point3 {
+            x: point.x / self.scale,
+            y: point.y / self.scale,
+            z: point.z / self.scale,
+        };
+        self.pattern.value(&point, self.seed).clamp(0f64, 1f64)
+    }
+
+    pub fn map_colors<F>(&mut self, f: &F)
+    where
+        F: Fn(&Color) -> Color,
+    {
+        for color in self.colors.iter_mut() {
+            *color = f(color);
+        }
+    }
+}
+
+impl Pattern {
+    fn value(&self, point: &Point3, seed: u64) -> f64 {
+        match self {
+            Pattern::Checker => {
+                let sum = point.x.floor() + point.y.floor() + point.z.floor();
+                (sum as i64).rem_euclid(2) as f64
+            }
+            Pattern::Stripes => (point.x.floor() as i64).rem_euclid(2) as f64,
+            Pattern::Perlin => 0.5f64 + 0.5f64 * perlin(point, seed),
+            Pattern::Simplex => 0.5f64 + 0.5f64 * simplex(point, seed),
+            Pattern::Fbm(octaves) => 0.5f64 + 0.5f64 * octaves.sum(point, seed, |noise| noise),
+            Pattern::Turbulence(octaves) => octaves.sum(point, seed, f64::abs),
+            Pattern::Worley => worley(point, seed),
+            Pattern::Marble { distortion } => {
+                let turbulence = Octaves::default().sum(point, seed, f64::abs);
+                0.5f64 + 0.5f64 * (2f64 * PI * (point.x + distortion * turbulence)).sin()
+            }
+            Pattern::Wood { distortion } => {
+                let fbm = Octaves::default().sum(point, seed, |noise| noise);
+                let radius = point.x.hypot(point.z) + distortion * fbm;
+                radius - radius.floor()
+            }
+        }
+    }
+}
+
+impl Octaves {
+    // Weighted average of `shape` applied to the layers of Perlin noise.
+    fn sum<F>(&self, point: &Point3, seed: u64, shape: F) -> f64
+    where
+        F: Fn(f64) -> f64,
+    {
+        let mut sum = 0f64;
+        let mut total_amplitude = 0f64;
+        let mut amplitude = 1f64;
+        let mut frequency = 1f64;
+        for octave in 0..self.octaves {
+            let layer = Point3 {
+                x: point.x * frequency,
+                y: point.y * frequency,
+                z: point.z * frequency,
+            };
+            sum += amplitude * shape(perlin(&layer, seed.wrapping_add(octave as u64)));
+            total_amplitude += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        if total_amplitude > 0f64 {
+            sum / total_amplitude
+        } else {
+            0f64
+        }
+    }
+}
+
+// Random bits attached to a corner of the unit grid.
+fn lattice(x: i64, y: i64, z: i64, seed: u64) -> u64 {
+    Rng::new(
+        (x as u64).wrapping_mul(0x8DA6_B343)
+            ^ (y as u64).wrapping_mul(0xD816_3841)
+            ^ (z as u64).wrapping_mul(0xCB1A_B31F)
+            ^ seed.rotate_left(32),
+    )
+    .next_u64()
+}
+
+// Dot product of `(x, y, z)` with one of the 12 directions from the center
+// of a cube to its edges, picked by `hash`.
+fn gradient(hash: u64, x: f64, y: f64, z: f64) -> f64 {
+    match hash % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+fn lerp(amount: f64, from: f64, to: f64) -> f64 {
+    from + amount * (to - from)
+}
+
+/// Perlin's improved noise, in about [-1, 1].
+fn perlin(point: &Point3, seed: u64) -> f64 {
+    let (x, y, z) = (point.x.floor(), point.y.floor(), point.z.floor());
+    let (dx, dy, dz) = (point.x - x, point.y - y, point.z - z);
+    let fade = |t: f64| t * t * t * (t * (t * 6f64 - 15f64) + 10f64);
+    let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+    let corner = |i: i64, j: i64, k: i64| {
+        let hash = lattice(x as i64 + i, y as i64 + j, z as i64 + k, seed);
+        gradient(hash, dx - i as f64, dy - j as f64, dz - k as f64)
+    };
+    let layer = |k: i64| {
+        lerp(
+            v,
+            lerp(u, corner(0, 0, k), corner(1, 0, k)),
+            lerp(u, corner(0, 1, k), corner(1, 1, k)),
+        )
+    };
+    lerp(w, layer(0), layer(1))
+}
+
+/// Gustavson's 3D simplex noise, in about [-1, 1].
+fn simplex(point: &Point3, seed: u64) -> f64 {
+    const SKEW: f64 = 1f64 / 3f64;
+    const UNSKEW: f64 = 1f64 / 6f64;
+    let skew = (point.x + point.y + point.z) * SKEW;
+    let cell = [
+        (point.x + skew).floor(),
+        (point.y + skew).floor(),
+        (point.z + skew).floor(),
+    ];
+    let unskew = (cell[0] + cell[1] + cell[2]) * UNSKEW;
+    let first = [
+        point.x - cell[0] + unskew,
+        point.y - cell[1] + unskew,
+        point.z - cell[2] + unskew,
+    ];
+    // the tetrahedron containing the point is reached from the cell origin
+    // by stepping along the axes in decreasing order of the offsets
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| first[b].total_cmp(&first[a]));
+
+    let mut step = [0i64; 3];
+    let mut sum = 0f64;
+    for corner in 0..4 {
+        if corner > 0 {
+            step[order[corner - 1]] += 1;
+        }
+        let offset: [f64; 3] =
+            [0, 1, 2].map(|axis| first[axis] - step[axis] as f64 + corner as f64 * UNSKEW);
+        let falloff = 0.6f64 - offset.iter().map(|d| d * d).sum::<f64>();
+        if falloff > 0f64 {
+            let hash = lattice(
+                cell[0] as i64 + step[0],
+                cell[1] as i64 + step[1],
+                cell[2] as i64 + step[2],
+                seed,
+            );
+            sum += falloff.powi(4) * gradient(hash, offset[0], offset[1], offset[2]);
+        }
+    }
+    32f64 * sum
+}
+
+/// Distance to the closest feature point, one being scattered in each unit
+/// cube.
+fn worley(point: &Point3, seed: u64) -> f64 {
+    let (x, y, z) = (
+        point.x.floor() as i64,
+        point.y.floor() as i64,
+        point.z.floor() as i64,
+    );
+    let mut closest = f64::INFINITY;
+    for i in x - 1..=x + 1 {
+        for j in y - 1..=y + 1 {
+            for k in z - 1..=z + 1 {
+                let mut rng = Rng::new(lattice(i, j, k, seed));
+                let feature = Point3 {
+                    x: i as f64 + rng.next_f64(),
+                    y: j as f64 + rng.next_f64(),
+                    z: k as f64 + rng.next_f64(),
+                };
+                closest = closest.min((&feature - point).norm());
+            }
+        }
+    }
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Sphere, Vector3};
+
+    fn patterns() -> Vec<Pattern> {
+        vec![
+            Pattern::Checker,
+            Pattern::Stripes,
+            Pattern::Perlin,
+            Pattern::Simplex,
+            Pattern::Fbm(Octaves::default()),
+            Pattern::Turbulence(Octaves::default()),
+            Pattern::Worley,
+            Pattern::Marble { distortion: 4f64 },
+            Pattern::Wood { distortion: 0.3f64 },
+        ]
+    }
+
+    fn random_point(rng: &mut Rng) -> Point3 {
+        Point3 {
+            x: 20f64 * rng.next_f64() - 10f64,
+            y: 20f64 * rng.next_f64() - 10f64,
+            z: 20f64 * rng.next_f64() - 10f64,
+        }
+    }
+
+    #[test]
+    fn patterns_vary_within_the_unit_interval() {
+        let mut rng = Rng::new(0);
+        for pattern in patterns() {
+            let values: Vec<f64> = (0..2000)
+                .map(|_| pattern.value(&random_point(&mut rng), 0))
+                .collect();
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            assert!(min >= -0.05f64 && max <= 1.05f64, "{:?}", pattern);
+            assert!(max - min > 0.4f64, "{:?}: {} {}", pattern, min, max);
+        }
+    }
+
+    #[test]
+    fn noise_is_continuous_and_vanishes_on_the_grid() {
+        let mut rng = Rng::new(1);
+        for _ in 0..200 {
+            let point = random_point(&mut rng);
+            let nearby = point.add(&Vector3 {
+                x: 1e-6f64,
+                y: -1e-6f64,
+                z: 1e-6f64,
+            });
+            assert!((perlin(&point, 3) - perlin(&nearby, 3)).abs() < 1e-4f64);
+            assert!((simplex(&point, 3) - simplex(&nearby, 3)).abs() < 1e-4f64);
+            assert!((worley(&point, 3) - worley(&nearby, 3)).abs() < 1e-4f64);
+
+            let corner = Point3 {
+                x: point.x.floor(),
+                y: point.y.floor(),
+                z: point.z.floor(),
+            };
+            assert!(perlin(&corner, 3).abs() < 1e-12f64);
+        }
+        let point = random_point(&mut rng);
+        assert_ne!(perlin(&point, 3), perlin(&point, 4));
+    }
+
+    #[test]
+    fn scene_files_give_pattern_parameters() {
+        let procedural: Procedural = serde_json::from_value(serde_json::json!({
+            "pattern": {"Fbm": {"octaves": 3}},
+            "colors": [
+                {"red": 0, "green": 0, "blue": 0},
+                {"red": 1, "green": 0.5, "blue": 0},
+            ],
+            "scale": 2,
+            "space": "Uv",
+        }))
+        .unwrap();
+        match &procedural.pattern {
+            Pattern::Fbm(octaves) => {
+                assert_eq!(octaves.octaves, 3);
+                assert_eq!(octaves.gain, 0.5f64);
+            }
+            pattern => panic!("{:?}", pattern),
+        }
+
+        let sphere = Object::Sphere(Sphere {
+            center: Point3 {
+                x: 0f64,
+                y: 0f64,
+                z: 0f64,
+            },
+            radius: 1f64,
+        });
+        let point = Point3 {
+            x: 0.6f64,
+            y: 0.8f64,
+            z: 0f64,
+        };
+        let color = procedural.color(&sphere, &point);
+        assert!((color.green - 0.5f64 * color.red).abs() < 1e-12f64);
+        let scaled_up = Procedural {
+            scale: 4f64,
+            ..procedural.clone()
+        };
+        assert_ne!(scaled_up.color(&sphere, &point), color);
+    }
+}
@@ -1,29 +1,35 @@
 use crate::color::Color;
+use crate::display::DisplaySettings;
 use crate::engine::render;
 use crate::engine::Scene;
 
 #[allow(unused)]
 pub fn render_scene_file(scene: &Scene) -> Result<(), String> {
     let screen = render(scene);
-    let mut imgbuf: image::RgbImage = image::ImageBuffer::new(
-        scene.camera.x_resolution as u32,
-        scene.camera.y_resolution as u32,
-    );
+    let imgbuf: image::RgbImage = image::ImageBuffer::from_raw(
+        screen.width() as u32,
+        screen.height() as u32,
+        screen.to_rgb8(),
+    )
+    .unwrap();
+    imgbuf.save("output.png").unwrap();
 
-    for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-        *pixel = screen[&(x as u16, y as u16)].into();
+    if scene.camera.sampling.adaptive.is_some() {
+        let heatmap = screen.sample_count_heatmap();
+        let samples_imgbuf: image::RgbImage = image::ImageBuffer::from_raw(
+            heatmap.width() as u32,
+            heatmap.height() as u32,
+            heatmap.to_rgb8(),
+        )
+        .unwrap();
+        samples_imgbuf.save("output_samples.png").unwrap();
     }
-    imgbuf.save("output.png").unwrap();
 
     Ok(())
 }
 
 impl From<Color> for image::Rgb<u8> {
     fn from(item: Color) -> Self {
-        image::Rgb([
-            ((item.red as f32) * 255.0) as u8,
-            ((item.green as f32) * 255.0) as u8,
-            ((item.blue as f32) * 255.0) as u8,
-        ])
+        image::Rgb(DisplaySettings::default().to_rgb8(&item, 0, 0))
     }
 }
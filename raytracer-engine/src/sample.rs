@@ -1,10 +1,13 @@
 use crate::camera::*;
 use crate::color::*;
+use crate::display::*;
 use crate::engine::*;
 use crate::geometry::*;
+use crate::integrator::*;
 use crate::light::*;
 use crate::material::*;
 use crate::parser::*;
+use crate::sampler::*;
 use crate::texture::*;
 use std::sync::Arc;
 
@@ -19,26 +22,29 @@ pub fn get_simple_mesh() -> Scene {
         objects.push(SceneObject {
             id: id,
             geometry: Object::MeshTriangle(triangle),
-            material: Material {
+            material: Material::new(PhongMaterial {
                 ambient_color: Coloration::Color(Color {
                     red: 1f64,
                     green: 0f64,
                     blue: 0f64,
                 }),
-                ambient_reflection: 0.3f64,
+                ambient_reflection: Scalar::Value(0.3f64),
                 diffuse_color: Coloration::Color(Color {
                     red: 0f64,
                     green: 0f64,
                     blue: 1f64,
                 }),
-                diffuse_reflection: 0.7f64,
+                diffuse_reflection: Scalar::Value(0.7f64),
                 specular_color: Coloration::Color(BLACK),
-                specular_reflection: 0f64,
-                shininess: 0f64,
-                reflectivity: 0f64,
-                transparency: 0f64,
-                index_of_refraction: 0f64,
-            },
+                specular_reflection: Scalar::Value(0f64),
+                shininess: Scalar::Value(0f64),
+                reflectivity: Scalar::Value(0f64),
+                transparency: Scalar::Value(0f64),
+                index_of_refraction: Scalar::Value(0f64),
+                absorption: BLACK,
+                detail: SurfaceDetail::default(),
+                emission: None,
+            }),
         });
         id += 1;
     }
@@ -52,26 +58,29 @@ pub fn get_simple_mesh() -> Scene {
             },
             radius: 70f64,
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(Color {
                 red: 1f64,
                 green: 0f64,
                 blue: 0f64,
             }),
-            ambient_reflection: 0.3f64,
+            ambient_reflection: Scalar::Value(0.3f64),
             diffuse_color: Coloration::Color(Color {
                 red: 0f64,
                 green: 0f64,
                 blue: 1f64,
             }),
-            diffuse_reflection: 0.7f64,
+            diffuse_reflection: Scalar::Value(0.7f64),
             specular_color: Coloration::Color(BLACK),
-            specular_reflection: 0f64,
-            shininess: 0f64,
-            reflectivity: 0f64,
-            transparency: 0f64,
-            index_of_refraction: 0f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(0f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(0f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     id += 1;
     objects.push(SceneObject {
@@ -84,26 +93,29 @@ pub fn get_simple_mesh() -> Scene {
             },
             radius: 70f64,
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(Color {
                 red: 1f64,
                 green: 0f64,
                 blue: 0f64,
             }),
-            ambient_reflection: 0.3f64,
+            ambient_reflection: Scalar::Value(0.3f64),
             diffuse_color: Coloration::Color(Color {
                 red: 0f64,
                 green: 0f64,
                 blue: 1f64,
             }),
-            diffuse_reflection: 0.7f64,
+            diffuse_reflection: Scalar::Value(0.7f64),
             specular_color: Coloration::Color(BLACK),
-            specular_reflection: 0f64,
-            shininess: 0f64,
-            reflectivity: 0f64,
-            transparency: 0f64,
-            index_of_refraction: 0f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(0f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(0f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
 
     let mut lights: Vec<Light> = vec![];
@@ -151,14 +163,21 @@ pub fn get_simple_mesh() -> Scene {
         field_of_view: PI / 2f64,
         x_resolution: 200u16,
         y_resolution: 200u16,
+        sampling: Sampling::default(),
+        lens: None,
+        projection: Projection::Perspective,
+        display: DisplaySettings::default(),
+        integrator: Integrator::Whitted,
     };
 
-    Scene {
+    let mut scene = Scene {
         objects: SceneObjects::initialize(objects),
         lights: lights,
         ambient_light: ambient_light,
         camera: standard_camera,
-    }
+    };
+    scene.decode_colors(ColorSpace::Srgb);
+    scene
 }
 
 #[allow(dead_code)]
@@ -170,26 +189,29 @@ pub fn get_mesh() -> Scene {
         objects.push(SceneObject {
             id: id,
             geometry: Object::MeshTriangle(triangle),
-            material: Material {
+            material: Material::new(PhongMaterial {
                 ambient_color: Coloration::Color(Color {
                     red: 1f64,
                     green: 0f64,
                     blue: 0f64,
                 }),
-                ambient_reflection: 0.3f64,
+                ambient_reflection: Scalar::Value(0.3f64),
                 diffuse_color: Coloration::Color(Color {
                     red: 0f64,
                     green: 0f64,
                     blue: 1f64,
                 }),
-                diffuse_reflection: 0.7f64,
+                diffuse_reflection: Scalar::Value(0.7f64),
                 specular_color: Coloration::Color(BLACK),
-                specular_reflection: 0f64,
-                shininess: 0f64,
-                reflectivity: 0f64,
-                transparency: 0f64,
-                index_of_refraction: 0f64,
-            },
+                specular_reflection: Scalar::Value(0f64),
+                shininess: Scalar::Value(0f64),
+                reflectivity: Scalar::Value(0f64),
+                transparency: Scalar::Value(0f64),
+                index_of_refraction: Scalar::Value(0f64),
+                absorption: BLACK,
+                detail: SurfaceDetail::default(),
+                emission: None,
+            }),
         });
         id += 1;
     }
@@ -239,14 +261,21 @@ pub fn get_mesh() -> Scene {
         field_of_view: PI / 4f64,
         x_resolution: 480u16,
         y_resolution: 260u16,
+        sampling: Sampling::default(),
+        lens: None,
+        projection: Projection::Perspective,
+        display: DisplaySettings::default(),
+        integrator: Integrator::Whitted,
     };
 
-    Scene {
+    let mut scene = Scene {
         objects: SceneObjects::initialize(objects),
         lights: lights,
         ambient_light: ambient_light,
         camera: standard_camera,
-    }
+    };
+    scene.decode_colors(ColorSpace::Srgb);
+    scene
 }
 
 #[allow(dead_code)]
@@ -262,18 +291,25 @@ pub fn get_transparent_sphere_in_sphere() -> Scene {
             },
             radius: 4f64,
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(BLACK),
-            ambient_reflection: 0f64,
+            ambient_reflection: Scalar::Value(0f64),
             diffuse_color: Coloration::Color(BLACK),
-            diffuse_reflection: 0f64,
+            diffuse_reflection: Scalar::Value(0f64),
             specular_color: Coloration::Color(BLACK),
-            specular_reflection: 0f64,
-            shininess: 0f64,
-            reflectivity: 0f64,
-            transparency: 1f64,
-            index_of_refraction: 1.33f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(0f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(1f64),
+            index_of_refraction: Scalar::Value(1.33f64),
+            absorption: Color {
+                red: 0.12f64,
+                green: 0.02f64,
+                blue: 0.06f64,
+            },
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     objects.push(SceneObject {
         id: 1,
@@ -285,18 +321,21 @@ pub fn get_transparent_sphere_in_sphere() -> Scene {
             },
             radius: 2f64,
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(BLACK),
-            ambient_reflection: 0f64,
+            ambient_reflection: Scalar::Value(0f64),
             diffuse_color: Coloration::Color(BLACK),
-            diffuse_reflection: 0f64,
+            diffuse_reflection: Scalar::Value(0f64),
             specular_color: Coloration::Color(BLACK),
-            specular_reflection: 0f64,
-            shininess: 0f64,
-            reflectivity: 0f64,
-            transparency: 1f64,
-            index_of_refraction: 0.95f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(0f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(1f64),
+            index_of_refraction: Scalar::Value(0.95f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     objects.push(SceneObject {
         id: 2,
@@ -313,22 +352,30 @@ pub fn get_transparent_sphere_in_sphere() -> Scene {
             }
             .normalize(),
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Texture(Texture {
-                pixels: get_checkboard(),
+                pixels: Arc::new(get_checkboard()),
+                path: None,
+                color_space: None,
                 scale: 5f64,
                 offset: POINT2_ORIGIN,
+                wrap: Wrap::Repeat,
+                filter: TextureFilter::Nearest,
+                mipmaps: Arc::default(),
             }),
-            ambient_reflection: 1f64,
+            ambient_reflection: Scalar::Value(1f64),
             diffuse_color: Coloration::Color(BLACK),
-            diffuse_reflection: 0f64,
+            diffuse_reflection: Scalar::Value(0f64),
             specular_color: Coloration::Color(BLACK),
-            specular_reflection: 0f64,
-            shininess: 0f64,
-            reflectivity: 0f64,
-            transparency: 1f64,
-            index_of_refraction: 1.33f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(0f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(1f64),
+            index_of_refraction: Scalar::Value(1.33f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     let lights: Vec<Light> = vec![];
 
@@ -360,14 +407,21 @@ pub fn get_transparent_sphere_in_sphere() -> Scene {
         field_of_view: PI / 5f64,
         x_resolution: 300u16,
         y_resolution: 300u16,
+        sampling: Sampling::default(),
+        lens: None,
+        projection: Projection::Perspective,
+        display: DisplaySettings::default(),
+        integrator: Integrator::Whitted,
     };
 
-    Scene {
+    let mut scene = Scene {
         objects: SceneObjects::initialize(objects),
         lights: lights,
         ambient_light: ambient_light,
         camera: standard_camera,
-    }
+    };
+    scene.decode_colors(ColorSpace::Srgb);
+    scene
 }
 
 #[allow(dead_code)]
@@ -383,30 +437,33 @@ pub fn get_spheres_with_plane() -> Scene {
             },
             radius: 5f64,
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(Color {
                 red: 0.1f64,
                 green: 1f64,
                 blue: 0.1f64,
             }),
-            ambient_reflection: 1f64,
+            ambient_reflection: Scalar::Value(1f64),
             diffuse_color: Coloration::Color(Color {
                 red: 0.1f64,
                 green: 0.8f64,
                 blue: 0.1f64,
             }),
-            diffuse_reflection: 0.5f64,
+            diffuse_reflection: Scalar::Value(0.5f64),
             specular_color: Coloration::Color(Color {
                 red: 1f64,
                 green: 1f64,
                 blue: 1f64,
             }),
-            specular_reflection: 0.4f64,
-            shininess: 40f64,
-            reflectivity: 0.3f64,
-            transparency: 0f64,
-            index_of_refraction: 0f64,
-        },
+            specular_reflection: Scalar::Value(0.4f64),
+            shininess: Scalar::Value(40f64),
+            reflectivity: Scalar::Value(0.3f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(0f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     objects.push(SceneObject {
         id: 1,
@@ -423,30 +480,33 @@ pub fn get_spheres_with_plane() -> Scene {
             }
             .normalize(),
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(Color {
                 red: 0.8f64,
                 green: 1f64,
                 blue: 0.8f64,
             }),
-            ambient_reflection: 0.1f64,
+            ambient_reflection: Scalar::Value(0.1f64),
             diffuse_color: Coloration::Color(Color {
                 red: 1f64,
                 green: 1f64,
                 blue: 1f64,
             }),
-            diffuse_reflection: 0.4f64,
+            diffuse_reflection: Scalar::Value(0.4f64),
             specular_color: Coloration::Color(Color {
                 red: 0f64,
                 green: 0f64,
                 blue: 0f64,
             }),
-            specular_reflection: 0f64,
-            shininess: 1f64,
-            reflectivity: 0.2f64,
-            transparency: 0f64,
-            index_of_refraction: 0f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(1f64),
+            reflectivity: Scalar::Value(0.2f64),
+            transparency: Scalar::Value(0f64),
+            index_of_refraction: Scalar::Value(0f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     objects.push(SceneObject {
         id: 2,
@@ -458,27 +518,30 @@ pub fn get_spheres_with_plane() -> Scene {
             },
             radius: 2f64,
         }),
-        material: Material {
+        material: Material::new(PhongMaterial {
             ambient_color: Coloration::Color(BLACK),
-            ambient_reflection: 0f64,
+            ambient_reflection: Scalar::Value(0f64),
             diffuse_color: Coloration::Color(Color {
                 red: 1f64,
                 green: 0.1f64,
                 blue: 0.1f64,
             }),
-            diffuse_reflection: 0.5f64 / PI,
+            diffuse_reflection: Scalar::Value(0.5f64 / PI),
             specular_color: Coloration::Color(BLACK),
-            specular_reflection: 0f64,
-            shininess: 1f64,
-            reflectivity: 0f64,
-            transparency: 0.9f64,
-            index_of_refraction: 1.33f64,
-        },
+            specular_reflection: Scalar::Value(0f64),
+            shininess: Scalar::Value(1f64),
+            reflectivity: Scalar::Value(0f64),
+            transparency: Scalar::Value(0.9f64),
+            index_of_refraction: Scalar::Value(1.33f64),
+            absorption: BLACK,
+            detail: SurfaceDetail::default(),
+            emission: None,
+        }),
     });
     let mut lights: Vec<Light> = vec![];
     lights.push(Light::PointLight(PointLight {
         origin: Point3 {
-            x: 100f64,
+            x: -100f64,
             y: 0f64,
             z: 0f64,
         },
@@ -488,6 +551,8 @@ pub fn get_spheres_with_plane() -> Scene {
             green: 1f64,
             blue: 1f64,
         },
+        attenuation: Attenuation::None,
+        cutoff_radius: None,
     }));
 
     lights.push(Light::DirectionalLight(DirectionalLight {
@@ -533,12 +598,19 @@ pub fn get_spheres_with_plane() -> Scene {
         field_of_view: PI / 2f64,
         x_resolution: 500u16,
         y_resolution: 250u16,
+        sampling: Sampling::default(),
+        lens: None,
+        projection: Projection::Perspective,
+        display: DisplaySettings::default(),
+        integrator: Integrator::Whitted,
     };
 
-    Scene {
+    let mut scene = Scene {
         objects: SceneObjects::initialize(objects),
         lights: lights,
         ambient_light: ambient_light,
         camera: standard_camera,
-    }
+    };
+    scene.decode_colors(ColorSpace::Srgb);
+    scene
 }
@@ -0,0 +1,449 @@
+use crate::color::{Color, BLACK};
+use crate::geometry::{Point2, Point3, Ray, Vector3};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Sampling {
+    pub samples_per_pixel: u32,
+    pub sampler: Sampler,
+    pub filter: Filter,
+    /// When set, `samples_per_pixel` is ignored and each pixel takes between
+    /// `min_samples` and `max_samples` depending on how noisy it is.
+    pub adaptive: Option<AdaptiveSampling>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    /// Standard error of the pixel luminance under which refining stops.
+    pub threshold: f64,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Sampling {
+            samples_per_pixel: 1,
+            sampler: Sampler::Uniform,
+            filter: Filter::Box { radius: 0.5 },
+            adaptive: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub enum Sampler {
+    Uniform,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub enum Filter {
+    Box { radius: f64 },
+    Tent { radius: f64 },
+    Gaussian { radius: f64, alpha: f64 },
+    Mitchell { radius: f64, b: f64, c: f64 },
+}
+
+/// Small deterministic generator (splitmix64), seeded per pixel so that
+/// tiles rendered in parallel give reproducible images.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn for_pixel(x: u16, y: u16, seed: u64) -> Self {
+        let mut rng = Rng::new(((x as u64) << 32) ^ ((y as u64) << 16) ^ seed);
+        rng.next_u64();
+        rng
+    }
+
+    /// Generator for samples taken from a point of the scene, e.g. on a
+    /// light seen from a shaded point.
+    pub fn for_point(point: &Point3, seed: u64) -> Self {
+        let mut rng = Rng::new(
+            point.x.to_bits()
+                ^ point.y.to_bits().rotate_left(21)
+                ^ point.z.to_bits().rotate_left(42)
+                ^ seed,
+        );
+        rng.next_u64();
+        rng
+    }
+
+    /// Generator for the random walk started by a camera ray.
+    pub fn for_ray(ray: &Ray, seed: u64) -> Self {
+        let mut rng = Rng::for_point(&ray.origin, seed);
+        rng.state ^= ray.direction.x.to_bits().rotate_left(7)
+            ^ ray.direction.y.to_bits().rotate_left(29)
+            ^ ray.direction.z.to_bits().rotate_left(51);
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Sampler {
+    /// Sequence based samplers stay well distributed when only a prefix of
+    /// their samples is used.
+    pub fn is_progressive(&self) -> bool {
+        match self {
+            Sampler::Uniform | Sampler::Stratified => false,
+            Sampler::Halton | Sampler::Sobol => true,
+        }
+    }
+
+    /// Samples in the order they should be consumed when a pixel can stop
+    /// early: grids are shuffled so that any prefix covers the whole pixel.
+    pub fn generate_progressive(&self, count: u32, x: u16, y: u16) -> Vec<Point2> {
+        let mut samples = self.generate(count, x, y);
+        if !self.is_progressive() {
+            let mut rng = Rng::for_pixel(x, y, 1);
+            for i in (1..samples.len()).rev() {
+                let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+                samples.swap(i, j);
+            }
+        }
+        samples
+    }
+
+    /// Sample positions inside the unit square for pixel `(x, y)`. Grid based
+    /// samplers round `count` up so that the grid is complete.
+    pub fn generate(&self, count: u32, x: u16, y: u16) -> Vec<Point2> {
+        let count = count.max(1);
+        let mut rng = Rng::for_pixel(x, y, 0);
+        match self {
+            Sampler::Uniform => grid(count, |_rng| 0.5, &mut rng),
+            Sampler::Stratified => grid(count, |rng| rng.next_f64(), &mut rng),
+            Sampler::Halton => {
+                // Cranley-Patterson rotation decorrelates neighbouring pixels
+                let shift = Point2 {
+                    x: rng.next_f64(),
+                    y: rng.next_f64(),
+                };
+                (0..count)
+                    .map(|index| Point2 {
+                        x: (radical_inverse(2, index as u64 + 1) + shift.x).fract(),
+                        y: (radical_inverse(3, index as u64 + 1) + shift.y).fract(),
+                    })
+                    .collect()
+            }
+            Sampler::Sobol => {
+                let scramble_x = rng.next_u64() as u32;
+                let scramble_y = rng.next_u64() as u32;
+                (0..count)
+                    .map(|index| Point2 {
+                        x: to_unit(index.reverse_bits() ^ scramble_x),
+                        y: to_unit(sobol_second_dimension(index) ^ scramble_y),
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Jittered grid of at least `count` samples in the unit square.
+pub fn stratified(count: u32, rng: &mut Rng) -> Vec<Point2> {
+    grid(count.max(1), |rng| rng.next_f64(), rng)
+}
+
+/// Number of samples `stratified` returns for `count`.
+pub fn stratified_count(count: u32) -> usize {
+    let (columns, rows) = grid_size(count.max(1));
+    (columns * rows) as usize
+}
+
+/// Maps a sample of the unit square to the unit disk, keeping strata
+/// adjacent (Shirley-Chiu concentric mapping).
+pub fn concentric_disk(sample: &Point2) -> Point2 {
+    let a = 2f64 * sample.x - 1f64;
+    let b = 2f64 * sample.y - 1f64;
+    if a == 0f64 && b == 0f64 {
+        return Point2 { x: 0f64, y: 0f64 };
+    }
+    let (radius, angle) = if a.abs() > b.abs() {
+        (a, PI / 4f64 * (b / a))
+    } else {
+        (b, PI / 2f64 - PI / 4f64 * (a / b))
+    };
+    Point2 {
+        x: radius * angle.cos(),
+        y: radius * angle.sin(),
+    }
+}
+
+/// Maps a sample of the unit square to a direction uniformly distributed
+/// on the unit sphere.
+pub fn uniform_sphere(sample: &Point2) -> Vector3 {
+    let z = 1f64 - 2f64 * sample.x;
+    let radius = (1f64 - z * z).max(0f64).sqrt();
+    let angle = 2f64 * PI * sample.y;
+    Vector3 {
+        x: radius * angle.cos(),
+        y: radius * angle.sin(),
+        z,
+    }
+}
+
+/// Maps a sample of the unit square to a direction of the hemisphere around
+/// +z, with a density proportional to the cosine with +z.
+pub fn cosine_hemisphere(sample: &Point2) -> Vector3 {
+    let disk = concentric_disk(sample);
+    Vector3 {
+        x: disk.x,
+        y: disk.y,
+        z: (1f64 - disk.x * disk.x - disk.y * disk.y).max(0f64).sqrt(),
+    }
+}
+
+fn grid<F>(count: u32, jitter: F, rng: &mut Rng) -> Vec<Point2>
+where
+    F: Fn(&mut Rng) -> f64,
+{
+    let (columns, rows) = grid_size(count);
+    let mut samples = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        for column in 0..columns {
+            samples.push(Point2 {
+                x: (column as f64 + jitter(rng)) / columns as f64,
+                y: (row as f64 + jitter(rng)) / rows as f64,
+            });
+        }
+    }
+    samples
+}
+
+fn grid_size(count: u32) -> (u32, u32) {
+    let columns = (count as f64).sqrt().ceil() as u32;
+    (columns, count.div_ceil(columns))
+}
+
+fn radical_inverse(base: u64, index: u64) -> f64 {
+    let inverse_base = 1f64 / base as f64;
+    let mut index = index;
+    let mut result = 0f64;
+    let mut factor = inverse_base;
+    while index > 0 {
+        result += (index % base) as f64 * factor;
+        index /= base;
+        factor *= inverse_base;
+    }
+    result
+}
+
+fn sobol_second_dimension(index: u32) -> u32 {
+    let mut result = 0u32;
+    let mut direction = 1u32 << 31;
+    let mut index = index;
+    while index > 0 {
+        if index & 1 == 1 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+fn to_unit(value: u32) -> f64 {
+    value as f64 / (1u64 << 32) as f64
+}
+
+impl Filter {
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box { radius } => radius,
+            Filter::Tent { radius } => radius,
+            Filter::Gaussian { radius, .. } => radius,
+            Filter::Mitchell { radius, .. } => radius,
+        }
+    }
+
+    /// Weight of a sample at offset `(dx, dy)` from the pixel center.
+    pub fn evaluate(&self, dx: f64, dy: f64) -> f64 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, d: f64) -> f64 {
+        let d = d.abs();
+        match *self {
+            Filter::Box { radius } => {
+                if d <= radius {
+                    1f64
+                } else {
+                    0f64
+                }
+            }
+            Filter::Tent { radius } => (radius - d).max(0f64),
+            Filter::Gaussian { radius, alpha } => {
+                ((-alpha * d * d).exp() - (-alpha * radius * radius).exp()).max(0f64)
+            }
+            Filter::Mitchell { radius, b, c } => mitchell(2f64 * d / radius, b, c),
+        }
+    }
+}
+
+fn mitchell(x: f64, b: f64, c: f64) -> f64 {
+    let x = x.abs();
+    if x > 2f64 {
+        0f64
+    } else if x > 1f64 {
+        ((-b - 6f64 * c) * x * x * x
+            + (6f64 * b + 30f64 * c) * x * x
+            + (-12f64 * b - 48f64 * c) * x
+            + (8f64 * b + 24f64 * c))
+            / 6f64
+    } else {
+        ((12f64 - 9f64 * b - 6f64 * c) * x * x * x
+            + (-18f64 + 12f64 * b + 6f64 * c) * x * x
+            + (6f64 - 2f64 * b))
+            / 6f64
+    }
+}
+
+/// Weighted running sum of samples, kept unclamped until resolved.
+#[derive(Debug, Clone, Default)]
+pub struct SampleAccumulator {
+    red: f64,
+    green: f64,
+    blue: f64,
+    weight: f64,
+    count: u32,
+    luminance_sum: f64,
+    luminance_square_sum: f64,
+}
+
+impl SampleAccumulator {
+    pub fn add(&mut self, color: &Color, weight: f64) {
+        self.red += color.red * weight;
+        self.green += color.green * weight;
+        self.blue += color.blue * weight;
+        self.weight += weight;
+        self.count += 1;
+        let luminance = color.luminance();
+        self.luminance_sum += luminance;
+        self.luminance_square_sum += luminance * luminance;
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Standard error of the mean luminance of the samples taken so far.
+    pub fn standard_error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let count = self.count as f64;
+        let mean = self.luminance_sum / count;
+        let variance = (self.luminance_square_sum / count - mean * mean).max(0f64);
+        (variance / (count - 1f64)).sqrt()
+    }
+
+    /// Weighted mean of the samples. Only negative values, which filters
+    /// with negative lobes may produce, are cut off.
+    pub fn resolve(&self) -> Color {
+        if self.weight.abs() < 1e-12 {
+            return BLACK;
+        }
+        Color {
+            red: (self.red / self.weight).max(0f64),
+            green: (self.green / self.weight).max(0f64),
+            blue: (self.blue / self.weight).max(0f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_stay_in_the_unit_square() {
+        for sampler in [
+            Sampler::Uniform,
+            Sampler::Stratified,
+            Sampler::Halton,
+            Sampler::Sobol,
+        ]
+        .iter()
+        {
+            let samples = sampler.generate(16, 3, 7);
+            assert_eq!(samples.len(), 16, "{:?}", sampler);
+            for sample in samples {
+                assert!(sample.x >= 0f64 && sample.x < 1f64, "{:?}", sampler);
+                assert!(sample.y >= 0f64 && sample.y < 1f64, "{:?}", sampler);
+            }
+        }
+    }
+
+    #[test]
+    fn progressive_grid_prefix_spreads_over_the_pixel() {
+        let samples = Sampler::Uniform.generate_progressive(64, 1, 2);
+        assert_eq!(samples.len(), 64);
+        let top_half = samples[..16]
+            .iter()
+            .filter(|sample| sample.y < 0.5f64)
+            .count();
+        assert!(top_half > 0 && top_half < 16);
+    }
+
+    #[test]
+    fn constant_samples_have_no_error() {
+        let mut accumulator = SampleAccumulator::default();
+        for _ in 0..4 {
+            accumulator.add(&BLACK, 1f64);
+        }
+        assert_eq!(accumulator.standard_error(), 0f64);
+    }
+
+    #[test]
+    fn single_uniform_sample_is_the_pixel_center() {
+        let samples = Sampler::Uniform.generate(1, 0, 0);
+        assert_eq!(samples.len(), 1);
+        assert!((samples[0].x - 0.5f64).abs() < 1e-9);
+        assert!((samples[0].y - 0.5f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn filters_peak_at_the_center() {
+        let filters = [
+            Filter::Tent { radius: 1f64 },
+            Filter::Gaussian {
+                radius: 1.5f64,
+                alpha: 2f64,
+            },
+            Filter::Mitchell {
+                radius: 2f64,
+                b: 1f64 / 3f64,
+                c: 1f64 / 3f64,
+            },
+        ];
+        for filter in filters.iter() {
+            let center = filter.evaluate(0f64, 0f64);
+            assert!(center > filter.evaluate(0.5f64, 0f64), "{:?}", filter);
+            assert!(filter.evaluate(filter.radius() + 0.1f64, 0f64).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::color::Color;
+use crate::display::DisplaySettings;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral,
+    Hilbert,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    pub tile_size: u16,
+    pub tile_order: TileOrder,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            tile_size: 32,
+            tile_order: TileOrder::Spiral,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tile {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+/// A finished tile, its pixels stored row-major like the `Framebuffer`.
+#[derive(Debug, Clone)]
+pub struct RenderedTile {
+    pub tile: Tile,
+    pub pixels: Vec<Color>,
+    pub sample_counts: Vec<u32>,
+}
+
+impl RenderedTile {
+    pub fn to_rgb8(&self, display: &DisplaySettings) -> Vec<u8> {
+        let mut output = Vec::with_capacity(self.pixels.len() * 3);
+        for (index, pixel) in self.pixels.iter().enumerate() {
+            let x = self.tile.x + (index % self.tile.width as usize) as u16;
+            let y = self.tile.y + (index / self.tile.width as usize) as u16;
+            output.extend_from_slice(&display.to_rgb8(pixel, x, y));
+        }
+        output
+    }
+}
+
+/// Shared flag used to abandon a render in progress, e.g. when the scene
+/// changed and the frame being computed is already stale.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+pub fn generate_tiles(width: u16, height: u16, settings: &RenderSettings) -> Vec<Tile> {
+    let tile_size = settings.tile_size.max(1);
+    let columns = width.div_ceil(tile_size);
+    let rows = height.div_ceil(tile_size);
+
+    let cells = match settings.tile_order {
+        TileOrder::Scanline => scanline_order(columns, rows),
+        TileOrder::Spiral => spiral_order(columns, rows),
+        TileOrder::Hilbert => hilbert_order(columns, rows),
+    };
+
+    cells
+        .into_iter()
+        .map(|(column, row)| {
+            let x = column * tile_size;
+            let y = row * tile_size;
+            Tile {
+                x,
+                y,
+                width: tile_size.min(width - x),
+                height: tile_size.min(height - y),
+            }
+        })
+        .collect()
+}
+
+fn scanline_order(columns: u16, rows: u16) -> Vec<(u16, u16)> {
+    let mut cells = vec![];
+    for row in 0..rows {
+        for column in 0..columns {
+            cells.push((column, row));
+        }
+    }
+    cells
+}
+
+// Walks outwards from the central tile, so the middle of the image shows up first.
+fn spiral_order(columns: u16, rows: u16) -> Vec<(u16, u16)> {
+    let total = columns as usize * rows as usize;
+    let mut cells = Vec::with_capacity(total);
+    let mut x = (columns as i32 - 1) / 2;
+    let mut y = (rows as i32 - 1) / 2;
+    let directions = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+    let mut direction = 0;
+    let mut leg_length = 1;
+
+    while cells.len() < total {
+        for _leg in 0..2 {
+            for _step in 0..leg_length {
+                if x >= 0 && y >= 0 && x < columns as i32 && y < rows as i32 {
+                    cells.push((x as u16, y as u16));
+                }
+                x += directions[direction].0;
+                y += directions[direction].1;
+            }
+            direction = (direction + 1) % 4;
+        }
+        leg_length += 1;
+    }
+    cells
+}
+
+fn hilbert_order(columns: u16, rows: u16) -> Vec<(u16, u16)> {
+    let side = columns.max(rows).max(1).next_power_of_two() as u32;
+    let mut cells = scanline_order(columns, rows);
+    cells.sort_by_key(|&(column, row)| hilbert_index(side, column as u32, row as u32));
+    cells
+}
+
+// Distance of (x, y) along the Hilbert curve filling a `side` x `side` square.
+fn hilbert_index(side: u32, x: u32, y: u32) -> u32 {
+    let mut x = x;
+    let mut y = y;
+    let mut index = 0;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = if x & s > 0 { 1 } else { 0 };
+        let ry = if y & s > 0 { 1 } else { 0 };
+        index += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn covered_pixels(tiles: &[Tile]) -> usize {
+        tiles
+            .iter()
+            .map(|tile| tile.width as usize * tile.height as usize)
+            .sum()
+    }
+
+    #[test]
+    fn tiles_cover_the_image_in_every_order() {
+        for &tile_order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+            let settings = RenderSettings {
+                tile_size: 16,
+                tile_order,
+            };
+            let tiles = generate_tiles(100, 37, &settings);
+            assert_eq!(tiles.len(), 7 * 3, "{:?}", tile_order);
+            assert_eq!(covered_pixels(&tiles), 100 * 37, "{:?}", tile_order);
+        }
+    }
+
+    #[test]
+    fn spiral_starts_at_the_center() {
+        let settings = RenderSettings {
+            tile_size: 10,
+            tile_order: TileOrder::Spiral,
+        };
+        let tiles = generate_tiles(50, 50, &settings);
+        assert_eq!((tiles[0].x, tiles[0].y), (20, 20));
+    }
+
+    #[test]
+    fn hilbert_tiles_are_adjacent() {
+        let settings = RenderSettings {
+            tile_size: 1,
+            tile_order: TileOrder::Hilbert,
+        };
+        let tiles = generate_tiles(8, 8, &settings);
+        for pair in tiles.windows(2) {
+            let distance = (pair[0].x as i32 - pair[1].x as i32).abs()
+                + (pair[0].y as i32 - pair[1].y as i32).abs();
+            assert_eq!(distance, 1);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::color::{Color, BLACK};
+use crate::geometry::{Object, Point2, Point3, POINT2_ORIGIN};
+use crate::material::Channel;
+use crate::procedural::Procedural;
+use crate::texture::{Texture, UvMap};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Node of a shader graph, computing a color at each hit from those of its
+/// inputs. Scalars travel through the graph as greys.
+///
+/// Scene colors are decoded like every other material color, except for
+/// `Value`s, which are data such as mixing factors.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum ShaderNode {
+    Constant(Color),
+    Value(f64),
+    Texture(Texture),
+    /// Any of the procedural patterns.
+    Noise(Procedural),
+    /// From `from` where `factor` is 0 to `to` where it is 1, channel by
+    /// channel.
+    Mix {
+        from: Box<ShaderNode>,
+        to: Box<ShaderNode>,
+        factor: Box<ShaderNode>,
+    },
+    Multiply(Box<ShaderNode>, Box<ShaderNode>),
+    Add(Box<ShaderNode>, Box<ShaderNode>),
+    /// Gradient through the colors of `stops`, given in increasing
+    /// positions, picked by the luminance of `input`.
+    Ramp {
+        input: Box<ShaderNode>,
+        stops: Vec<RampStop>,
+    },
+    /// `input` looked up at transformed texture coordinates: scaled, turned
+    /// by `rotation` radians, then moved by `offset`.
+    UvTransform {
+        input: Box<ShaderNode>,
+        #[serde(default = "unit_scale")]
+        scale: Point2,
+        #[serde(default)]
+        rotation: f64,
+        #[serde(default = "no_offset")]
+        offset: Point2,
+    },
+    /// One channel of `input`, as a grey.
+    Channel {
+        input: Box<ShaderNode>,
+        channel: Channel,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RampStop {
+    pub position: f64,
+    pub color: Color,
+}
+
+fn unit_scale() -> Point2 {
+    Point2 { x: 1f64, y: 1f64 }
+}
+
+fn no_offset() -> Point2 {
+    POINT2_ORIGIN
+}
+
+impl ShaderNode {
+    /// Output of the node at `point`, `footprint` being the width of the
+    /// surface the lookup stands for.
+    pub fn color(&self, object: &Object, point: &Point3, footprint: f64) -> Color {
+        self.evaluate(object, point, footprint, &|coordinate| coordinate)
+    }
+
+    fn evaluate(&self, object: &Object, point: &Point3, footprint: f64, uv_map: &UvMap) -> Color {
+        let input = |node: &ShaderNode| node.evaluate(object, point, footprint, uv_map);
+        match self {
+            ShaderNode::Constant(color) => *color,
+            ShaderNode::Value(value) => Color::grey(*value),
+            ShaderNode::Texture(texture) => {
+                texture.get_mapped_color(object, point, footprint, uv_map)
+            }
+            ShaderNode::Noise(procedural) => procedural.mapped_color(object, point, uv_map),
+            ShaderNode::Mix { from, to, factor } => {
+                let (from, to, factor) = (input(from), input(to), input(factor));
+                let mix = |from: f64, to: f64, factor: f64| from + factor * (to - from);
+                Color {
+                    red: mix(from.red, to.red, factor.red),
+                    green: mix(from.green, to.green, factor.green),
+                    blue: mix(from.blue, to.blue, factor.blue),
+                }
+            }
+            ShaderNode::Multiply(a, b) => &input(a) * &input(b),
+            ShaderNode::Add(a, b) => &input(a) + &input(b),
+            ShaderNode::Ramp { input: node, stops } => {
+                let position = input(node).luminance();
+                let after = stops.iter().position(|stop| stop.position > position);
+                match after {
+                    _ if stops.is_empty() => BLACK,
+                    Some(0) => stops[0].color,
+                    None => stops[stops.len() - 1].color,
+                    Some(index) => {
+                        let (low, high) = (&stops[index - 1], &stops[index]);
+                        let amount = (position - low.position) / (high.position - low.position);
+                        low.color.mix(&high.color, amount)
+                    }
+                }
+            }
+            ShaderNode::UvTransform {
+                input,
+                scale,
+                rotation,
+                offset,
+            } => {
+                let (sin, cos) = rotation.sin_cos();
+                let transform = |coordinate: Point2| {
+                    let coordinate = uv_map(coordinate);
+                    let (x, y) = (coordinate.x * scale.x, coordinate.y * scale.y);
+                    Point2 {
+                        x: cos * x - sin * y + offset.x,
+                        y: sin * x + cos * y + offset.y,
+                    }
+                };
+                input.evaluate(object, point, footprint, &transform)
+            }
+            ShaderNode::Channel {
+                input: node,
+                channel,
+            } => Color::grey(channel.of(&input(node))),
+        }
+    }
+
+    pub fn map_colors<F>(&mut self, f: &F)
+    where
+        F: Fn(&Color) -> Color,
+    {
+        match self {
+            ShaderNode::Constant(color) => *color = f(color),
+            ShaderNode::Value(_) => {}
+            ShaderNode::Texture(texture) => texture.map_colors(f),
+            ShaderNode::Noise(procedural) => procedural.map_colors(f),
+            ShaderNode::Mix { from, to, factor } => {
+                from.map_colors(f);
+                to.map_colors(f);
+                factor.map_colors(f);
+            }
+            ShaderNode::Multiply(a, b) | ShaderNode::Add(a, b) => {
+                a.map_colors(f);
+                b.map_colors(f);
+            }
+            ShaderNode::Ramp { input, stops } => {
+                input.map_colors(f);
+                for stop in stops.iter_mut() {
+                    stop.color = f(&stop.color);
+                }
+            }
+            ShaderNode::UvTransform { input, .. } | ShaderNode::Channel { input, .. } => {
+                input.map_colors(f)
+            }
+        }
+    }
+
+    /// Reads the image files of the texture nodes, their paths being
+    /// relative to `directory`.
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        let mut textures = vec![];
+        self.collect_textures(&mut textures);
+        for texture in textures {
+            texture.load_resources(directory)?;
+        }
+        Ok(())
+    }
+
+    /// Same as `load_resources` for graphs computing data, whose textures
+    /// are read with `Texture::load_data_resources`.
+    pub fn load_data_resources(&mut self, directory: &Path) -> Result<(), String> {
+        let mut textures = vec![];
+        self.collect_textures(&mut textures);
+        for texture in textures {
+            texture.load_data_resources(directory)?;
+        }
+        Ok(())
+    }
+
+    fn collect_textures<'a>(&'a mut self, textures: &mut Vec<&'a mut Texture>) {
+        match self {
+            ShaderNode::Constant(_) | ShaderNode::Value(_) | ShaderNode::Noise(_) => {}
+            ShaderNode::Texture(texture) => textures.push(texture),
+            ShaderNode::Mix { from, to, factor } => {
+                from.collect_textures(textures);
+                to.collect_textures(textures);
+                factor.collect_textures(textures);
+            }
+            ShaderNode::Multiply(a, b) | ShaderNode::Add(a, b) => {
+                a.collect_textures(textures);
+                b.collect_textures(textures);
+            }
+            ShaderNode::Ramp { input, .. }
+            | ShaderNode::UvTransform { input, .. }
+            | ShaderNode::Channel { input, .. } => input.collect_textures(textures),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Material, Scalar};
+    use crate::texture::test_plane;
+
+    fn node(json: serde_json::Value) -> ShaderNode {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn evaluate(node: &ShaderNode, x: f64, y: f64) -> Color {
+        let point = Point3 {
+            x: -x,
+            y: -y,
+            z: 0f64,
+        };
+        node.color(&test_plane(), &point, 0f64)
+    }
+
+    fn ramp_texture() -> serde_json::Value {
+        serde_json::json!({"Texture": {
+            "pixels": [
+                [{"red": 0, "green": 0, "blue": 0}],
+                [{"red": 1, "green": 0.5, "blue": 0.25}],
+            ],
+            "scale": 1,
+            "offset": {"x": 0, "y": 0},
+        }})
+    }
+
+    #[test]
+    fn nodes_combine_their_inputs() {
+        let tint = serde_json::json!({"Constant": {"red": 0.5, "green": 1, "blue": 1}});
+        let multiply = node(serde_json::json!({"Multiply": [ramp_texture(), tint]}));
+        assert_eq!(
+            evaluate(&multiply, 1.5f64, 0.5f64),
+            Color {
+                red: 0.5f64,
+                green: 0.5f64,
+                blue: 0.25f64
+            }
+        );
+
+        let add = node(serde_json::json!({"Add": [{"Value": 0.25}, {"Value": 0.5}]}));
+        assert_eq!(evaluate(&add, 0f64, 0f64), Color::grey(0.75f64));
+
+        let mix = node(serde_json::json!({"Mix": {
+            "from": {"Constant": {"red": 0, "green": 0, "blue": 0}},
+            "to": {"Constant": {"red": 1, "green": 1, "blue": 1}},
+            "factor": {"Channel": {"input": ramp_texture(), "channel": "Green"}},
+        }}));
+        assert_eq!(evaluate(&mix, 1.5f64, 0.5f64), Color::grey(0.5f64));
+        assert_eq!(evaluate(&mix, 0.5f64, 0.5f64), BLACK);
+    }
+
+    #[test]
+    fn ramps_interpolate_between_stops() {
+        let ramp = |value: f64| {
+            node(serde_json::json!({"Ramp": {
+                "input": {"Value": value},
+                "stops": [
+                    {"position": 0.2, "color": {"red": 0, "green": 0, "blue": 1}},
+                    {"position": 0.6, "color": {"red": 1, "green": 0, "blue": 0}},
+                ],
+            }}))
+        };
+        let blue = Color {
+            red: 0f64,
+            green: 0f64,
+            blue: 1f64,
+        };
+        assert_eq!(evaluate(&ramp(0f64), 0f64, 0f64), blue);
+        assert_eq!(evaluate(&ramp(1f64), 0f64, 0f64).red, 1f64);
+        let between = evaluate(&ramp(0.5f64), 0f64, 0f64);
+        assert!((between.red - 0.75f64).abs() < 1e-9 && (between.blue - 0.25f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn uv_transforms_move_textures() {
+        let moved = node(serde_json::json!({"UvTransform": {
+            "input": ramp_texture(),
+            "offset": {"x": 1, "y": 0},
+        }}));
+        assert_eq!(evaluate(&moved, 0.5f64, 0.5f64).red, 1f64);
+        assert_eq!(evaluate(&moved, 1.5f64, 0.5f64).red, 0f64);
+
+        let turned = node(serde_json::json!({"UvTransform": {
+            "input": ramp_texture(),
+            "scale": {"x": 2, "y": 2},
+            "rotation": std::f64::consts::FRAC_PI_2,
+        }}));
+        // (0.1, -0.75) is scaled to (0.2, -1.5), then turned to (1.5, 0.2)
+        assert_eq!(evaluate(&turned, 0.1f64, -0.75f64).red, 1f64);
+        assert_eq!(evaluate(&turned, 0.1f64, -0.25f64).red, 0f64);
+    }
+
+    #[test]
+    fn graphs_drive_material_inputs() {
+        let roughness = serde_json::json!({"Graph": {"Channel": {
+            "input": ramp_texture(),
+            "channel": "Blue",
+        }}});
+        let material = serde_json::from_value::<Material>(serde_json::json!({
+            "type": "microfacet",
+            "baseColor": {"Graph": {"Multiply": [
+                {"Constant": {"red": 1, "green": 1, "blue": 1}},
+                {"Value": 0.5},
+            ]}},
+            "metallic": {"Value": 0},
+            "roughness": roughness,
+        }));
+        assert!(material.is_ok(), "{:?}", material);
+
+        let roughness: Scalar = serde_json::from_value(roughness).unwrap();
+        let point = Point3 {
+            x: -1.5f64,
+            y: -0.5f64,
+            z: 0f64,
+        };
+        assert_eq!(roughness.value(&test_plane(), &point, 0f64), 0.25f64);
+    }
+
+    #[test]
+    fn values_are_not_decoded_as_colors() {
+        let mut decoded = node(serde_json::json!({"Mix": {
+            "from": {"Constant": {"red": 0.5, "green": 0.5, "blue": 0.5}},
+            "to": {"Constant": {"red": 1, "green": 1, "blue": 1}},
+            "factor": {"Value": 0.5},
+        }}));
+        decoded.map_colors(&|color: &Color| color * 0.5f64);
+        assert_eq!(evaluate(&decoded, 0f64, 0f64), Color::grey(0.375f64));
+    }
+}
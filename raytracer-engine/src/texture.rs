@@ -1,32 +1,452 @@
-use crate::color::{Color, BLACK, WHITE};
+use crate::color::{Color, ColorSpace, BLACK, WHITE};
 use crate::geometry::{MeshTriangle, Object, Plane, Point2, Point3, Sphere, Vector3};
+use crate::parser::open_scene_file;
+use image::codecs::hdr::HdrDecoder;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::f64::consts::PI;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
 
+/// Grid of colors indexed by `[x][y]`, `y` going down the image.
+pub type Pixels = Vec<Vec<Color>>;
+
+/// Transformation of the texture coordinates of objects.
+pub type UvMap<'a> = dyn Fn(Point2) -> Point2 + 'a;
+
+/// Image mapped onto objects, `scale` being the size of a pixel in the
+/// texture coordinates of the object. Scene files give the pixels inline,
+/// or the `path` of an image file, relative to the scene file, which stays
+/// unread until `load_resources`.
+///
+/// Pixel `(x, y)` covers `[x, x + 1) x [y, y + 1)` in pixel coordinates.
 #[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "SerdeTexture", into = "SerdeTexture")]
 pub struct Texture {
-    pub pixels: Vec<Vec<Color>>,
+    /// Empty until `load_resources` reads the image file at `path`.
+    pub pixels: Arc<Pixels>,
+    /// Image file the pixels come from, already decoded to linear values.
+    pub path: Option<String>,
+    /// Encoding of the image file, sRGB unless it is an HDR one. Textures
+    /// holding data rather than colors, like roughness maps, are linear.
+    pub color_space: Option<ColorSpace>,
     pub scale: f64,
     pub offset: Point2,
+    pub wrap: Wrap,
+    pub filter: TextureFilter,
+    /// Successive halvings of `pixels`, down to a single pixel, built for
+    /// trilinear filtering only and shared like the pixels of image files.
+    pub mipmaps: Arc<Vec<Pixels>>,
+}
+
+/// How coordinates outside of the image are brought back onto it.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub enum Wrap {
+    /// Tiles the image.
+    #[default]
+    Repeat,
+    /// Stretches the border pixels.
+    Clamp,
+    /// Tiles the image, flipping every other tile.
+    Mirror,
+}
+
+/// How pixels are blended around the point looked up.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub enum TextureFilter {
+    /// The pixel under the point, blocky up close.
+    #[default]
+    Nearest,
+    /// Interpolates between the four pixels closest to the point.
+    Bilinear,
+    /// Bilinear lookups in the two mipmaps whose pixels are closest in size
+    /// to the ray footprint, blended together. Removes the aliasing of
+    /// textures seen from afar.
+    Trilinear,
+}
+
+impl Wrap {
+    fn apply(&self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        let wrapped = match self {
+            Wrap::Repeat => index.rem_euclid(size),
+            Wrap::Clamp => index.clamp(0, size - 1),
+            Wrap::Mirror => {
+                let folded = index.rem_euclid(2 * size);
+                if folded < size {
+                    folded
+                } else {
+                    2 * size - 1 - folded
+                }
+            }
+        };
+        wrapped as usize
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SerdeTexture {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pixels: Pixels,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color_space: Option<ColorSpace>,
+    scale: f64,
+    offset: Point2,
+    #[serde(default)]
+    wrap: Wrap,
+    #[serde(default)]
+    filter: TextureFilter,
+}
+
+impl TryFrom<SerdeTexture> for Texture {
+    type Error = String;
+
+    fn try_from(texture: SerdeTexture) -> Result<Self, Self::Error> {
+        let pixels = match &texture.path {
+            Some(_) => Arc::default(),
+            None if texture.pixels.is_empty() || texture.pixels[0].is_empty() => {
+                return Err("a texture needs either pixels or a path".to_string())
+            }
+            None => Arc::new(texture.pixels),
+        };
+        let mut texture = Texture {
+            pixels,
+            path: texture.path,
+            color_space: texture.color_space,
+            scale: texture.scale,
+            offset: texture.offset,
+            wrap: texture.wrap,
+            filter: texture.filter,
+            mipmaps: Arc::default(),
+        };
+        if texture.path.is_none() {
+            texture.build_mipmaps();
+        }
+        Ok(texture)
+    }
+}
+
+impl From<Texture> for SerdeTexture {
+    fn from(texture: Texture) -> Self {
+        SerdeTexture {
+            pixels: match texture.path {
+                Some(_) => vec![],
+                None => texture.pixels.as_ref().clone(),
+            },
+            path: texture.path,
+            color_space: texture.color_space,
+            scale: texture.scale,
+            offset: texture.offset,
+            wrap: texture.wrap,
+            filter: texture.filter,
+        }
+    }
+}
+
+/// Decoded image file along with its mipmaps, held only while textures use
+/// them.
+#[derive(Default)]
+struct CachedImage {
+    pixels: Weak<Pixels>,
+    mipmaps: Weak<Vec<Pixels>>,
+}
+
+type ImageCache = HashMap<(PathBuf, ColorSpace), CachedImage>;
+
+fn image_cache() -> MutexGuard<'static, ImageCache> {
+    static CACHE: OnceLock<Mutex<ImageCache>> = OnceLock::new();
+    CACHE.get_or_init(Default::default).lock().unwrap()
+}
+
+fn is_hdr(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"))
+}
+
+/// Key of the image cache: the file and the encoding it is decoded from.
+fn image_key(path: &Path, color_space: Option<ColorSpace>) -> (PathBuf, ColorSpace) {
+    let color_space = color_space.unwrap_or(if is_hdr(path) {
+        ColorSpace::Linear
+    } else {
+        ColorSpace::Srgb
+    });
+    (
+        path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+        color_space,
+    )
+}
+
+/// Decodes the image at `path` to linear colors, sharing the pixels with
+/// every texture still alive that was loaded from the same file.
+pub fn load_image(path: &Path, color_space: Option<ColorSpace>) -> Result<Arc<Pixels>, String> {
+    let key = image_key(path, color_space);
+    let color_space = key.1;
+    let mut cache = image_cache();
+    if let Some(pixels) = cache.get(&key).and_then(|image| image.pixels.upgrade()) {
+        return Ok(pixels);
+    }
+    let error = |error: image::ImageError| format!("{}: {}", path.display(), error);
+    let file = BufReader::new(open_scene_file(path)?);
+    let pixels = if is_hdr(path) {
+        let decoder = HdrDecoder::new(file).map_err(error)?;
+        let metadata = decoder.metadata();
+        let (width, height) = (metadata.width as usize, metadata.height as usize);
+        let texels = decoder.read_image_hdr().map_err(error)?;
+        (0..width)
+            .map(|x| {
+                (0..height)
+                    .map(|y| {
+                        let [red, green, blue] = texels[y * width + x].0;
+                        color_space.to_linear(&Color {
+                            red: red as f64,
+                            green: green as f64,
+                            blue: blue as f64,
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    } else {
+        let format = image::ImageFormat::from_path(path).map_err(error)?;
+        let image = image::load(file, format).map_err(error)?.to_rgb8();
+        (0..image.width())
+            .map(|x| {
+                (0..image.height())
+                    .map(|y| {
+                        let [red, green, blue] = image.get_pixel(x, y).0;
+                        color_space.to_linear(&Color {
+                            red: red as f64 / 255f64,
+                            green: green as f64 / 255f64,
+                            blue: blue as f64 / 255f64,
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let pixels: Arc<Pixels> = Arc::new(pixels);
+    if pixels.is_empty() || pixels[0].is_empty() {
+        return Err(format!("{}: empty image", path.display()));
+    }
+    cache.retain(|_, image| image.pixels.strong_count() > 0);
+    cache.insert(
+        key,
+        CachedImage {
+            pixels: Arc::downgrade(&pixels),
+            mipmaps: Weak::new(),
+        },
+    );
+    Ok(pixels)
+}
+
+/// Mipmaps of `pixels`, decoded from the image at `path`, built once for
+/// every texture still alive that was loaded from the same file.
+fn load_mipmaps(path: &Path, color_space: Option<ColorSpace>, pixels: &Pixels) -> Arc<Vec<Pixels>> {
+    let mut cache = image_cache();
+    let image = cache.entry(image_key(path, color_space)).or_default();
+    if let Some(mipmaps) = image.mipmaps.upgrade() {
+        return mipmaps;
+    }
+    let mipmaps = Arc::new(halvings(pixels));
+    image.mipmaps = Arc::downgrade(&mipmaps);
+    mipmaps
+}
+
+/// Successive halvings of `pixels` down to a single pixel, averaging blocks
+/// of 2x2 pixels of the previous level. Odd sizes repeat their last row or
+/// column.
+fn halvings(pixels: &Pixels) -> Vec<Pixels> {
+    let mut mipmaps: Vec<Pixels> = vec![];
+    loop {
+        let previous = mipmaps.last().unwrap_or(pixels);
+        let (width, height) = (previous.len(), previous[0].len());
+        if width == 1 && height == 1 {
+            return mipmaps;
+        }
+        let level = (0..width.div_ceil(2))
+            .map(|x| {
+                (0..height.div_ceil(2))
+                    .map(|y| {
+                        let xs = [2 * x, (2 * x + 1).min(width - 1)];
+                        let ys = [2 * y, (2 * y + 1).min(height - 1)];
+                        let sum = xs
+                            .iter()
+                            .flat_map(|&x| ys.iter().map(move |&y| previous[x][y]))
+                            .fold(BLACK, |sum, color| &sum + &color);
+                        &sum * 0.25f64
+                    })
+                    .collect()
+            })
+            .collect();
+        mipmaps.push(level);
+    }
 }
 
 pub fn get_checkboard() -> Vec<Vec<Color>> {
     vec![vec![BLACK, WHITE], vec![WHITE, BLACK]]
 }
 
+/// The plane z = 0, where the point (-x, -y, 0) has the texture coordinates
+/// (x, y).
+#[cfg(test)]
+pub(crate) fn test_plane() -> Object {
+    Object::Plane(Plane {
+        point: crate::geometry::POINT3_ORIGIN,
+        normal: Vector3 {
+            x: 0f64,
+            y: 0f64,
+            z: 1f64,
+        },
+    })
+}
+
 impl Texture {
-    pub fn get_color(&self, object: &Object, point: &Point3) -> Color {
-        let coordinate = object.get_2d_coordinate(point);
-        let x_float = coordinate.x / self.scale + self.offset.x;
-        let y_float = coordinate.y / self.scale + self.offset.y;
-        let x = x_float.floor() as usize % self.pixels.len();
-        let y = y_float.floor() as usize % self.pixels[0].len();
-        self.pixels[x][y]
+    /// Reads the image file of the texture, its path being relative to
+    /// `directory`, unless it was read already.
+    pub fn load_resources(&mut self, directory: &Path) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) if self.pixels.is_empty() => directory.join(path),
+            _ => return Ok(()),
+        };
+        self.pixels = load_image(&path, self.color_space)?;
+        if self.filter == TextureFilter::Trilinear {
+            self.mipmaps = load_mipmaps(&path, self.color_space, &self.pixels);
+        }
+        Ok(())
+    }
+
+    /// Same as `load_resources` for textures holding data rather than colors,
+    /// such as roughness or normal maps: their image files are decoded as
+    /// `Linear`, the only color space they may ask for.
+    pub fn load_data_resources(&mut self, directory: &Path) -> Result<(), String> {
+        match self.color_space {
+            None => self.color_space = Some(ColorSpace::Linear),
+            Some(ColorSpace::Linear) => {}
+            Some(color_space) => {
+                return Err(format!(
+                    "textures holding data need a Linear color space, not {:?}",
+                    color_space
+                ))
+            }
+        }
+        self.load_resources(directory)
+    }
+
+    /// Applies `f` to inline pixels. Those of image files were decoded when
+    /// loading them.
+    pub fn map_colors<F>(&mut self, f: &F)
+    where
+        F: Fn(&Color) -> Color,
+    {
+        if self.path.is_some() {
+            return;
+        }
+        for pixel in Arc::make_mut(&mut self.pixels).iter_mut().flatten() {
+            *pixel = f(pixel);
+        }
+        self.build_mipmaps();
+    }
+
+    /// Fills `mipmaps` if the filter needs them.
+    pub fn build_mipmaps(&mut self) {
+        self.mipmaps = Arc::new(match self.filter {
+            TextureFilter::Trilinear => halvings(&self.pixels),
+            _ => vec![],
+        });
+    }
+
+    /// Color at `point` of `object`, `footprint` being the width of the
+    /// surface around it that the lookup stands for.
+    pub fn get_color(&self, object: &Object, point: &Point3, footprint: f64) -> Color {
+        self.get_mapped_color(object, point, footprint, &|coordinate| coordinate)
+    }
+
+    /// Same as `get_color`, with the texture coordinates of the object
+    /// transformed by `uv_map`.
+    pub fn get_mapped_color(
+        &self,
+        object: &Object,
+        point: &Point3,
+        footprint: f64,
+        uv_map: &UvMap,
+    ) -> Color {
+        let coordinate = uv_map(object.get_2d_coordinate(point));
+        let x = coordinate.x / self.scale + self.offset.x;
+        let y = coordinate.y / self.scale + self.offset.y;
+        match self.filter {
+            TextureFilter::Nearest => self.pixel(&self.pixels, x.floor() as i64, y.floor() as i64),
+            TextureFilter::Bilinear => self.bilinear(&self.pixels, x, y),
+            TextureFilter::Trilinear => {
+                let level = self.mip_level(object, point, footprint, uv_map);
+                let finer = level.floor();
+                let color = |level: f64| {
+                    let size = 2f64.powf(level);
+                    let pixels = match level as usize {
+                        0 => &self.pixels,
+                        level => &self.mipmaps[level - 1],
+                    };
+                    self.bilinear(pixels, x / size, y / size)
+                };
+                let fine = color(finer);
+                if level == finer {
+                    return fine;
+                }
+                fine.mix(&color(finer + 1f64), level - finer)
+            }
+        }
+    }
+
+    fn pixel(&self, pixels: &Pixels, x: i64, y: i64) -> Color {
+        pixels[self.wrap.apply(x, pixels.len())][self.wrap.apply(y, pixels[0].len())]
+    }
+
+    fn bilinear(&self, pixels: &Pixels, x: f64, y: f64) -> Color {
+        // relative to the pixel centers
+        let (x, y) = (x - 0.5f64, y - 0.5f64);
+        let (left, top) = (x.floor(), y.floor());
+        let (i, j) = (left as i64, top as i64);
+        let upper = self
+            .pixel(pixels, i, j)
+            .mix(&self.pixel(pixels, i + 1, j), x - left);
+        let lower = self
+            .pixel(pixels, i, j + 1)
+            .mix(&self.pixel(pixels, i + 1, j + 1), x - left);
+        upper.mix(&lower, y - top)
+    }
+
+    // Fractional mip level, 0 being `pixels`, whose pixels span about as
+    // much as `footprint` does around `point`.
+    fn mip_level(&self, object: &Object, point: &Point3, footprint: f64, uv_map: &UvMap) -> f64 {
+        let center = uv_map(object.get_2d_coordinate(point));
+        let stretch = |direction: &Vector3| {
+            // the shorter side, the other one may cross a seam of the mapping
+            [1f64, -1f64]
+                .iter()
+                .map(|side| {
+                    let other = uv_map(
+                        object.get_2d_coordinate(&point.add(&direction.times(side * footprint))),
+                    );
+                    (other.x - center.x).hypot(other.y - center.y)
+                })
+                .fold(f64::INFINITY, f64::min)
+        };
+        let (tangent, bitangent) = object.get_normal(point).orthonormal_basis();
+        let pixels = stretch(&tangent).max(stretch(&bitangent)) / self.scale;
+        pixels.log2().clamp(0f64, self.mipmaps.len() as f64)
     }
 }
 
-trait Texturable {
+pub(crate) trait Texturable {
     fn get_2d_coordinate(&self, point: &Point3) -> Point2;
+
+    /// Unit directions along the surface in which the texture coordinates
+    /// x and y grow.
+    fn get_tangents(&self, point: &Point3) -> (Vector3, Vector3);
 }
 
 impl Texturable for Object {
@@ -37,6 +457,14 @@ impl Texturable for Object {
             Object::MeshTriangle(ref obj) => obj.get_2d_coordinate(point),
         }
     }
+
+    fn get_tangents(&self, point: &Point3) -> (Vector3, Vector3) {
+        match *self {
+            Object::Sphere(ref obj) => obj.get_tangents(point),
+            Object::Plane(ref obj) => obj.get_tangents(point),
+            Object::MeshTriangle(ref obj) => obj.get_tangents(point),
+        }
+    }
 }
 
 impl Texturable for Sphere {
@@ -47,10 +475,30 @@ impl Texturable for Sphere {
             y: (hit_vec.y / self.radius).acos() / PI,
         }
     }
+
+    fn get_tangents(&self, point: &Point3) -> (Vector3, Vector3) {
+        let hit_vec = point - &self.center;
+        let around_axis = hit_vec.x.hypot(hit_vec.z);
+        if around_axis < 1e-9 * self.radius {
+            return hit_vec.normalize().orthonormal_basis();
+        }
+        let along_parallel = Vector3 {
+            x: -hit_vec.z,
+            y: 0f64,
+            z: hit_vec.x,
+        };
+        let along_meridian = Vector3 {
+            x: hit_vec.y * hit_vec.x / around_axis,
+            y: -around_axis,
+            z: hit_vec.y * hit_vec.z / around_axis,
+        };
+        (along_parallel.normalize(), along_meridian.normalize())
+    }
 }
 
-impl Texturable for Plane {
-    fn get_2d_coordinate(&self, point: &Point3) -> Point2 {
+impl Plane {
+    // Directions of the texture coordinates x and y.
+    fn texture_axes(&self) -> (Vector3, Vector3) {
         let mut x_axis = self.normal.cross(&Vector3 {
             x: 0.0,
             y: 0.0,
@@ -64,7 +512,13 @@ impl Texturable for Plane {
             });
         }
         let y_axis = self.normal.cross(&x_axis);
+        (x_axis, y_axis)
+    }
+}
 
+impl Texturable for Plane {
+    fn get_2d_coordinate(&self, point: &Point3) -> Point2 {
+        let (x_axis, y_axis) = self.texture_axes();
         let hit_vec = point - &self.point;
 
         Point2 {
@@ -72,6 +526,11 @@ impl Texturable for Plane {
             y: hit_vec.dot(&y_axis),
         }
     }
+
+    fn get_tangents(&self, _point: &Point3) -> (Vector3, Vector3) {
+        let (x_axis, y_axis) = self.texture_axes();
+        (x_axis.normalize(), y_axis.normalize())
+    }
 }
 
 impl Texturable for MeshTriangle {
@@ -98,11 +557,17 @@ impl Texturable for MeshTriangle {
 
         &a_t + &(&(&ab_t * ab_point) + &(&ac_t * ac_point))
     }
+
+    fn get_tangents(&self, _point: &Point3) -> (Vector3, Vector3) {
+        self.mesh.tangents(self.triangle_index)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::color::srgb_to_linear;
+    use crate::geometry::{Mesh, MeshPlainTriangle, MeshVertex};
 
     #[test]
     fn sphere_2d_coordinates() {
@@ -211,4 +676,212 @@ mod tests {
             coordinates.y
         );
     }
+
+    #[test]
+    fn wrap_modes() {
+        let wrapped = |wrap: Wrap| -> Vec<usize> { (-4..7).map(|i| wrap.apply(i, 3)).collect() };
+        assert_eq!(wrapped(Wrap::Repeat), [2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0]);
+        assert_eq!(wrapped(Wrap::Clamp), [0, 0, 0, 0, 0, 1, 2, 2, 2, 2, 2]);
+        assert_eq!(wrapped(Wrap::Mirror), [2, 2, 1, 0, 0, 1, 2, 2, 1, 0, 0]);
+    }
+
+    // Texture on the test plane, with the pixel coordinates (x, y) at
+    // (-x, -y, 0).
+    fn lookup(pixels: Pixels, filter: TextureFilter, x: f64, y: f64, footprint: f64) -> f64 {
+        let mut texture = Texture {
+            pixels: Arc::new(pixels),
+            path: None,
+            color_space: None,
+            scale: 1f64,
+            offset: Point2 { x: 0f64, y: 0f64 },
+            wrap: Wrap::Clamp,
+            filter,
+            mipmaps: Arc::default(),
+        };
+        texture.build_mipmaps();
+        let point = Point3 {
+            x: -x,
+            y: -y,
+            z: 0f64,
+        };
+        texture.get_color(&test_plane(), &point, footprint).red
+    }
+
+    #[test]
+    fn bilinear_filtering_interpolates_between_pixel_centers() {
+        let grey = Color::grey;
+        let ramp = || vec![vec![grey(0f64)], vec![grey(1f64)], vec![grey(0.5f64)]];
+        let bilinear = |x: f64| lookup(ramp(), TextureFilter::Bilinear, x, 0.5f64, 0f64);
+        assert!((bilinear(0.5f64) - 0f64).abs() < 1e-9);
+        assert!((bilinear(1.25f64) - 0.75f64).abs() < 1e-9);
+        assert!((bilinear(2f64) - 0.75f64).abs() < 1e-9);
+        assert!((bilinear(3f64) - 0.5f64).abs() < 1e-9);
+        let nearest = lookup(ramp(), TextureFilter::Nearest, 1.25f64, 0.5f64, 0f64);
+        assert!((nearest - 1f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn trilinear_filtering_averages_over_the_footprint() {
+        let pixels: Pixels = (0..4)
+            .map(|x| (0..6).map(|y| Color::grey((x + y) as f64 % 2f64)).collect())
+            .collect();
+        let mut texture: Texture = serde_json::from_value(serde_json::json!({
+            "pixels": pixels.clone(),
+            "scale": 1,
+            "offset": {"x": 0, "y": 0},
+            "filter": "Trilinear",
+        }))
+        .unwrap();
+        let sizes = |texture: &Texture| -> Vec<(usize, usize)> {
+            texture
+                .mipmaps
+                .iter()
+                .map(|level| (level.len(), level[0].len()))
+                .collect()
+        };
+        assert_eq!(sizes(&texture), [(2, 3), (1, 2), (1, 1)]);
+        texture.map_colors(&|color: &Color| color * 2f64);
+        assert_eq!(sizes(&texture), [(2, 3), (1, 2), (1, 1)]);
+
+        let sharp = lookup(
+            pixels.clone(),
+            TextureFilter::Trilinear,
+            1.5f64,
+            1.5f64,
+            0f64,
+        );
+        assert!((sharp - 0f64).abs() < 1e-9);
+        let blurred = lookup(
+            pixels.clone(),
+            TextureFilter::Trilinear,
+            1.5f64,
+            1.5f64,
+            1e3f64,
+        );
+        assert!((blurred - 0.5f64).abs() < 1e-9, "{}", blurred);
+        let between = lookup(pixels, TextureFilter::Trilinear, 1.5f64, 1.5f64, 1.5f64);
+        assert!(between > sharp && between < blurred, "{}", between);
+    }
+
+    #[test]
+    fn image_files_are_decoded_once_and_shared() {
+        let path = std::env::temp_dir().join(format!("texture-{}.png", std::process::id()));
+        image::RgbImage::from_raw(2, 1, vec![255, 0, 0, 0, 128, 255])
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        let json = |options: &str| {
+            format!(
+                r#"{{"path": {:?}, {} "scale": 1, "offset": {{"x": 0, "y": 0}}}}"#,
+                path.to_str().unwrap(),
+                options
+            )
+        };
+        let load = |options: &str| -> Texture {
+            let mut texture: Texture = serde_json::from_str(&json(options)).unwrap();
+            assert!(texture.pixels.is_empty());
+            texture.load_resources(Path::new("")).unwrap();
+            texture
+        };
+        let first = load("");
+        let second = load("");
+        let linear = load(r#""colorSpace": "Linear","#);
+        let trilinear = || load(r#""filter": "Trilinear","#);
+        let (first_trilinear, second_trilinear) = (trilinear(), trilinear());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(Arc::ptr_eq(&first.pixels, &second.pixels));
+        assert!(!Arc::ptr_eq(&first.pixels, &linear.pixels));
+        assert!(Arc::ptr_eq(&first.pixels, &first_trilinear.pixels));
+        assert_eq!(first_trilinear.mipmaps.len(), 1);
+        assert!(Arc::ptr_eq(
+            &first_trilinear.mipmaps,
+            &second_trilinear.mipmaps
+        ));
+        assert_eq!((first.pixels.len(), first.pixels[0].len()), (2, 1));
+        assert_eq!(
+            first.pixels[0][0],
+            Color {
+                red: 1f64,
+                green: 0f64,
+                blue: 0f64
+            }
+        );
+        let green = first.pixels[1][0].green;
+        assert!(
+            (green - srgb_to_linear(128f64 / 255f64)).abs() < 1e-9,
+            "{}",
+            green
+        );
+        assert!((linear.pixels[1][0].green - 128f64 / 255f64).abs() < 1e-9);
+
+        let serialized = serde_json::to_string(&first).unwrap();
+        assert!(!serialized.contains("pixels"), "{}", serialized);
+        assert!(
+            serde_json::from_str::<Texture>(r#"{"scale": 1, "offset": {"x": 0, "y": 0}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn tangents_follow_texture_coordinates() {
+        let corner = |x: f64, y: f64| Point3 { x, y, z: 0f64 };
+        let vertex = |index: usize| MeshVertex {
+            vertex_index: index,
+            texture_index: index,
+            normal_index: 0,
+        };
+        let mesh = Arc::new(Mesh::new(
+            vec![corner(0f64, 0f64), corner(2f64, 0f64), corner(0f64, 1f64)],
+            vec![
+                Point2 { x: 0f64, y: 1f64 },
+                Point2 { x: 1f64, y: 1f64 },
+                Point2 { x: 0f64, y: 0f64 },
+            ],
+            vec![],
+            vec![MeshPlainTriangle {
+                vertex_a: vertex(0),
+                vertex_b: vertex(1),
+                vertex_c: vertex(2),
+            }],
+        ));
+        let (along_x, along_y) = mesh.tangents(0);
+        assert!((along_x.x - 1f64).abs() < 1e-9, "{:?}", along_x);
+        assert!((along_y.y + 1f64).abs() < 1e-9, "{:?}", along_y);
+
+        let objects = vec![
+            Object::Sphere(Sphere {
+                center: corner(1f64, 2f64),
+                radius: 2f64,
+            }),
+            Object::Plane(Plane {
+                point: corner(1f64, 2f64),
+                normal: Vector3 {
+                    x: 0.6f64,
+                    y: 0f64,
+                    z: 0.8f64,
+                },
+            }),
+        ];
+        let point = Point3 {
+            x: 2.2f64,
+            y: 3f64,
+            z: 1.2f64,
+        };
+        for object in objects {
+            let normal = object.get_normal(&point);
+            let point = match &object {
+                Object::Sphere(sphere) => sphere.center.add(&normal.times(sphere.radius)),
+                _ => point,
+            };
+            let (along_x, along_y) = object.get_tangents(&point);
+            let moved = |direction: &Vector3| {
+                &object.get_2d_coordinate(&point.add(&direction.times(1e-6f64)))
+                    - &object.get_2d_coordinate(&point)
+            };
+            let (x_step, y_step) = (moved(&along_x), moved(&along_y));
+            assert!(x_step.x > 0f64 && x_step.y.abs() < 1e-9, "{:?}", object);
+            assert!(y_step.y > 0f64 && y_step.x.abs() < 1e-9, "{:?}", object);
+            assert!(along_x.dot(&normal).abs() < 1e-9 && along_y.dot(&normal).abs() < 1e-9);
+        }
+    }
 }
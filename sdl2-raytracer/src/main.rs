@@ -1,12 +1,25 @@
-use raytracer_engine::color::Color;
 use raytracer_engine::engine::get_object;
-use raytracer_engine::engine::render;
+use raytracer_engine::engine::get_point;
+use raytracer_engine::engine::render_tiles;
 use raytracer_engine::engine::Scene;
-use raytracer_engine::geometry::Vector3;
+use raytracer_engine::geometry::{Point3, Vector3};
 use raytracer_engine::sample::*;
+use raytracer_engine::scheduler::{CancellationToken, RenderSettings, RenderedTile};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+// radians per dragged pixel
+const ORBIT_SPEED: f64 = 0.01;
+// fraction of the distance to the target per dragged pixel
+const PAN_SPEED: f64 = 0.002;
+// fraction of the distance to the target per scroll step
+const DOLLY_SPEED: f64 = 0.1;
 
 fn main() -> Result<(), String> {
     let mut scene = get_mesh();
@@ -34,17 +47,40 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
         .map_err(|e| e.to_string())?;
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(sdl2::pixels::PixelFormatEnum::RGBA8888, width, height)
+        .create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGB24, width, height)
         .map_err(|e| e.to_string())?;
 
-    scene.objects.build_kd_tree();
-    render_frame_scene_sdl2(scene, &mut canvas, &mut texture, width, height)?;
+    scene.objects.build_bvh();
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut frame = render_frame_scene_sdl2(
+        scene,
+        &mut canvas,
+        &mut texture,
+        &mut event_pump,
+        width,
+        height,
+    )?;
 
     let mut object_to_move_index: Option<usize> = None;
+    let mut target = get_point(
+        scene,
+        scene.camera.x_resolution / 2,
+        scene.camera.y_resolution / 2,
+    )
+    .unwrap_or_else(|| {
+        scene
+            .camera
+            .position
+            .add(&scene.camera.direction.normalize().times(10f64))
+    });
 
     'mainloop: loop {
-        for event in sdl_context.event_pump()?.poll_iter() {
-            let mut render = false;
+        let mut events = frame.pending_events;
+        frame.pending_events = vec![];
+        events.extend(event_pump.poll_iter());
+        // a cancelled frame has to be redone even if nothing moved
+        let mut render = !frame.completed;
+        for event in events {
             match event {
                 Event::KeyDown {
                     keycode: Some(Keycode::Escape),
@@ -55,9 +91,10 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                     keycode: Some(Keycode::M),
                     ..
                 } => {
-                    render = translate_object(
+                    render |= translate_object(
                         scene,
                         object_to_move_index,
+                        &mut target,
                         &Vector3 {
                             x: 0f64,
                             y: 0f64,
@@ -69,9 +106,10 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                     keycode: Some(Keycode::P),
                     ..
                 } => {
-                    render = translate_object(
+                    render |= translate_object(
                         scene,
                         object_to_move_index,
+                        &mut target,
                         &Vector3 {
                             x: 0f64,
                             y: 0f64,
@@ -83,9 +121,10 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                     keycode: Some(Keycode::Left),
                     ..
                 } => {
-                    render = translate_object(
+                    render |= translate_object(
                         scene,
                         object_to_move_index,
+                        &mut target,
                         &Vector3 {
                             x: -1f64,
                             y: 0f64,
@@ -97,9 +136,10 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                     keycode: Some(Keycode::Right),
                     ..
                 } => {
-                    render = translate_object(
+                    render |= translate_object(
                         scene,
                         object_to_move_index,
+                        &mut target,
                         &Vector3 {
                             x: 1f64,
                             y: 0f64,
@@ -111,9 +151,10 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                     keycode: Some(Keycode::Up),
                     ..
                 } => {
-                    render = translate_object(
+                    render |= translate_object(
                         scene,
                         object_to_move_index,
+                        &mut target,
                         &Vector3 {
                             x: 0f64,
                             y: 1f64,
@@ -125,9 +166,10 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                     keycode: Some(Keycode::Down),
                     ..
                 } => {
-                    render = translate_object(
+                    render |= translate_object(
                         scene,
                         object_to_move_index,
+                        &mut target,
                         &Vector3 {
                             x: 0f64,
                             y: -1f64,
@@ -141,16 +183,60 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
                 } => {
                     object_to_move_index = None;
                 }
-                Event::MouseButtonDown { x, y, .. } => {
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } => {
                     object_to_move_index = get_object(scene, x as u16, y as u16);
                     println!("Clicked on {:?}", object_to_move_index);
-                    render = false;
+                    if let Some(point) = get_point(scene, x as u16, y as u16) {
+                        target = point;
+                    }
+                }
+                Event::MouseMotion {
+                    mousestate,
+                    xrel,
+                    yrel,
+                    ..
+                } => {
+                    if mousestate.right() {
+                        scene.camera.orbit(
+                            &target,
+                            -(xrel as f64) * ORBIT_SPEED,
+                            -(yrel as f64) * ORBIT_SPEED,
+                        );
+                        render = true;
+                    } else if mousestate.middle() {
+                        let distance = (&target - &scene.camera.position).norm();
+                        scene.camera.pan(
+                            &mut target,
+                            -(xrel as f64) * distance * PAN_SPEED,
+                            (yrel as f64) * distance * PAN_SPEED,
+                        );
+                        render = true;
+                    }
+                }
+                Event::MouseWheel { y, .. } => {
+                    let distance = (&target - &scene.camera.position).norm();
+                    scene
+                        .camera
+                        .dolly(&target, (y as f64) * distance * DOLLY_SPEED);
+                    render = true;
                 }
                 _ => {}
             }
-            if render {
-                render_frame_scene_sdl2(scene, &mut canvas, &mut texture, width, height)?;
-            }
+        }
+        if render {
+            frame = render_frame_scene_sdl2(
+                scene,
+                &mut canvas,
+                &mut texture,
+                &mut event_pump,
+                width,
+                height,
+            )?;
         }
     }
 
@@ -160,47 +246,108 @@ pub fn render_scene_sdl2(scene: &mut Scene) -> Result<(), String> {
 fn translate_object<'a>(
     scene: &'a mut Scene,
     object_to_move_index: Option<usize>,
+    target: &mut Point3,
     direction: &Vector3,
 ) -> bool {
     match object_to_move_index {
         Some(id) => {
-            scene.objects.objects[id].geometry.translate(direction);
-            scene.objects.build_kd_tree();
+            scene.objects.translate_object(id, direction);
             return true;
         }
         None => {
             scene.camera.translate(direction);
+            *target = target.add(direction);
             return true;
         }
     }
 }
 
+pub struct Frame {
+    pub completed: bool,
+    pub pending_events: Vec<Event>,
+}
+
+// Input that changes the scene makes the frame being rendered stale.
+fn interrupts_rendering(event: &Event) -> bool {
+    match event {
+        Event::KeyDown { .. } | Event::Quit { .. } | Event::MouseWheel { .. } => true,
+        Event::MouseMotion { mousestate, .. } => mousestate.right() || mousestate.middle(),
+        _ => false,
+    }
+}
+
+/// Renders the scene on a background thread, presenting tiles as they come.
+/// Events received meanwhile are returned to the caller, and the frame is
+/// dropped as soon as one of them would make it stale.
 pub fn render_frame_scene_sdl2(
     scene: &Scene,
     canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
     texture: &mut sdl2::render::Texture,
+    event_pump: &mut sdl2::EventPump,
     width: u32,
     height: u32,
-) -> Result<(), String> {
-    let screen = render(scene);
-    canvas
-        .with_texture_canvas(texture, |texture_canvas| {
-            texture_canvas.clear();
-            for x in 0..scene.camera.x_resolution {
-                for y in 0..scene.camera.y_resolution {
-                    let sdl2_color: sdl2::pixels::Color = color_to_sdl2(screen[&(x, y)]);
-                    texture_canvas.set_draw_color(sdl2_color);
-                    texture_canvas
-                        .draw_point(sdl2::rect::Point::new(x as i32, y as i32))
-                        .unwrap();
+) -> Result<Frame, String> {
+    let settings = RenderSettings::default();
+    let cancellation = CancellationToken::new();
+    let (sender, receiver) = mpsc::channel::<RenderedTile>();
+    let mut pending_events = vec![];
+
+    thread::scope(|scope| -> Result<(), String> {
+        let renderer_cancellation = &cancellation;
+        let settings = &settings;
+        scope.spawn(move || {
+            let sender = Mutex::new(sender);
+            render_tiles(scene, settings, renderer_cancellation, |rendered_tile| {
+                let _ = sender.lock().unwrap().send(rendered_tile.clone());
+            });
+        });
+
+        loop {
+            for event in event_pump.poll_iter() {
+                if interrupts_rendering(&event) {
+                    cancellation.cancel();
                 }
+                pending_events.push(event);
             }
-        })
-        .map_err(|e| e.to_string())?;
+            match receiver.recv_timeout(Duration::from_millis(10)) {
+                Ok(rendered_tile) => {
+                    let tile = &rendered_tile.tile;
+                    texture
+                        .update(
+                            Some(sdl2::rect::Rect::new(
+                                tile.x as i32,
+                                tile.y as i32,
+                                tile.width as u32,
+                                tile.height as u32,
+                            )),
+                            &rendered_tile.to_rgb8(&scene.camera.display),
+                            tile.width as usize * 3,
+                        )
+                        .map_err(|e| e.to_string())?;
+                    present(canvas, texture, width, height)?;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    })?;
+
+    Ok(Frame {
+        completed: !cancellation.is_cancelled(),
+        pending_events,
+    })
+}
+
+fn present(
+    canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
+    texture: &sdl2::render::Texture,
+    width: u32,
+    height: u32,
+) -> Result<(), String> {
     canvas.set_draw_color(sdl2::pixels::Color::RGBA(0, 0, 0, 255));
     canvas.clear();
     canvas.copy(
-        &texture,
+        texture,
         None,
         Some(sdl2::rect::Rect::new(0, 0, width, height)),
     )?;
@@ -208,11 +355,3 @@ pub fn render_frame_scene_sdl2(
 
     Ok(())
 }
-
-fn color_to_sdl2(item: Color) -> sdl2::pixels::Color {
-    sdl2::pixels::Color::RGB(
-        ((item.red as f32) * 255.0) as u8,
-        ((item.green as f32) * 255.0) as u8,
-        ((item.blue as f32) * 255.0) as u8,
-    )
-}
@@ -1,4 +1,3 @@
-use raytracer_engine::color::Color;
 use raytracer_engine::engine::render;
 use raytracer_engine::engine::Scene;
 use raytracer_engine::sample::*;
@@ -17,18 +16,11 @@ pub fn render_scene_console(scene: &Scene) -> Result<(), String> {
     let screen = render(scene);
     for y in 0..scene.camera.y_resolution {
         for x in 0..scene.camera.x_resolution {
-            let ansi_color: ansi_term::Color = color_to_ansi(screen[&(x, y)]);
+            let [r, g, b] = screen.display().to_rgb8(&screen.get_pixel(x, y), x, y);
+            let ansi_color = ansi_term::Color::RGB(r, g, b);
             print!("{}", ansi_color.paint("█"));
         }
         println!();
     }
     Ok(())
 }
-
-fn color_to_ansi(item: Color) -> ansi_term::Color {
-    ansi_term::Color::RGB(
-        (item.red * 255f64).round() as u8,
-        (item.green * 255f64).round() as u8,
-        (item.blue * 255f64).round() as u8,
-    )
-}
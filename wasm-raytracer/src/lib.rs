@@ -2,45 +2,18 @@ mod utils;
 
 use wasm_bindgen::prelude::*;
 
-use raytracer_engine::camera::ViewRay;
 use raytracer_engine::color::Color;
+use raytracer_engine::display::DisplaySettings;
+use raytracer_engine::engine::get_focal_distance;
 use raytracer_engine::engine::get_object;
 use raytracer_engine::engine::render;
-use raytracer_engine::engine::render_pixel;
+use raytracer_engine::engine::render_tile;
 use raytracer_engine::engine::Scene;
 use raytracer_engine::geometry::Vector3;
 use raytracer_engine::parser::deserialize_scene;
 use raytracer_engine::sample::*;
-
-const SUBDIVISIONS: &[usize] = &[13, 11, 9, 7, 5, 4, 3, 2];
-
-fn eligible_to_step(x: usize, y: usize, step: usize) -> bool {
-    if step >= SUBDIVISIONS.len() {
-        return eligible_to_last_step_for(x) && eligible_to_last_step_for(y);
-    }
-    return eligible_to_step_for(x, step) || eligible_to_step_for(y, step);
-}
-
-fn eligible_to_step_for(x: usize, step: usize) -> bool {
-    for (i, item) in SUBDIVISIONS.iter().enumerate() {
-        if i < step && x % item == 0 {
-            return false;
-        }
-        if i == step {
-            return x % item == 0;
-        }
-    }
-    false
-}
-
-fn eligible_to_last_step_for(x: usize) -> bool {
-    for item in SUBDIVISIONS.iter() {
-        if x % item == 0 {
-            return false;
-        }
-    }
-    true
-}
+use raytracer_engine::scheduler::{generate_tiles, RenderSettings, Tile};
+use std::collections::VecDeque;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -55,33 +28,34 @@ pub struct Screen {
     pixels: Vec<u8>,
     scene: Scene,
     selected_object: Option<usize>,
-    step_rendering: Option<StepRendering>,
-}
-
-pub struct StepRendering {
-    viewport: Vec<ViewRay>,
+    pending_tiles: VecDeque<Tile>,
+    focal_distance: f64,
 }
 
 #[wasm_bindgen]
 impl Screen {
-    pub fn new(scene_string: String) -> Screen {
+    pub fn new(scene_string: String) -> Result<Screen, JsValue> {
         utils::set_panic_hook();
 
-        let mut scene = deserialize_scene(&scene_string);
-        //scene.objects.build_kd_tree();
+        let mut scene =
+            deserialize_scene(&scene_string, None).map_err(|error| JsValue::from_str(&error))?;
+        scene.objects.build_bvh();
         let width = scene.camera.x_resolution;
         let height = scene.camera.y_resolution;
 
         let pixels = vec![0u8; width as usize * height as usize * 3];
 
-        Screen {
+        let mut screen = Screen {
             width,
             height,
             pixels,
             scene: scene,
             selected_object: None,
-            step_rendering: None,
-        }
+            pending_tiles: VecDeque::new(),
+            focal_distance: 0f64,
+        };
+        screen.restart_tiles();
+        Ok(screen)
     }
 
     pub fn width(&self) -> u16 {
@@ -96,40 +70,42 @@ impl Screen {
         self.pixels.as_ptr()
     }
 
-    fn initialize_step_rendering(&mut self, step: usize) {
-        match step {
-            0 => {
-                let x = StepRendering {
-                    viewport: self.scene.camera.generate_viewport(),
-                };
-                self.step_rendering = Some(x);
-            }
-            _ => (),
-        }
+    // Drops whatever is left of the current frame and queues a full new one.
+    fn restart_tiles(&mut self) {
+        self.pending_tiles =
+            generate_tiles(self.width, self.height, &RenderSettings::default()).into();
+        self.focal_distance = get_focal_distance(&self.scene);
     }
 
-    #[wasm_bindgen(js_name = renderStep)]
-    pub fn render_step(&mut self, step: usize) {
-        self.initialize_step_rendering(step);
-        let step_rendering = self.step_rendering.as_mut().unwrap();
-        for view_ray in step_rendering.viewport.iter() {
-            let x = view_ray.x as usize;
-            let y = view_ray.y as usize;
-
-            if eligible_to_step(x, y, step) {
-                let result = render_pixel(&self.scene, view_ray.ray.clone());
-                print_pixel(&mut self.pixels, self.width, x, y, result);
+    /// Renders up to `count` tiles of the current frame, returns whether the
+    /// frame is complete.
+    #[wasm_bindgen(js_name = renderTiles)]
+    pub fn render_tiles(&mut self, count: usize) -> bool {
+        for _ in 0..count {
+            let tile = match self.pending_tiles.pop_front() {
+                Some(tile) => tile,
+                None => break,
+            };
+            let rendered_tile = render_tile(&self.scene, &tile, self.focal_distance);
+            for (index, color) in rendered_tile.pixels.into_iter().enumerate() {
+                let x = tile.x as usize + index % tile.width as usize;
+                let y = tile.y as usize + index / tile.width as usize;
+                print_pixel(
+                    &mut self.pixels,
+                    self.width,
+                    x,
+                    y,
+                    &self.scene.camera.display,
+                    color,
+                );
             }
         }
+        self.pending_tiles.is_empty()
     }
 
     pub fn render(&mut self) {
         let screen = render(&self.scene);
-        for ((xr, yr), color) in screen {
-            let x = xr as usize;
-            let y = yr as usize;
-            print_pixel(&mut self.pixels, self.width, x, y, color);
-        }
+        screen.write_rgb8(&mut self.pixels);
     }
 
     pub fn click(&mut self, x: u16, y: u16) {
@@ -144,58 +120,82 @@ impl Screen {
         match self.selected_object {
             Some(id) => match keycode {
                 keycodes::KEY_M => {
-                    self.scene.objects.objects[id].geometry.translate(&Vector3 {
-                        x: 1f64,
-                        y: 0f64,
-                        z: 0f64,
-                    });
+                    self.scene.objects.translate_object(
+                        id,
+                        &Vector3 {
+                            x: 1f64,
+                            y: 0f64,
+                            z: 0f64,
+                        },
+                    );
                 }
                 keycodes::KEY_K => {
-                    self.scene.objects.objects[id].geometry.translate(&Vector3 {
-                        x: -1f64,
-                        y: 0f64,
-                        z: 0f64,
-                    });
+                    self.scene.objects.translate_object(
+                        id,
+                        &Vector3 {
+                            x: -1f64,
+                            y: 0f64,
+                            z: 0f64,
+                        },
+                    );
                 }
                 keycodes::KEY_O => {
-                    self.scene.objects.objects[id].geometry.translate(&Vector3 {
-                        x: 0f64,
-                        y: 1f64,
-                        z: 0f64,
-                    });
+                    self.scene.objects.translate_object(
+                        id,
+                        &Vector3 {
+                            x: 0f64,
+                            y: 1f64,
+                            z: 0f64,
+                        },
+                    );
                 }
                 keycodes::KEY_L => {
-                    self.scene.objects.objects[id].geometry.translate(&Vector3 {
-                        x: 0f64,
-                        y: -1f64,
-                        z: 0f64,
-                    });
+                    self.scene.objects.translate_object(
+                        id,
+                        &Vector3 {
+                            x: 0f64,
+                            y: -1f64,
+                            z: 0f64,
+                        },
+                    );
                 }
                 keycodes::KEY_I => {
-                    self.scene.objects.objects[id].geometry.translate(&Vector3 {
-                        x: 0f64,
-                        y: 0f64,
-                        z: 1f64,
-                    });
+                    self.scene.objects.translate_object(
+                        id,
+                        &Vector3 {
+                            x: 0f64,
+                            y: 0f64,
+                            z: 1f64,
+                        },
+                    );
                 }
                 keycodes::KEY_P => {
-                    self.scene.objects.objects[id].geometry.translate(&Vector3 {
-                        x: 0f64,
-                        y: 0f64,
-                        z: -1f64,
-                    });
+                    self.scene.objects.translate_object(
+                        id,
+                        &Vector3 {
+                            x: 0f64,
+                            y: 0f64,
+                            z: -1f64,
+                        },
+                    );
                 }
                 _ => (),
             },
             None => (),
         }
+        self.restart_tiles();
     }
 }
 
-fn print_pixel(pixels: &mut Vec<u8>, width: u16, x: usize, y: usize, color: Color) {
-    let r = ((color.red as f32) * 255.0) as u8;
-    let g = ((color.green as f32) * 255.0) as u8;
-    let b = ((color.blue as f32) * 255.0) as u8;
+fn print_pixel(
+    pixels: &mut Vec<u8>,
+    width: u16,
+    x: usize,
+    y: usize,
+    display: &DisplaySettings,
+    color: Color,
+) {
+    let [r, g, b] = display.to_rgb8(&color, x as u16, y as u16);
 
     let w = width as usize;
 
@@ -10,19 +10,22 @@ let screen = null;
 let width = 0;
 let height = 0;
 let animationId = null;
-var renderingStep = 0;
 
 
 const onClick = () => {
     cancelAnimationFrame(animationId);
 
-    screen = Screen.new(textarea.value);
+    try {
+        screen = Screen.new(textarea.value);
+    } catch (error) {
+        alert(error);
+        return;
+    }
     width = screen.width();
     height = screen.height();
     canvas.height = PIXEL_SIZE * height;
     canvas.width = PIXEL_SIZE * width;
 
-    renderingStep = 0;
     animationId = requestAnimationFrame(repeatOften);
 }
 
@@ -47,19 +50,6 @@ const paint = () => {
     }
 };
 
-const PIXEL_SIZE_STEPS = [13, 11, 9, 7, 5, 4, 3, 2, 1];
-
-const paintStep = (step) => {
-    const cellsPtr = screen.pixels();
-    const cells = new Uint8Array(memory.buffer, cellsPtr, width * height * 3);
-
-    for (let row = 0; row < height / PIXEL_SIZE_STEPS[step]; row++) {
-        for (let col = 0; col < width / PIXEL_SIZE_STEPS[step]; col++) {
-            paintPixel(cells, row, col, PIXEL_SIZE_STEPS[step]);
-        }
-    }
-};
-
 const paintPixel = (cells, row, col, pixelSize) => {
     const idx = getIndex(row * pixelSize, col * pixelSize);
     const r = cells[idx];
@@ -90,20 +80,21 @@ canvas.addEventListener("click", event => {
 
 canvas.addEventListener('keydown', function (event) {
     cancelAnimationFrame(animationId);
-    renderingStep = 0;
     screen.keydown(event.keyCode);
     animationId = requestAnimationFrame(repeatOften);
 });
 
+const TILES_PER_FRAME = 4;
+
 function repeatOften() {
     const startTime = new Date();
-    screen.renderStep(renderingStep);
-    paintStep(renderingStep);
-    if (renderingStep++ > PIXEL_SIZE_STEPS.length - 1) {
+    const completed = screen.renderTiles(TILES_PER_FRAME);
+    paint();
+    if (completed) {
         return;
     }
     const timeDiff = new Date() - startTime;
-    console.log("Step " + renderingStep + " " + timeDiff + " ms");
+    console.log("Tiles " + timeDiff + " ms");
     animationId = requestAnimationFrame(repeatOften);
 }
 animationId = requestAnimationFrame(repeatOften);